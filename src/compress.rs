@@ -0,0 +1,337 @@
+//! Compressing code into a code chunk and unpacking it again.
+
+use crate::{deflate, encoder, tic_file};
+use anyhow::{bail, Result};
+use flate2::write::DeflateEncoder;
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// The result of `compress_code`.
+pub struct Compressed {
+    /// The smallest code chunk: compressed, or uncompressed if that is smaller.
+    pub chunk: tic_file::Chunk,
+    /// The analysis of the smallest compressed deflate stream.
+    pub analysis: deflate::Analysis,
+    pub uncompressed_size: usize,
+    /// The size of the compressed chunk data (zlib header + deflate stream) produced by each
+    /// of the compressors.
+    pub zopfli_size: usize,
+    pub zlib_size: usize,
+    pub tic_tool_size: usize,
+}
+
+/// Skips the zlib header of compressed code chunk data.
+pub fn deflate_stream(data: &[u8]) -> Result<&[u8]> {
+    if data.len() < 2 {
+        bail!("Compressed code chunk is too short for a zlib header");
+    }
+    Ok(&data[2..])
+}
+
+/// Decompresses the data of a compressed code chunk.
+pub fn unpack_code(data: &[u8]) -> Result<Vec<u8>> {
+    let mut unpacked = vec![];
+    libflate::deflate::Decoder::new(deflate_stream(data)?).read_to_end(&mut unpacked)?;
+    Ok(unpacked)
+}
+
+/// Returns the code of the first code chunk, decompressed if necessary.
+pub fn find_code(chunks: Vec<tic_file::Chunk>) -> Result<Vec<u8>> {
+    for chunk in chunks {
+        match chunk.type_ {
+            0x05 => return Ok(chunk.data),
+            0x10 => return unpack_code(&chunk.data),
+            _ => (),
+        }
+    }
+    bail!("No code chunk found");
+}
+
+/// Compresses `code` to a raw deflate stream with the default zopfli settings. Fast enough
+/// to evaluate renames.
+pub fn zopfli(code: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    zopfli_rs::compress(
+        &zopfli_rs::Options::default(),
+        &zopfli_rs::Format::Deflate,
+        code,
+        &mut compressed,
+    )
+    .unwrap();
+    compressed
+}
+
+/// Compresses `code` to a raw deflate stream with zlib at its best level. Much faster than
+/// zopfli, but usually a few percent bigger.
+pub fn zlib_best(code: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::best());
+    encoder.write_all(code).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// How the rename search estimates the compressed size of code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CostModel {
+    /// The compressors of `compress_code` with the same iterations, so the search
+    /// optimizes for the final result. Only the built-in encoder is left out, it's too
+    /// slow to run for every candidate.
+    Exact,
+    /// `zlib_best`.
+    Fast,
+    /// Searches with `Fast`, then picks among the best candidates with `Exact`.
+    TwoStage,
+}
+
+impl CostModel {
+    pub const NAMES: &'static [&'static str] = &["exact", "fast", "two-stage"];
+
+    /// The model renamings are searched with, `Fast` for `TwoStage`.
+    pub fn search_model(self) -> CostModel {
+        match self {
+            CostModel::TwoStage => CostModel::Fast,
+            model => model,
+        }
+    }
+
+    /// Compresses and analyzes `code`, with `Exact` for `TwoStage`, on up to `jobs`
+    /// threads.
+    pub fn analyze(self, code: &[u8], iterations: u32, jobs: usize) -> Result<deflate::Analysis> {
+        match self {
+            CostModel::Exact | CostModel::TwoStage => {
+                deflate::analyze(&smallest(compress_all(code, iterations, jobs, false)))
+            }
+            CostModel::Fast => deflate::analyze(&zlib_best(code)),
+        }
+    }
+}
+
+impl FromStr for CostModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<CostModel> {
+        Ok(match s {
+            "exact" => CostModel::Exact,
+            "fast" => CostModel::Fast,
+            "two-stage" => CostModel::TwoStage,
+            _ => bail!("Unknown cost model '{}'", s),
+        })
+    }
+}
+
+/// The raw deflate streams of zopfli (using `iterations`) and zlib, and of the built-in
+/// encoder if `encode`. With more than one job, they run on threads of their own.
+fn compress_all(code: &[u8], iterations: u32, jobs: usize, encode: bool) -> Vec<Vec<u8>> {
+    let zopfli = || {
+        let mut zopfli = vec![];
+        zopfli_rs::compress(
+            &zopfli_rs::Options {
+                iterations: iterations as i32,
+                ..Default::default()
+            },
+            &zopfli_rs::Format::Deflate,
+            code,
+            &mut zopfli,
+        )
+        .unwrap();
+        zopfli
+    };
+    if jobs <= 1 {
+        let mut streams = vec![zopfli(), zlib_best(code)];
+        if encode {
+            streams.push(encoder::compress(code, iterations));
+        }
+        return streams;
+    }
+    thread::scope(|scope| {
+        let encoded = if encode {
+            Some(scope.spawn(|| encoder::compress(code, iterations)))
+        } else {
+            None
+        };
+        let mut streams = if encode && jobs == 2 {
+            vec![zopfli(), zlib_best(code)]
+        } else {
+            let zopfli = scope.spawn(zopfli);
+            let zlib = zlib_best(code);
+            vec![zopfli.join().unwrap(), zlib]
+        };
+        streams.extend(encoded.map(|encoded| encoded.join().unwrap()));
+        streams
+    })
+}
+
+/// The first of the smallest `streams`.
+fn smallest(streams: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut streams = streams.into_iter();
+    let mut smallest = streams.next().unwrap();
+    for stream in streams {
+        if stream.len() < smallest.len() {
+            smallest = stream;
+        }
+    }
+    smallest
+}
+
+/// Compresses `code` with zopfli (using `iterations`), zlib and the built-in encoder and
+/// returns the smallest result as a code chunk. The compressors run on up to `jobs` threads.
+pub fn compress_code(code: Vec<u8>, iterations: u32, jobs: usize) -> Result<Compressed> {
+    let streams = compress_all(&code, iterations, jobs, true);
+    // + the zlib header
+    let zopfli_size = streams[0].len() + 2;
+    let zlib_size = streams[1].len() + 2;
+    let tic_tool_size = streams[2].len() + 2;
+    let mut data = vec![0x78, 0xda];
+    data.extend(smallest(streams));
+
+    let analysis = deflate::analyze(&data[2..])?;
+    let uncompressed_size = code.len();
+
+    let chunk = if code.len() <= data.len() {
+        tic_file::Chunk {
+            type_: 0x05,
+            bank: 0,
+            data: code,
+        }
+    } else {
+        tic_file::Chunk {
+            type_: 0x10,
+            bank: 0,
+            data,
+        }
+    };
+    Ok(Compressed {
+        chunk,
+        analysis,
+        uncompressed_size,
+        zopfli_size,
+        zlib_size,
+        tic_tool_size,
+    })
+}
+
+/// Improves `best`, which compresses to `size` bits, by `changes`, each a site and what to
+/// `apply` there: tries each change on its own, then combines the ones that helped, best
+/// first, as long as they still help together, keeping at most one change per site.
+/// `evaluate` returns the compressed sizes of candidates, using up to the given number of
+/// jobs. Returns the best candidate and its size.
+pub(crate) fn combine_changes<T: Clone, S: PartialEq, V>(
+    best: T,
+    size: usize,
+    changes: &[(S, V)],
+    apply: impl Fn(&mut T, &S, &V),
+    mut evaluate: impl FnMut(Vec<T>, usize) -> Result<Vec<usize>>,
+    jobs: usize,
+) -> Result<(T, usize)> {
+    let variants = changes
+        .iter()
+        .map(|(site, value)| {
+            let mut variant = best.clone();
+            apply(&mut variant, site, value);
+            variant
+        })
+        .collect();
+    let mut improvements: Vec<(usize, usize)> = evaluate(variants, jobs)?
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, variant_size)| variant_size < size)
+        .map(|(index, variant_size)| (variant_size, index))
+        .collect();
+    improvements.sort_unstable();
+    let (mut best, mut size) = (best, size);
+    let mut changed: Vec<&S> = vec![];
+    for (_, index) in improvements {
+        let (site, value) = &changes[index];
+        if changed.contains(&site) {
+            continue;
+        }
+        let mut candidate = best.clone();
+        apply(&mut candidate, site, value);
+        let candidate_size = evaluate(vec![candidate.clone()], 1)?[0];
+        if candidate_size < size {
+            best = candidate;
+            size = candidate_size;
+            changed.push(site);
+        }
+    }
+    Ok((best, size))
+}
+
+/// `f` applied to all `items`, on up to `jobs` threads, to compress several candidates
+/// at once. `f` also gets the number of jobs each item may use, more than one when there
+/// are fewer items than jobs.
+pub(crate) fn parallel_map<T: Sync, R: Send>(
+    jobs: usize,
+    items: &[T],
+    f: impl Fn(&T, usize) -> R + Sync,
+) -> Vec<R> {
+    let item_jobs = (jobs / items.len().max(1)).max(1);
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(|item| f(item, item_jobs)).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let item = match items.get(index) {
+                    Some(item) => item,
+                    None => break,
+                };
+                let result = f(item, item_jobs);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn combine() {
+        let mut evaluated = 0;
+        let (best, size) = combine_changes(
+            vec![5, 5, 5],
+            15,
+            &[(0, 2), (0, 1), (1, 4), (2, 9)],
+            |values, &site, &value| values[site] = value,
+            |candidates: Vec<Vec<usize>>, _| {
+                evaluated += candidates.len();
+                Ok(candidates
+                    .iter()
+                    .map(|values| values.iter().sum())
+                    .collect())
+            },
+            1,
+        )
+        .unwrap();
+        // (0, 1) beats (0, 2) at the same site, (2, 9) doesn't help
+        assert_eq!((best, size), (vec![1, 4, 5], 10));
+        assert_eq!(evaluated, 4 + 2);
+    }
+
+    #[test]
+    fn jobs() {
+        let code = b"circ(1,2,3,4) circ(2,3,4,5) circ(3,4,5,6) circ(4,5,6,7) circ(5,6,7,8)";
+        let streams = compress_all(code, 5, 1, true);
+        assert_eq!(streams.len(), 3);
+        for jobs in 2..=3 {
+            assert_eq!(compress_all(code, 5, jobs, true), streams);
+            assert_eq!(compress_all(code, 5, jobs, false), streams[..2]);
+        }
+        let compressed = compress_code(code.to_vec(), 5, 3).unwrap();
+        assert_eq!(compressed.chunk.type_, 0x10);
+        assert_eq!(compressed.chunk.data[2..], smallest(streams)[..]);
+    }
+}
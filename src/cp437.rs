@@ -1,3 +1,5 @@
+//! The code page 437 characters TIC-80 uses to display bytes.
+
 pub const MAPPING: [char; 256] = [
     '\u{0000}', '\u{263a}', '\u{263b}', '\u{2665}', '\u{2666}', '\u{2663}', '\u{2660}', '\u{2022}', '\u{25d8}', '\u{25cb}', '\u{25d9}', '\u{2642}',
     '\u{2640}', '\u{266a}', '\u{266b}', '\u{263c}', '\u{25ba}', '\u{25c4}', '\u{2195}', '\u{203c}', '\u{00b6}', '\u{00a7}', '\u{25ac}', '\u{21a8}',
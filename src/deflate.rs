@@ -1,7 +1,12 @@
+//! Decoding deflate streams into an analysis of where the bits go: a disassembly, the
+//! cost of each byte of the uncompressed data and the sizes of the blocks.
+
 use super::cp437;
-use anyhow::Result;
+use super::encoder::Symbol;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
 
-pub fn analyze(data: &[u8]) -> Analysis {
+pub fn analyze(data: &[u8]) -> Result<Analysis> {
     let mut bitstream = Bitstream::new(data);
     let mut data = AnalysisData {
         unpacked: vec![],
@@ -14,136 +19,21 @@ pub fn analyze(data: &[u8]) -> Analysis {
 
     let mut is_final = false;
     while !is_final {
-        is_final = bitstream.get_bit() == 1;
+        let block_pos = bitstream.pos;
+        is_final = bitstream.get_bit()? == 1;
         let block_start_pos = data.unpacked.len();
-        let block_type = bitstream.get_bits(2);
+        let block_type = bitstream.get_bits(2)?;
         let header_item = bitstream.take_item();
-        match block_type {
-            1 => {
-                let mut huff_lit_length = HuffmanBuilder::new();
-                huff_lit_length.add_codes(0..=143, 8);
-                huff_lit_length.add_codes(144..=255, 9);
-                huff_lit_length.add_codes(256..=279, 7);
-                huff_lit_length.add_codes(280..=287, 8);
-
-                let mut huff_distance = HuffmanBuilder::new();
-                huff_distance.add_codes(0..=31, 5);
-
-                let lz_items = decode_block(
-                    &mut bitstream,
-                    &mut data,
-                    huff_lit_length.build(),
-                    huff_distance.build(),
-                );
-
-                blocks.push(BlockAnalysis {
-                    header_item,
-                    block_type: BlockType::StaticHuffman,
-                    lz: lz_items,
-                });
-            }
-            2 => {
-                let hlit = bitstream.get_bits(5) as usize;
-                let hdist = bitstream.get_bits(5) as usize;
-                let hclen = bitstream.get_bits(4) as usize;
-                let huff_header_item = bitstream.take_item();
-                let mut huff_header = HuffmanBuilder::new();
-                let mut huff_header_lengths = vec![];
-                for &code in &[
-                    16u32, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
-                ][..hclen + 4]
-                {
-                    let length = bitstream.get_bits(3);
-                    huff_header.add_code(code, length);
-                    huff_header_lengths.push((code, length, bitstream.take_item()));
-                }
-                let huff_header = huff_header.build();
-                let mut huff_lengths = vec![0u32; hlit + 257 + hdist + 1];
-                let mut pos = 0;
-                let mut huff_header_codes = vec![];
-                while pos < huff_lengths.len() {
-                    let code = huff_header.read(&mut bitstream);
-                    let huff_item = bitstream.take_item();
-                    match code {
-                        16 => {
-                            let count = bitstream.get_bits(2) + 3;
-                            huff_header_codes.push(HuffmanHeaderCode::Repeat {
-                                huff_item,
-                                count_item: bitstream.take_item(),
-                                count,
-                            });
-                            for _ in 0..count {
-                                huff_lengths[pos] = huff_lengths[pos - 1];
-                                pos += 1;
-                            }
-                        }
-                        17 => {
-                            let count = bitstream.get_bits(3) + 3;
-                            huff_header_codes.push(HuffmanHeaderCode::Skip {
-                                huff_item,
-                                count_item: bitstream.take_item(),
-                                count,
-                            });
-                            for _ in 0..count {
-                                huff_lengths[pos] = 0;
-                                pos += 1;
-                            }
-                        }
-                        18 => {
-                            let count = bitstream.get_bits(7) + 11;
-                            huff_header_codes.push(HuffmanHeaderCode::Skip {
-                                huff_item,
-                                count_item: bitstream.take_item(),
-                                count,
-                            });
-                            for _ in 0..count {
-                                huff_lengths[pos] = 0;
-                                pos += 1;
-                            }
-                        }
-                        num_bits => {
-                            huff_header_codes.push(HuffmanHeaderCode::Length {
-                                huff_item,
-                                length: num_bits,
-                            });
-                            huff_lengths[pos] = num_bits;
-                            pos += 1;
-                        }
-                    }
-                }
-
-                let mut huff_lit_length = HuffmanBuilder::new();
-                for code in 0..hlit + 257 {
-                    huff_lit_length.add_code(code as u32, huff_lengths[code]);
-                }
-                let mut huff_distance = HuffmanBuilder::new();
-                for code in 0..hdist + 1 {
-                    huff_distance.add_code(code as u32, huff_lengths[hlit + 257 + code]);
-                }
-
-                let lz_items = decode_block(
-                    &mut bitstream,
-                    &mut data,
-                    huff_lit_length.build(),
-                    huff_distance.build(),
-                );
-
-                blocks.push(BlockAnalysis {
-                    header_item,
-                    block_type: BlockType::DynamicHuffman {
-                        huff_header_item,
-                        hlit,
-                        hdist,
-                        hclen,
-                        huff_header_lengths,
-                        huff_header_codes,
-                    },
-                    lz: lz_items,
-                });
-            }
-            _ => panic!("Block type {} not implemented yet", block_type),
-        }
-        data.block_type.extend(std::iter::repeat(block_type as u8).take(data.unpacked.len() - block_start_pos));
+        let block = read_block(&mut bitstream, &mut data, block_type, header_item)
+            .with_context(|| {
+                format!(
+                    "Failed to decode block {} starting at bit {}",
+                    blocks.len(),
+                    block_pos
+                )
+            })?;
+        blocks.push(block);
+        data.block_type.extend(std::iter::repeat_n(block_type as u8, data.unpacked.len() - block_start_pos));
     }
 
     let mut ref_count = vec![0usize; data.unpacked.len()];
@@ -162,11 +52,207 @@ pub fn analyze(data: &[u8]) -> Analysis {
             shifted.push(0.);
         }
     }
-    for (cost, delta) in data.cost.iter_mut().zip(shifted.into_iter()) {
+    for (cost, delta) in data.cost.iter_mut().zip(shifted) {
         *cost += delta;
     }
 
-    Analysis { data, blocks }
+    Ok(Analysis { data, blocks })
+}
+
+fn read_block(
+    bitstream: &mut Bitstream,
+    data: &mut AnalysisData,
+    block_type: u32,
+    header_item: BitstreamItem,
+) -> Result<BlockAnalysis> {
+    match block_type {
+        0 => {
+            bitstream.align_to_byte();
+            let align_item = bitstream.take_item();
+            let len = bitstream.get_bits(16)?;
+            let len_item = bitstream.take_item();
+            let nlen = bitstream.get_bits(16)?;
+            let nlen_item = bitstream.take_item();
+            if nlen != !len & 0xffff {
+                bail!(
+                    "Stored block len {:04x} doesn't match nlen {:04x} at bit {}",
+                    len,
+                    nlen,
+                    len_item.pos
+                );
+            }
+
+            let mut lz_items = vec![];
+            for _ in 0..len {
+                let byte = bitstream.get_bits(8)? as u8;
+                let item = bitstream.take_item();
+                data.cost.push(8.);
+                data.unpacked.push(byte);
+                data.literal_index.push(usize::MAX);
+                lz_items.push(LzItem::Literal { item, byte });
+            }
+
+            Ok(BlockAnalysis {
+                header_item,
+                block_type: BlockType::Stored {
+                    align_item,
+                    len_item,
+                    nlen_item,
+                    len,
+                },
+                lz: lz_items,
+            })
+        }
+        1 => {
+            let mut huff_lit_length = HuffmanBuilder::new();
+            huff_lit_length.add_codes(0..=143, 8);
+            huff_lit_length.add_codes(144..=255, 9);
+            huff_lit_length.add_codes(256..=279, 7);
+            huff_lit_length.add_codes(280..=287, 8);
+
+            let mut huff_distance = HuffmanBuilder::new();
+            huff_distance.add_codes(0..=31, 5);
+
+            let lz_items = decode_block(
+                bitstream,
+                data,
+                huff_lit_length.build()?,
+                huff_distance.build()?,
+            )?;
+
+            Ok(BlockAnalysis {
+                header_item,
+                block_type: BlockType::StaticHuffman,
+                lz: lz_items,
+            })
+        }
+        2 => {
+            let hlit = bitstream.get_bits(5)? as usize;
+            let hdist = bitstream.get_bits(5)? as usize;
+            let hclen = bitstream.get_bits(4)? as usize;
+            let huff_header_item = bitstream.take_item();
+            if hlit > 29 || hdist > 29 {
+                bail!(
+                    "Too many length or distance codes (hlit: {}, hdist: {}) at bit {}",
+                    hlit,
+                    hdist,
+                    huff_header_item.pos
+                );
+            }
+            let mut huff_header = HuffmanBuilder::new();
+            let mut huff_header_lengths = vec![];
+            for &code in &CODE_LENGTH_ORDER[..hclen + 4] {
+                let length = bitstream.get_bits(3)?;
+                huff_header.add_code(code, length);
+                huff_header_lengths.push((code, length, bitstream.take_item()));
+            }
+            let huff_header = huff_header
+                .build()
+                .context("Invalid code length huffman table")?;
+            let mut huff_lengths = vec![0u32; hlit + 257 + hdist + 1];
+            let mut pos = 0;
+            let mut huff_header_codes = vec![];
+            while pos < huff_lengths.len() {
+                let code = huff_header.read(bitstream)?;
+                let huff_item = bitstream.take_item();
+                let (count, length) = match code {
+                    16 => {
+                        if pos == 0 {
+                            bail!(
+                                "Repeat code without previous length at bit {}",
+                                huff_item.pos
+                            );
+                        }
+                        let count = bitstream.get_bits(2)? + 3;
+                        huff_header_codes.push(HuffmanHeaderCode::Repeat {
+                            huff_item,
+                            count_item: bitstream.take_item(),
+                            count,
+                        });
+                        (count, huff_lengths[pos - 1])
+                    }
+                    17 => {
+                        let count = bitstream.get_bits(3)? + 3;
+                        huff_header_codes.push(HuffmanHeaderCode::Skip {
+                            huff_item,
+                            count_item: bitstream.take_item(),
+                            count,
+                        });
+                        (count, 0)
+                    }
+                    18 => {
+                        let count = bitstream.get_bits(7)? + 11;
+                        huff_header_codes.push(HuffmanHeaderCode::Skip {
+                            huff_item,
+                            count_item: bitstream.take_item(),
+                            count,
+                        });
+                        (count, 0)
+                    }
+                    num_bits => {
+                        huff_header_codes.push(HuffmanHeaderCode::Length {
+                            huff_item,
+                            length: num_bits,
+                        });
+                        (1, num_bits)
+                    }
+                };
+                if pos + count as usize > huff_lengths.len() {
+                    bail!(
+                        "Code lengths overrun the {} expected entries at bit {}",
+                        huff_lengths.len(),
+                        bitstream.pos
+                    );
+                }
+                for _ in 0..count {
+                    huff_lengths[pos] = length;
+                    pos += 1;
+                }
+            }
+
+            if huff_lengths[256] == 0 {
+                bail!("Missing end-of-block code in literal/length table");
+            }
+
+            let mut huff_lit_length = HuffmanBuilder::new();
+            for (code, &length) in huff_lengths[..hlit + 257].iter().enumerate() {
+                huff_lit_length.add_code(code as u32, length);
+            }
+            let mut huff_distance = HuffmanBuilder::new();
+            for (code, &length) in huff_lengths[hlit + 257..].iter().enumerate() {
+                huff_distance.add_code(code as u32, length);
+            }
+
+            let lz_items = decode_block(
+                bitstream,
+                data,
+                huff_lit_length
+                    .build()
+                    .context("Invalid literal/length huffman table")?,
+                huff_distance
+                    .build()
+                    .context("Invalid distance huffman table")?,
+            )?;
+
+            Ok(BlockAnalysis {
+                header_item,
+                block_type: BlockType::DynamicHuffman {
+                    huff_header_item,
+                    hlit,
+                    hdist,
+                    hclen,
+                    huff_header_lengths,
+                    huff_header_codes,
+                },
+                lz: lz_items,
+            })
+        }
+        _ => bail!(
+            "Reserved block type {} at bit {}",
+            block_type,
+            header_item.pos
+        ),
+    }
 }
 
 pub struct Analysis {
@@ -174,10 +260,14 @@ pub struct Analysis {
     blocks: Vec<BlockAnalysis>,
 }
 
+/// The sizes of a block in bits.
+#[derive(Serialize)]
 pub struct BlockSizes {
-    header: usize,
-    huffman: usize,
-    body: usize,
+    /// The block header, including the alignment and length of stored blocks.
+    pub header: usize,
+    /// The huffman tables of dynamic blocks.
+    pub huffman: usize,
+    pub body: usize,
 }
 
 impl Analysis {
@@ -189,8 +279,23 @@ impl Analysis {
         let mut pos = 0;
         for block in &self.blocks {
             match block.block_type {
+                BlockType::Stored {
+                    ref align_item,
+                    ref len_item,
+                    ref nlen_item,
+                    len,
+                } => {
+                    disass_line(&[&block.header_item], "stored block".to_string());
+                    if align_item.length > 0 {
+                        disass_line(&[align_item], "align to byte".to_string());
+                    }
+                    disass_line(
+                        &[len_item, nlen_item],
+                        format!("len: {}, nlen: {}", len, !len & 0xffff),
+                    );
+                }
                 BlockType::StaticHuffman => {
-                    disass_line(&[&block.header_item], format!("static huffman block"));
+                    disass_line(&[&block.header_item], "static huffman block".to_string());
                 }
                 BlockType::DynamicHuffman {
                     ref huff_header_item,
@@ -200,7 +305,7 @@ impl Analysis {
                     ref huff_header_lengths,
                     ref huff_header_codes,
                 } => {
-                    disass_line(&[&block.header_item], format!("dynamic huffman block"));
+                    disass_line(&[&block.header_item], "dynamic huffman block".to_string());
                     disass_line(
                         &[huff_header_item],
                         format!("hlit: {}, hdist: {}, hclen: {}", hlit, hdist, hclen),
@@ -254,7 +359,7 @@ impl Analysis {
             for lz_item in &block.lz {
                 match *lz_item {
                     LzItem::EndOfBlock { ref item } => {
-                        disass_line(&[item], format!("end of block"))
+                        disass_line(&[item], "end of block".to_string())
                     }
                     LzItem::Literal { ref item, byte } => {
                         disass_line(&[item], format!("lit '{}'", cp437::MAPPING[byte as usize]));
@@ -315,7 +420,7 @@ impl Analysis {
                 print!("\n ");
                 pos = 1;
             }
-            let color = colors[(cost.round() as usize).max(1).min(8) - 1];
+            let color = colors[(cost.round() as usize).clamp(1, 8) - 1];
             print!(
                 "{}",
                 crossterm::style::style(cp437::MAPPING[byte as usize])
@@ -340,8 +445,22 @@ impl Analysis {
 
     pub fn sizes(&self) -> Vec<BlockSizes> {
         self.blocks.iter().map(|block| {
+            let header_size = match block.block_type {
+                BlockType::Stored {
+                    ref align_item,
+                    ref len_item,
+                    ref nlen_item,
+                    ..
+                } => {
+                    block.header_item.length
+                        + align_item.length
+                        + len_item.length
+                        + nlen_item.length
+                }
+                _ => block.header_item.length,
+            };
             let huffman_size = match block.block_type {
-                BlockType::StaticHuffman => 0,
+                BlockType::Stored { .. } | BlockType::StaticHuffman => 0,
                 BlockType::DynamicHuffman {
                     ref huff_header_item,
                     ref huff_header_lengths,
@@ -394,13 +513,84 @@ impl Analysis {
                 })
                 .sum::<usize>();
             BlockSizes {
-                header: 3,
+                header: header_size,
                 huffman: huffman_size,
                 body: body_size
             }
         }).collect()
     }
 
+    /// The LZ parse of each block, as used by the encoder.
+    pub fn lz_symbols(&self) -> Vec<Vec<Symbol>> {
+        self.blocks
+            .iter()
+            .map(|block| {
+                block
+                    .lz
+                    .iter()
+                    .filter_map(|lz_item| match *lz_item {
+                        LzItem::Literal { byte, .. } => Some(Symbol::Literal(byte)),
+                        LzItem::Match { length, offset, .. } => Some(Symbol::Match {
+                            length,
+                            distance: offset,
+                        }),
+                        LzItem::EndOfBlock { .. } => None,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The LZ parse of each block with the position and size of each item in the
+    /// bitstream, for reports.
+    pub fn lz_entries(&self) -> Vec<Vec<LzEntry>> {
+        let mut offset = 0;
+        self.blocks
+            .iter()
+            .map(|block| {
+                block
+                    .lz
+                    .iter()
+                    .map(|lz_item| match *lz_item {
+                        LzItem::Literal { byte, ref item } => {
+                            offset += 1;
+                            LzEntry::Literal {
+                                bit_pos: item.pos,
+                                bits: item.length,
+                                offset: offset - 1,
+                                byte,
+                            }
+                        }
+                        LzItem::Match {
+                            length,
+                            offset: distance,
+                            ref length_base,
+                            ref length_ext,
+                            ref offset_base,
+                            ref offset_ext,
+                        } => {
+                            offset += length as usize;
+                            LzEntry::Match {
+                                bit_pos: length_base.pos,
+                                bits: length_base.length
+                                    + length_ext.length
+                                    + offset_base.length
+                                    + offset_ext.length,
+                                offset: offset - length as usize,
+                                length,
+                                distance,
+                            }
+                        }
+                        LzItem::EndOfBlock { ref item } => LzEntry::EndOfBlock {
+                            bit_pos: item.pos,
+                            bits: item.length,
+                        },
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
     pub fn total_size(&self) -> usize {
         self.sizes().into_iter().map(|b| b.header + b.huffman + b.body).sum()
     }
@@ -460,6 +650,50 @@ pub struct AnalysisData {
     pub block_type: Vec<u8>
 }
 
+impl AnalysisData {
+    /// How often each byte is encoded as a literal, most frequent first.
+    pub fn literal_counts(&self) -> Vec<(u8, usize)> {
+        let mut counts = [0usize; 256];
+        for (&c, &lit_index) in self.unpacked.iter().zip(self.literal_index.iter()) {
+            if lit_index == usize::MAX {
+                counts[c as usize] += 1;
+            }
+        }
+        let mut counts: Vec<(u8, usize)> = counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(c, &count)| (c as u8, count))
+            .collect();
+        counts.sort_by_key(|&(c, count)| (std::cmp::Reverse(count), c));
+        counts
+    }
+}
+
+/// An item of the LZ parse of a block. `bit_pos` and `bits` locate it in the bitstream,
+/// `offset` is the position of the first byte it produces in the uncompressed data.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LzEntry {
+    Literal {
+        bit_pos: usize,
+        bits: usize,
+        offset: usize,
+        byte: u8,
+    },
+    Match {
+        bit_pos: usize,
+        bits: usize,
+        offset: usize,
+        length: u32,
+        distance: u32,
+    },
+    EndOfBlock {
+        bit_pos: usize,
+        bits: usize,
+    },
+}
+
 struct BlockAnalysis {
     block_type: BlockType,
     header_item: BitstreamItem,
@@ -467,7 +701,12 @@ struct BlockAnalysis {
 }
 
 enum BlockType {
-    //    Uncompressed,
+    Stored {
+        align_item: BitstreamItem,
+        len_item: BitstreamItem,
+        nlen_item: BitstreamItem,
+        len: u32,
+    },
     StaticHuffman,
     DynamicHuffman {
         huff_header_item: BitstreamItem,
@@ -519,16 +758,19 @@ fn decode_block(
     data: &mut AnalysisData,
     huff_lit_length: Huffman,
     huff_distance: Huffman,
-) -> Vec<LzItem> {
+) -> Result<Vec<LzItem>> {
     let mut lz_items = vec![];
     loop {
-        let lit_length = huff_lit_length.read(bitstream);
+        if bitstream.is_at_end() {
+            bail!("Missing end-of-block at bit {}", bitstream.pos);
+        }
+        let lit_length = huff_lit_length.read(bitstream)?;
         let lit_length_item = bitstream.take_item();
         if lit_length == 256 {
             lz_items.push(LzItem::EndOfBlock {
                 item: lit_length_item,
             });
-            return lz_items;
+            return Ok(lz_items);
         }
 
         if lit_length < 256 {
@@ -540,75 +782,37 @@ fn decode_block(
             data.unpacked.push(lit_length as u8);
             data.literal_index.push(usize::MAX);
         } else {
-            let (extra_bits, base_length) = [
-                (0, 3),
-                (0, 4),
-                (0, 5),
-                (0, 6),
-                (0, 7),
-                (0, 8),
-                (0, 9),
-                (0, 10),
-                (1, 11),
-                (1, 13),
-                (1, 15),
-                (1, 17),
-                (2, 19),
-                (2, 23),
-                (2, 27),
-                (2, 31),
-                (3, 35),
-                (3, 43),
-                (3, 51),
-                (3, 59),
-                (4, 67),
-                (4, 83),
-                (4, 99),
-                (4, 115),
-                (5, 131),
-                (5, 163),
-                (5, 195),
-                (5, 227),
-                (0, 258),
-            ][lit_length as usize - 257];
-            let length = base_length + bitstream.get_bits(extra_bits);
+            let &(extra_bits, base_length) = LENGTH_CODES
+                .get(lit_length as usize - 257)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid length code {} at bit {}",
+                        lit_length,
+                        lit_length_item.pos
+                    )
+                })?;
+            let length = base_length + bitstream.get_bits(extra_bits)?;
             let length_ext = bitstream.take_item();
-            let offset_index = huff_distance.read(bitstream);
+            let offset_index = huff_distance.read(bitstream)?;
             let offset_base = bitstream.take_item();
-            let (extra_bits, base_distance) = [
-                (0, 1),
-                (0, 2),
-                (0, 3),
-                (0, 4),
-                (1, 5),
-                (1, 7),
-                (2, 9),
-                (2, 13),
-                (3, 17),
-                (3, 25),
-                (4, 33),
-                (4, 49),
-                (5, 65),
-                (5, 97),
-                (6, 129),
-                (6, 193),
-                (7, 257),
-                (7, 385),
-                (8, 513),
-                (8, 769),
-                (9, 1025),
-                (9, 1537),
-                (10, 2049),
-                (10, 3073),
-                (11, 4097),
-                (11, 6145),
-                (12, 8193),
-                (12, 12289),
-                (13, 16385),
-                (13, 24577),
-            ][offset_index as usize];
-            let distance = base_distance + bitstream.get_bits(extra_bits);
+            let &(extra_bits, base_distance) =
+                DISTANCE_CODES.get(offset_index as usize).ok_or_else(|| {
+                    anyhow!(
+                        "Invalid distance code {} at bit {}",
+                        offset_index,
+                        offset_base.pos
+                    )
+                })?;
+            let distance = base_distance + bitstream.get_bits(extra_bits)?;
             let offset_ext = bitstream.take_item();
+            if distance as usize > data.unpacked.len() {
+                bail!(
+                    "Distance {} beyond window of {} bytes at bit {}",
+                    distance,
+                    data.unpacked.len(),
+                    offset_base.pos
+                );
+            }
             let cost = (lit_length_item.length
                 + length_ext.length
                 + offset_base.length
@@ -637,6 +841,75 @@ fn decode_block(
     }
 }
 
+pub const CODE_LENGTH_ORDER: [u32; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub const LENGTH_CODES: [(u32, u32); 29] = [
+    (0, 3),
+    (0, 4),
+    (0, 5),
+    (0, 6),
+    (0, 7),
+    (0, 8),
+    (0, 9),
+    (0, 10),
+    (1, 11),
+    (1, 13),
+    (1, 15),
+    (1, 17),
+    (2, 19),
+    (2, 23),
+    (2, 27),
+    (2, 31),
+    (3, 35),
+    (3, 43),
+    (3, 51),
+    (3, 59),
+    (4, 67),
+    (4, 83),
+    (4, 99),
+    (4, 115),
+    (5, 131),
+    (5, 163),
+    (5, 195),
+    (5, 227),
+    (0, 258),
+];
+
+pub const DISTANCE_CODES: [(u32, u32); 30] = [
+    (0, 1),
+    (0, 2),
+    (0, 3),
+    (0, 4),
+    (1, 5),
+    (1, 7),
+    (2, 9),
+    (2, 13),
+    (3, 17),
+    (3, 25),
+    (4, 33),
+    (4, 49),
+    (5, 65),
+    (5, 97),
+    (6, 129),
+    (6, 193),
+    (7, 257),
+    (7, 385),
+    (8, 513),
+    (8, 769),
+    (9, 1025),
+    (9, 1537),
+    (10, 2049),
+    (10, 3073),
+    (11, 4097),
+    (11, 6145),
+    (12, 8193),
+    (12, 12289),
+    (13, 16385),
+    (13, 24577),
+];
+
 struct HuffmanBuilder {
     codes: Vec<(u32, u32)>,
 }
@@ -660,10 +933,14 @@ impl HuffmanBuilder {
         }
     }
 
-    fn build(mut self) -> Huffman {
+    fn build(mut self) -> Result<Huffman> {
+        let kraft_sum: u64 = self.codes.iter().map(|&(_, bits)| 1 << (15 - bits)).sum();
+        if kraft_sum > 1 << 15 {
+            bail!("Over-subscribed huffman code lengths");
+        }
         self.codes
             .sort_unstable_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
-        Huffman { codes: self.codes }
+        Ok(Huffman { codes: self.codes })
     }
 }
 
@@ -672,20 +949,21 @@ struct Huffman {
 }
 
 impl Huffman {
-    fn read(&self, bitstream: &mut Bitstream) -> u32 {
+    fn read(&self, bitstream: &mut Bitstream) -> Result<u32> {
+        let start = bitstream.pos;
         let mut code = 0;
         let mut num_bits = 0;
         for &(value, length) in &self.codes {
             while num_bits < length {
-                code = (code << 1) | bitstream.get_bit();
+                code = (code << 1) | bitstream.get_bit()?;
                 num_bits += 1;
             }
             if code == 0 {
-                return value;
+                return Ok(value);
             }
             code -= 1;
         }
-        panic!("No value found for huffman code")
+        bail!("Invalid huffman code at bit {}", start)
     }
 }
 
@@ -710,27 +988,86 @@ impl<'a> Bitstream<'a> {
         }
     }
 
-    fn get_bit(&mut self) -> u32 {
-        let bit = (self.data[self.pos >> 3] >> (self.pos & 7)) as u32 & 1;
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.data.len() * 8
+    }
+
+    fn bit_at(&self, pos: usize) -> u32 {
+        (self.data[pos >> 3] >> (pos & 7)) as u32 & 1
+    }
+
+    fn get_bit(&mut self) -> Result<u32> {
+        if self.is_at_end() {
+            bail!("Unexpected end of data at bit {}", self.pos);
+        }
+        let bit = self.bit_at(self.pos);
         self.pos += 1;
-        bit
+        Ok(bit)
     }
 
-    fn get_bits(&mut self, num_bits: u32) -> u32 {
+    fn align_to_byte(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+
+    fn get_bits(&mut self, num_bits: u32) -> Result<u32> {
         let mut value = 0;
         for i in 0..num_bits {
-            value |= self.get_bit() << i;
+            value |= self.get_bit()? << i;
         }
-        value
+        Ok(value)
     }
 
     fn take_item(&mut self) -> BitstreamItem {
         let length = self.pos - self.item_start;
         assert!(length <= 32);
         let pos = self.item_start;
-        self.pos = pos;
-        let bits = self.get_bits(length as u32);
+        let bits = (0..length).fold(0, |bits, i| bits | (self.bit_at(pos + i) << i));
         self.item_start = self.pos;
         BitstreamItem { pos, length, bits }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(vec![], flate2::Compression::new(level));
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn stored_block() {
+        let code = b"cls()print('hi')";
+        let analysis = analyze(&deflate(code, 0)).unwrap();
+        assert_eq!(analysis.data().unpacked, code);
+        assert!(analysis.data().block_type.iter().all(|&t| t == 0));
+        assert!(analysis.data().cost.iter().all(|&c| c == 8.));
+        let total_bits = analysis.total_size();
+        assert_eq!(total_bits, 8 * (5 + code.len()));
+    }
+
+    #[test]
+    fn reserved_block_type() {
+        assert!(analyze(&[0x07, 0x00]).is_err());
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let compressed = deflate(b"function TIC()cls(t)t=t+1 end", 9);
+        for len in 0..compressed.len() {
+            assert!(analyze(&compressed[..len]).is_err());
+        }
+        assert!(analyze(&compressed).is_ok());
+    }
+
+    #[test]
+    fn distance_beyond_window() {
+        // static block: match of length 3 (code 257) at distance 1 with no preceding data
+        let err = analyze(&[0x03, 0x02, 0x00]).err().unwrap();
+        assert!(format!("{:#}", err).contains("beyond window"));
+    }
+}
@@ -0,0 +1,928 @@
+//! A deflate encoder optimizing the LZ parse and the huffman tables for the smallest
+//! possible output on small inputs.
+
+use super::deflate::{CODE_LENGTH_ORDER, DISTANCE_CODES, LENGTH_CODES};
+
+const WINDOW_SIZE: usize = 32768;
+const MAX_MATCH: usize = 258;
+
+/// Compresses `data` into a raw deflate stream consisting of a single block.
+///
+/// Unlike zopfli, which is tuned for large inputs, this searches for the LZ parse
+/// that is optimal under the exact bit costs of the current huffman tables and
+/// refines the tables over `iterations` rounds, trying both static and dynamic
+/// encodings of each parse.
+pub fn compress(data: &[u8], iterations: u32) -> Vec<u8> {
+    let matches = MatchTable::new(data);
+    let block = optimize_block(data, &matches, iterations);
+    let mut writer = BitWriter::new();
+    block.write(&mut writer, true);
+    writer.finish()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symbol {
+    Literal(u8),
+    Match { length: u32, distance: u32 },
+}
+
+#[derive(Clone)]
+pub struct HuffmanTables {
+    pub lit_lengths: Vec<u32>,
+    pub dist_lengths: Vec<u32>,
+}
+
+impl HuffmanTables {
+    pub fn fixed() -> HuffmanTables {
+        let mut lit_lengths = vec![8; 144];
+        lit_lengths.resize(256, 9);
+        lit_lengths.resize(280, 7);
+        lit_lengths.resize(288, 8);
+        HuffmanTables {
+            lit_lengths,
+            dist_lengths: vec![5; 30],
+        }
+    }
+
+    pub fn from_symbols(symbols: &[Symbol]) -> HuffmanTables {
+        let (lit_freqs, dist_freqs) = symbol_frequencies(symbols);
+        HuffmanTables {
+            lit_lengths: limited_huffman_lengths(&lit_freqs, 15),
+            dist_lengths: limited_huffman_lengths(&dist_freqs, 15),
+        }
+    }
+
+    fn body_bits(&self, symbols: &[Symbol]) -> usize {
+        let mut bits = self.lit_lengths[256] as usize;
+        for symbol in symbols {
+            bits += match *symbol {
+                Symbol::Literal(byte) => self.lit_lengths[byte as usize],
+                Symbol::Match { length, distance } => {
+                    let (length_code, length_extra, _) = length_symbol(length);
+                    let (dist_code, dist_extra, _) = distance_symbol(distance);
+                    self.lit_lengths[257 + length_code]
+                        + length_extra
+                        + self.dist_lengths[dist_code]
+                        + dist_extra
+                }
+            } as usize;
+        }
+        bits
+    }
+}
+
+#[derive(Clone)]
+pub enum BlockEncoding {
+    Static,
+    Dynamic {
+        tables: HuffmanTables,
+        header: CodeLengthHeader,
+    },
+}
+
+#[derive(Clone)]
+pub struct EncodedBlock {
+    pub symbols: Vec<Symbol>,
+    pub encoding: BlockEncoding,
+    bits: usize,
+}
+
+impl EncodedBlock {
+    pub fn new_static(symbols: Vec<Symbol>) -> EncodedBlock {
+        let bits = 3 + HuffmanTables::fixed().body_bits(&symbols);
+        EncodedBlock {
+            symbols,
+            encoding: BlockEncoding::Static,
+            bits,
+        }
+    }
+
+    pub fn new_dynamic(symbols: Vec<Symbol>, tables: HuffmanTables) -> EncodedBlock {
+        let header = CodeLengthHeader::new(&tables);
+        let bits = 3 + header.bits() + tables.body_bits(&symbols);
+        EncodedBlock {
+            symbols,
+            encoding: BlockEncoding::Dynamic { tables, header },
+            bits,
+        }
+    }
+
+    /// Exact size of the block in bits, including the 3 bit block header.
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn write(&self, writer: &mut BitWriter, is_final: bool) {
+        writer.write_bits(is_final as u32, 1);
+        let fixed;
+        let tables = match self.encoding {
+            BlockEncoding::Static => {
+                writer.write_bits(1, 2);
+                fixed = HuffmanTables::fixed();
+                &fixed
+            }
+            BlockEncoding::Dynamic {
+                ref tables,
+                ref header,
+            } => {
+                writer.write_bits(2, 2);
+                header.write(writer);
+                tables
+            }
+        };
+
+        let lit_codes = canonical_codes(&tables.lit_lengths);
+        let dist_codes = canonical_codes(&tables.dist_lengths);
+        let write_lit = |writer: &mut BitWriter, code: usize| {
+            writer.write_code(lit_codes[code], tables.lit_lengths[code])
+        };
+        for symbol in &self.symbols {
+            match *symbol {
+                Symbol::Literal(byte) => write_lit(writer, byte as usize),
+                Symbol::Match { length, distance } => {
+                    let (length_code, extra_bits, extra) = length_symbol(length);
+                    write_lit(writer, 257 + length_code);
+                    writer.write_bits(extra, extra_bits);
+                    let (dist_code, extra_bits, extra) = distance_symbol(distance);
+                    writer.write_code(dist_codes[dist_code], tables.dist_lengths[dist_code]);
+                    writer.write_bits(extra, extra_bits);
+                }
+            }
+        }
+        write_lit(writer, 256);
+    }
+}
+
+/// The code length sequence of a dynamic block, run length encoded with the
+/// repeat/skip codes 16, 17 and 18.
+#[derive(Clone)]
+pub struct CodeLengthHeader {
+    num_lit: usize,
+    num_dist: usize,
+    cl_lengths: [u32; 19],
+    codes: Vec<(u32, u32)>,
+}
+
+impl CodeLengthHeader {
+    pub fn new(tables: &HuffmanTables) -> CodeLengthHeader {
+        let (num_lit, num_dist, sequence) = Self::sequence(tables);
+        let mut best: Option<CodeLengthHeader> = None;
+        for &initial_cost in &[4, 2, 6] {
+            let mut costs = [initial_cost; 19];
+            for _ in 0..8 {
+                let header = Self::encode(num_lit, num_dist, &sequence, &costs);
+                let new_costs = unused_as(&header.cl_lengths, 8);
+                match best {
+                    Some(ref b) if b.bits() <= header.bits() => (),
+                    _ => best = Some(header),
+                }
+                if new_costs == costs {
+                    break;
+                }
+                costs = new_costs;
+            }
+        }
+        best.unwrap()
+    }
+
+    /// Encodes the code lengths with a single pass for the given code length code costs.
+    fn with_costs(tables: &HuffmanTables, costs: &[u32; 19]) -> CodeLengthHeader {
+        let (num_lit, num_dist, sequence) = Self::sequence(tables);
+        Self::encode(num_lit, num_dist, &sequence, costs)
+    }
+
+    fn sequence(tables: &HuffmanTables) -> (usize, usize, Vec<u32>) {
+        fn used_len(lengths: &[u32], min: usize) -> usize {
+            lengths
+                .iter()
+                .rposition(|&l| l > 0)
+                .map_or(0, |p| p + 1)
+                .max(min)
+        }
+        let num_lit = used_len(&tables.lit_lengths, 257);
+        let num_dist = used_len(&tables.dist_lengths, 1);
+        let mut sequence = tables.lit_lengths[..num_lit].to_vec();
+        sequence.extend_from_slice(&tables.dist_lengths[..num_dist]);
+        (num_lit, num_dist, sequence)
+    }
+
+    fn encode(
+        num_lit: usize,
+        num_dist: usize,
+        sequence: &[u32],
+        costs: &[u32; 19],
+    ) -> CodeLengthHeader {
+        let codes = rle_code_lengths(sequence, costs);
+        let mut freqs = [0u32; 19];
+        for &(symbol, _) in &codes {
+            freqs[symbol as usize] += 1;
+        }
+        CodeLengthHeader {
+            num_lit,
+            num_dist,
+            cl_lengths: code_length_code_lengths(&freqs),
+            codes,
+        }
+    }
+
+    pub fn hclen(&self) -> usize {
+        CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&c| self.cl_lengths[c as usize] > 0)
+            .map_or(4, |p| (p + 1).max(4))
+    }
+
+    pub fn bits(&self) -> usize {
+        14 + 3 * self.hclen()
+            + self
+                .codes
+                .iter()
+                .map(|&(symbol, _)| {
+                    (self.cl_lengths[symbol as usize] + cl_extra_bits(symbol)) as usize
+                })
+                .sum::<usize>()
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        let hclen = self.hclen();
+        writer.write_bits(self.num_lit as u32 - 257, 5);
+        writer.write_bits(self.num_dist as u32 - 1, 5);
+        writer.write_bits(hclen as u32 - 4, 4);
+        for &code in &CODE_LENGTH_ORDER[..hclen] {
+            writer.write_bits(self.cl_lengths[code as usize], 3);
+        }
+        let cl_codes = canonical_codes(&self.cl_lengths);
+        for &(symbol, extra) in &self.codes {
+            writer.write_code(cl_codes[symbol as usize], self.cl_lengths[symbol as usize]);
+            writer.write_bits(extra, cl_extra_bits(symbol));
+        }
+    }
+}
+
+fn cl_extra_bits(symbol: u32) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Finds the cheapest run length encoding of the code length sequence for the
+/// given code length code costs.
+fn rle_code_lengths(sequence: &[u32], costs: &[u32; 19]) -> Vec<(u32, u32)> {
+    let n = sequence.len();
+    let mut total = vec![0u32; n + 1];
+    let mut choice = vec![(0u32, 0u32, 1usize); n];
+    for i in (0..n).rev() {
+        let value = sequence[i];
+        let mut best = total[i + 1] + costs[value as usize];
+        let mut best_choice = (value, 0, 1);
+        let run = sequence[i..].iter().take_while(|&&v| v == value).count();
+        let mut consider = |symbol: u32, extra: usize, count: usize| {
+            let cost = total[i + count] + costs[symbol as usize] + cl_extra_bits(symbol);
+            if cost < best {
+                best = cost;
+                best_choice = (symbol, extra as u32, count);
+            }
+        };
+        if value == 0 {
+            for count in 3..=run.min(10) {
+                consider(17, count - 3, count);
+            }
+            for count in 11..=run.min(138) {
+                consider(18, count - 11, count);
+            }
+        }
+        if i > 0 && sequence[i - 1] == value {
+            for count in 3..=run.min(6) {
+                consider(16, count - 3, count);
+            }
+        }
+        total[i] = best;
+        choice[i] = best_choice;
+    }
+
+    let mut codes = vec![];
+    let mut i = 0;
+    while i < n {
+        let (symbol, extra, count) = choice[i];
+        codes.push((symbol, extra));
+        i += count;
+    }
+    codes
+}
+
+fn code_length_code_lengths(freqs: &[u32; 19]) -> [u32; 19] {
+    let mut lengths = [0; 19];
+    lengths.copy_from_slice(&limited_huffman_lengths(freqs, 7));
+    if lengths.iter().filter(|&&l| l > 0).count() == 1 {
+        // a code length code with a single symbol would be incomplete,
+        // which zlib rejects
+        let &other = CODE_LENGTH_ORDER
+            .iter()
+            .find(|&&c| lengths[c as usize] == 0)
+            .unwrap();
+        lengths[other as usize] = 1;
+    }
+    lengths
+}
+
+fn unused_as(lengths: &[u32; 19], cost: u32) -> [u32; 19] {
+    let mut costs = *lengths;
+    for c in costs.iter_mut() {
+        if *c == 0 {
+            *c = cost;
+        }
+    }
+    costs
+}
+
+/// Computes huffman code lengths limited to `max_bits` using the package-merge algorithm.
+pub fn limited_huffman_lengths(freqs: &[u32], max_bits: u32) -> Vec<u32> {
+    let mut lengths = vec![0; freqs.len()];
+    let mut leaves: Vec<(u64, usize)> = freqs
+        .iter()
+        .enumerate()
+        .filter(|&(_, &f)| f > 0)
+        .map(|(symbol, &f)| (f as u64, symbol))
+        .collect();
+    if leaves.len() <= 1 {
+        for &(_, symbol) in &leaves {
+            lengths[symbol] = 1;
+        }
+        return lengths;
+    }
+    leaves.sort_unstable();
+
+    let leaf_items: Vec<(u64, Vec<usize>)> =
+        leaves.iter().map(|&(f, symbol)| (f, vec![symbol])).collect();
+    let mut items = leaf_items.clone();
+    for _ in 1..max_bits {
+        let packages = items
+            .chunks_exact(2)
+            .map(|pair| (pair[0].0 + pair[1].0, [&pair[0].1[..], &pair[1].1[..]].concat()));
+        let mut merged: Vec<(u64, Vec<usize>)> = leaf_items.iter().cloned().chain(packages).collect();
+        merged.sort_by_key(|item| item.0);
+        items = merged;
+    }
+    for item in &items[..2 * leaves.len() - 2] {
+        for &symbol in &item.1 {
+            lengths[symbol] += 1;
+        }
+    }
+    lengths
+}
+
+pub fn canonical_codes(lengths: &[u32]) -> Vec<u32> {
+    let max_bits = lengths.iter().copied().max().unwrap_or(0) as usize;
+    let mut bl_count = vec![0u32; max_bits + 1];
+    for &l in lengths {
+        if l > 0 {
+            bl_count[l as usize] += 1;
+        }
+    }
+    let mut next_code = vec![0u32; max_bits + 1];
+    let mut code = 0;
+    for bits in 1..=max_bits {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lengths
+        .iter()
+        .map(|&l| {
+            if l > 0 {
+                let code = next_code[l as usize];
+                next_code[l as usize] += 1;
+                code
+            } else {
+                0
+            }
+        })
+        .collect()
+}
+
+pub fn length_symbol(length: u32) -> (usize, u32, u32) {
+    let index = LENGTH_CODES
+        .iter()
+        .rposition(|&(_, base)| base <= length)
+        .unwrap();
+    let (extra_bits, base) = LENGTH_CODES[index];
+    (index, extra_bits, length - base)
+}
+
+pub fn distance_symbol(distance: u32) -> (usize, u32, u32) {
+    let index = DISTANCE_CODES
+        .iter()
+        .rposition(|&(_, base)| base <= distance)
+        .unwrap();
+    let (extra_bits, base) = DISTANCE_CODES[index];
+    (index, extra_bits, distance - base)
+}
+
+fn symbol_frequencies(symbols: &[Symbol]) -> (Vec<u32>, Vec<u32>) {
+    let mut lit_freqs = vec![0u32; 286];
+    let mut dist_freqs = vec![0u32; 30];
+    lit_freqs[256] = 1;
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Literal(byte) => lit_freqs[byte as usize] += 1,
+            Symbol::Match { length, distance } => {
+                lit_freqs[257 + length_symbol(length).0] += 1;
+                dist_freqs[distance_symbol(distance).0] += 1;
+            }
+        }
+    }
+    (lit_freqs, dist_freqs)
+}
+
+/// For each position and distance code, the longest match (and a distance achieving it).
+struct MatchTable {
+    max_length: Vec<[u16; 30]>,
+    distance: Vec<[u16; 30]>,
+}
+
+impl MatchTable {
+    fn new(data: &[u8]) -> MatchTable {
+        let n = data.len();
+        let mut max_length = vec![[0u16; 30]; n];
+        let mut distance = vec![[0u16; 30]; n];
+        for d in 1..n.min(WINDOW_SIZE + 1) {
+            let code = distance_symbol(d as u32).0;
+            let mut run = 0usize;
+            for i in (d..n).rev() {
+                if data[i] == data[i - d] {
+                    run += 1;
+                } else {
+                    run = 0;
+                }
+                let length = run.min(MAX_MATCH) as u16;
+                if length > max_length[i][code] {
+                    max_length[i][code] = length;
+                    distance[i][code] = d as u16;
+                }
+            }
+        }
+        MatchTable {
+            max_length,
+            distance,
+        }
+    }
+}
+
+/// Bit costs of each literal, match length and distance code under a set of huffman tables.
+struct CostModel {
+    literal: Vec<u32>,
+    length: Vec<u32>,
+    distance: Vec<u32>,
+}
+
+impl CostModel {
+    fn new(tables: &HuffmanTables) -> CostModel {
+        // symbols not in the tables get a pessimistic estimate so that the next
+        // parse can still pick them up if they are worth it
+        let unused_lit = tables.lit_lengths.iter().copied().max().unwrap_or(0) + 2;
+        let unused_dist = tables.dist_lengths.iter().copied().max().unwrap_or(0) + 2;
+        let lit = |code: usize| match tables.lit_lengths[code] {
+            0 => unused_lit,
+            l => l,
+        };
+        let dist = |code: usize| match tables.dist_lengths[code] {
+            0 => unused_dist,
+            l => l,
+        };
+        let mut length = vec![0; MAX_MATCH + 1];
+        for (l, cost) in length.iter_mut().enumerate().skip(3) {
+            let (code, extra_bits, _) = length_symbol(l as u32);
+            *cost = lit(257 + code) + extra_bits;
+        }
+        CostModel {
+            literal: (0..256).map(lit).collect(),
+            length,
+            distance: DISTANCE_CODES
+                .iter()
+                .enumerate()
+                .map(|(code, &(extra_bits, _))| dist(code) + extra_bits)
+                .collect(),
+        }
+    }
+}
+
+/// Finds the parse with the lowest total bit cost under the given cost model.
+fn optimal_parse(data: &[u8], matches: &MatchTable, costs: &CostModel) -> Vec<Symbol> {
+    let n = data.len();
+    let mut dist_order: Vec<usize> = (0..DISTANCE_CODES.len()).collect();
+    dist_order.sort_by_key(|&code| costs.distance[code]);
+
+    let mut total = vec![0u32; n + 1];
+    let mut choice = vec![(1usize, 0u32); n];
+    for i in (0..n).rev() {
+        let mut best = total[i + 1] + costs.literal[data[i] as usize];
+        let mut best_choice = (1, 0);
+        // lengths up to `covered` are already available with a cheaper distance code
+        let mut covered = 2;
+        for &code in &dist_order {
+            let max_length = matches.max_length[i][code] as usize;
+            if max_length <= covered {
+                continue;
+            }
+            let distance_cost = costs.distance[code];
+            for length in covered + 1..=max_length {
+                let cost = total[i + length] + costs.length[length] + distance_cost;
+                if cost < best {
+                    best = cost;
+                    best_choice = (length, matches.distance[i][code] as u32);
+                }
+            }
+            covered = max_length;
+        }
+        total[i] = best;
+        choice[i] = best_choice;
+    }
+
+    collect_symbols(data, &choice)
+}
+
+fn greedy_parse(data: &[u8], matches: &MatchTable) -> Vec<Symbol> {
+    let choice: Vec<(usize, u32)> = (0..data.len())
+        .map(|i| {
+            (0..DISTANCE_CODES.len())
+                .map(|code| {
+                    (
+                        matches.max_length[i][code] as usize,
+                        matches.distance[i][code] as u32,
+                    )
+                })
+                .filter(|&(length, _)| length >= 3)
+                .max_by_key(|&(length, distance)| (length, std::cmp::Reverse(distance)))
+                .unwrap_or((1, 0))
+        })
+        .collect();
+    collect_symbols(data, &choice)
+}
+
+fn collect_symbols(data: &[u8], choice: &[(usize, u32)]) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = choice[i];
+        if length == 1 {
+            symbols.push(Symbol::Literal(data[i]));
+        } else {
+            symbols.push(Symbol::Match {
+                length: length as u32,
+                distance,
+            });
+        }
+        i += length;
+    }
+    symbols
+}
+
+fn optimize_block(data: &[u8], matches: &MatchTable, iterations: u32) -> EncodedBlock {
+    let static_parse = optimal_parse(data, matches, &CostModel::new(&HuffmanTables::fixed()));
+    let mut best = EncodedBlock::new_static(static_parse.clone());
+
+    let start_parses = vec![
+        static_parse,
+        greedy_parse(data, matches),
+        data.iter().map(|&b| Symbol::Literal(b)).collect(),
+    ];
+    for mut symbols in start_parses {
+        for _ in 0..iterations.max(1) {
+            let tables = HuffmanTables::from_symbols(&symbols);
+            let costs = CostModel::new(&tables);
+            let block = EncodedBlock::new_dynamic(symbols.clone(), tables);
+            if block.bits() < best.bits() {
+                best = block;
+            }
+            let static_block = EncodedBlock::new_static(symbols.clone());
+            if static_block.bits() < best.bits() {
+                best = static_block;
+            }
+
+            let new_symbols = optimal_parse(data, matches, &costs);
+            if new_symbols == symbols {
+                break;
+            }
+            symbols = new_symbols;
+        }
+    }
+
+    let optimized = optimize_tables(best.symbols.clone());
+    if optimized.bits() < best.bits() {
+        best = optimized;
+    }
+
+    best
+}
+
+/// Re-encodes an existing LZ parse (one list of symbols per block), keeping the parse
+/// and the block boundaries but searching for cheaper huffman tables for each block.
+pub fn reencode(blocks: Vec<Vec<Symbol>>) -> Vec<u8> {
+    let num_blocks = blocks.len();
+    let mut writer = BitWriter::new();
+    for (index, symbols) in blocks.into_iter().enumerate() {
+        optimize_tables(symbols).write(&mut writer, index + 1 == num_blocks);
+    }
+    writer.finish()
+}
+
+const MAX_BODY_PENALTY: i64 = 16;
+const MAX_TABLE_EVALUATIONS: usize = 20000;
+
+/// Searches for the code lengths minimizing header + body size of a block with a
+/// fixed parse.
+///
+/// Plain huffman codes only minimize the body. Here we also try length-limited trees
+/// and counts smoothed to allow longer 16/17/18 runs, followed by a hill climb over
+/// small changes to the code lengths that keep the code complete.
+pub fn optimize_tables(symbols: Vec<Symbol>) -> EncodedBlock {
+    let (lit_freqs, dist_freqs) = symbol_frequencies(&symbols);
+    let table_bits = |tables: &HuffmanTables, costs: Option<&[u32; 19]>| {
+        let header = match costs {
+            Some(costs) => CodeLengthHeader::with_costs(tables, costs),
+            None => CodeLengthHeader::new(tables),
+        };
+        header.bits()
+            + weighted_bits(&tables.lit_lengths, &lit_freqs)
+            + weighted_bits(&tables.dist_lengths, &dist_freqs)
+    };
+
+    let mut best_tables = HuffmanTables::from_symbols(&symbols);
+    let mut best_bits = table_bits(&best_tables, None);
+    for max_bits in 1..=15 {
+        for &tolerance in &[0., 0.25, 0.5, 1.] {
+            let lit_counts = smooth_counts(&lit_freqs, tolerance);
+            let dist_counts = smooth_counts(&dist_freqs, tolerance);
+            let max_used = lit_counts
+                .iter()
+                .filter(|&&c| c > 0)
+                .count()
+                .max(dist_counts.iter().filter(|&&c| c > 0).count());
+            if max_used > 1 << max_bits {
+                continue;
+            }
+            let tables = HuffmanTables {
+                lit_lengths: limited_huffman_lengths(&lit_counts, max_bits),
+                dist_lengths: limited_huffman_lengths(&dist_counts, max_bits),
+            };
+            let bits = table_bits(&tables, None);
+            if bits < best_bits {
+                best_tables = tables;
+                best_bits = bits;
+            }
+        }
+    }
+
+    // hill climb, evaluating headers with the code length costs of the current best
+    // to keep each step cheap
+    let mut costs = unused_as(&CodeLengthHeader::new(&best_tables).cl_lengths, 8);
+    let mut best_quick_bits = table_bits(&best_tables, Some(&costs));
+    let mut evaluations = 0;
+    'search: loop {
+        for &is_lit in &[true, false] {
+            let (lengths, freqs) = if is_lit {
+                (&best_tables.lit_lengths, &lit_freqs)
+            } else {
+                (&best_tables.dist_lengths, &dist_freqs)
+            };
+            for candidate in neighbour_lengths(lengths, freqs) {
+                let mut tables = best_tables.clone();
+                if is_lit {
+                    tables.lit_lengths = candidate;
+                } else {
+                    tables.dist_lengths = candidate;
+                }
+                let bits = table_bits(&tables, Some(&costs));
+                evaluations += 1;
+                if bits < best_quick_bits {
+                    costs = unused_as(&CodeLengthHeader::new(&tables).cl_lengths, 8);
+                    best_quick_bits = table_bits(&tables, Some(&costs));
+                    best_tables = tables;
+                    continue 'search;
+                }
+                if evaluations >= MAX_TABLE_EVALUATIONS {
+                    break 'search;
+                }
+            }
+        }
+        break;
+    }
+
+    let dynamic_block = EncodedBlock::new_dynamic(symbols.clone(), best_tables);
+    let static_block = EncodedBlock::new_static(symbols);
+    if static_block.bits() <= dynamic_block.bits() {
+        static_block
+    } else {
+        dynamic_block
+    }
+}
+
+fn weighted_bits(lengths: &[u32], freqs: &[u32]) -> usize {
+    lengths
+        .iter()
+        .zip(freqs.iter())
+        .map(|(&l, &f)| (l * f) as usize)
+        .sum()
+}
+
+/// Replaces runs of similar non-zero counts by their average, so that the resulting
+/// code lengths are more likely to repeat.
+fn smooth_counts(freqs: &[u32], tolerance: f32) -> Vec<u32> {
+    let mut counts = freqs.to_vec();
+    let mut start = 0;
+    while start < freqs.len() {
+        let mut end = start + 1;
+        let mut sum = freqs[start] as f32;
+        if freqs[start] > 0 {
+            while end < freqs.len() && freqs[end] > 0 {
+                let average = sum / (end - start) as f32;
+                if (freqs[end] as f32 - average).abs() > average * tolerance {
+                    break;
+                }
+                sum += freqs[end] as f32;
+                end += 1;
+            }
+            if end - start >= 3 {
+                let average = ((sum / (end - start) as f32).round() as u32).max(1);
+                for count in &mut counts[start..end] {
+                    *count = average;
+                }
+            }
+        }
+        start = end;
+    }
+    counts
+}
+
+/// Small changes to a complete set of code lengths that keep it complete:
+/// swapping two lengths, giving an unused symbol next to a coded one a code
+/// by splitting another leaf, and dropping codes of unused symbols.
+fn neighbour_lengths(lengths: &[u32], freqs: &[u32]) -> Vec<Vec<u32>> {
+    let mut result = vec![];
+    let coded: Vec<usize> = (0..lengths.len()).filter(|&s| lengths[s] > 0).collect();
+    if coded.len() < 2 {
+        return result;
+    }
+
+    for (index, &a) in coded.iter().enumerate() {
+        for &b in &coded[index + 1..] {
+            let delta = lengths[b] as i64 - lengths[a] as i64;
+            if delta.abs() != 1 {
+                continue;
+            }
+            let penalty = (freqs[a] as i64 - freqs[b] as i64) * delta;
+            if penalty <= MAX_BODY_PENALTY {
+                let mut candidate = lengths.to_vec();
+                candidate.swap(a, b);
+                result.push(candidate);
+            }
+        }
+    }
+
+    for unused in 0..lengths.len() {
+        if lengths[unused] != 0 {
+            continue;
+        }
+        for &neighbour in &[unused.wrapping_sub(1), unused + 1] {
+            let target = match lengths.get(neighbour) {
+                Some(&target) if target >= 2 => target,
+                _ => continue,
+            };
+            let split = coded
+                .iter()
+                .copied()
+                .filter(|&s| lengths[s] == target - 1)
+                .min_by_key(|&s| freqs[s]);
+            if let Some(split) = split {
+                if freqs[split] as i64 <= MAX_BODY_PENALTY {
+                    let mut candidate = lengths.to_vec();
+                    candidate[split] = target;
+                    candidate[unused] = target;
+                    result.push(candidate);
+                }
+            }
+        }
+    }
+
+    for &unused in &coded {
+        if freqs[unused] != 0 || lengths[unused] < 2 {
+            continue;
+        }
+        let partner = coded
+            .iter()
+            .copied()
+            .filter(|&s| s != unused && lengths[s] == lengths[unused])
+            .max_by_key(|&s| freqs[s]);
+        if let Some(partner) = partner {
+            let mut candidate = lengths.to_vec();
+            candidate[unused] = 0;
+            candidate[partner] -= 1;
+            result.push(candidate);
+        }
+    }
+
+    result
+}
+
+#[derive(Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            data: vec![],
+            pos: 0,
+        }
+    }
+
+    pub fn write_bits(&mut self, value: u32, num_bits: u32) {
+        for i in 0..num_bits {
+            if self.pos & 7 == 0 {
+                self.data.push(0);
+            }
+            *self.data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (self.pos & 7);
+            self.pos += 1;
+        }
+    }
+
+    /// Writes a huffman code, which is stored starting with its most significant bit.
+    pub fn write_code(&mut self, code: u32, length: u32) {
+        for i in (0..length).rev() {
+            self.write_bits(code >> i, 1);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    fn roundtrip(data: &[u8]) -> usize {
+        let compressed = compress(data, 5);
+        let mut unpacked = vec![];
+        flate2::read::DeflateDecoder::new(&compressed[..])
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert_eq!(unpacked, data);
+        let analysis = super::super::deflate::analyze(&compressed).unwrap();
+        assert_eq!(analysis.data().unpacked, data);
+        compressed.len()
+    }
+
+    #[test]
+    fn roundtrips() {
+        roundtrip(b"");
+        roundtrip(b"a");
+        roundtrip(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        roundtrip(
+            b"t=0function TIC()cls()for i=0,99 do circ(i*t%240,i*7%136,i%9,i)end t=t+1 end",
+        );
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..3000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8 & 0x3f
+            })
+            .collect();
+        roundtrip(&noise);
+    }
+
+    #[test]
+    fn reencode_keeps_data() {
+        let code: &[u8] =
+            b"t=0function TIC()cls()for i=0,99 do circ(i*t%240,i*7%136,i%9,i)end t=t+1 end";
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(vec![], flate2::Compression::best());
+        std::io::Write::write_all(&mut encoder, code).unwrap();
+        let original = encoder.finish().unwrap();
+        let analysis = super::super::deflate::analyze(&original).unwrap();
+
+        let reencoded = reencode(analysis.lz_symbols());
+        let mut unpacked = vec![];
+        flate2::read::DeflateDecoder::new(&reencoded[..])
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert_eq!(unpacked, code);
+        let new_analysis = super::super::deflate::analyze(&reencoded).unwrap();
+        assert!(new_analysis.total_size() <= analysis.total_size());
+    }
+
+    #[test]
+    fn package_merge_respects_limit() {
+        let freqs: Vec<u32> = (0..20).map(|i| 1 << i).collect();
+        let lengths = limited_huffman_lengths(&freqs, 7);
+        assert!(lengths.iter().all(|&l| l > 0 && l <= 7));
+        let kraft: f64 = lengths.iter().map(|&l| 0.5f64.powi(l as i32)).sum();
+        assert!((kraft - 1.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,19 @@
+//! The library behind the `tic-tool` command line tool: reading and writing TIC-80 carts
+//! (`tic_file`), minifying Lua code (`lua`), suggesting renames (`rename`), compressing
+//! code (`compress`, `encoder`) and analyzing deflate streams (`deflate`), also as
+//! machine-readable reports (`report`), choosing the whitespace between tokens
+//! (`separators`) and the forms of numbers (`number_choice`), and mapping packed code back to
+//! its source (`source_map`).
+
+pub mod compress;
+pub mod cp437;
+pub mod deflate;
+pub mod encoder;
+pub mod lua;
+pub mod number_choice;
+pub mod rename;
+pub mod report;
+pub mod separators;
+pub mod source_map;
+pub mod tic_file;
+pub mod vm;
@@ -1,297 +1,745 @@
+//! Parsing and minifying Lua code: whitespace and comment removal, the transforms given
+//! in directive comments, renames and verifying the result.
+
+use anyhow::Result;
 use lazy_static::lazy_static;
 use regex::bytes::Regex;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+mod api;
+mod ast;
+mod emit;
+mod numbers;
+mod parser;
+mod pretty;
+mod scope;
+
+use ast::{Block, StmtKind};
+use emit::FlatToken;
+use scope::Symbol;
 
+pub use api::TicVersion;
+pub use emit::FunctionSpan;
+pub use numbers::equivalent_forms;
+
+/// Maps symbol keys (see `Symbol::key`) to new names. A plain name as key also renames
+/// locals of that name which have no rename of their own.
 pub type Renaming = BTreeMap<Vec<u8>, Vec<u8>>;
 
+/// Maps numeric literals as written in the source to the equivalent forms to output
+/// instead, see `equivalent_forms`.
+pub type NumberForms = BTreeMap<Vec<u8>, Vec<u8>>;
+
 pub struct Program {
-    tt: TokenTree,
+    block: Block,
+    symbols: Vec<Symbol>,
+    tokens: Vec<FlatToken>,
+    functions: Vec<FunctionSpan>,
+    version: TicVersion,
     pub renames: Renaming,
+    /// Applied by `serialize`.
+    pub number_forms: NumberForms,
+}
+
+/// What produced a byte of the serialized code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SpanKind {
+    Keyword,
+    Identifier,
+    Number,
+    String,
+    /// Operators and other punctuation.
+    Symbol,
+    /// Whitespace needed to separate two tokens.
+    Separator,
+    /// The quotes of a code string.
+    Quote,
+}
+
+impl SpanKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpanKind::Keyword => "keyword",
+            SpanKind::Identifier => "identifier",
+            SpanKind::Number => "number",
+            SpanKind::String => "string",
+            SpanKind::Symbol => "symbol",
+            SpanKind::Separator => "separator",
+            SpanKind::Quote => "quote",
+        }
+    }
+}
+
+/// A range of the serialized code and the source token it was produced from.
+#[derive(Debug, Clone)]
+pub struct OutputSpan {
+    /// The output bytes, including the escapes needed inside code strings.
+    pub range: Range<usize>,
+    pub kind: SpanKind,
+    /// The source offset of the token. Separators and quotes get the offset of the
+    /// preceding token.
+    pub source_offset: usize,
+    /// For identifiers, the name in the source code, before any renames.
+    pub name: Option<Vec<u8>>,
 }
 #[derive(Debug)]
 pub struct RenameCandidates {
-    pub renameable: HashMap<Vec<u8>, Vec<usize>>,
+    /// The output ranges of all renameable symbols, by symbol key.
+    pub renameable: HashMap<Vec<u8>, Vec<Range<usize>>>,
     pub fixed: HashSet<Vec<u8>>,
     pub candidate_chars: Vec<usize>,
+    /// For each renameable symbol key, the keys of those which can't share a name with it.
+    pub conflicts: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
 }
 
 impl Program {
-    pub fn parse(code: &[u8]) -> Program {
-        let tt = parse(code);
-        let (tt, renames) = find_renames(tt);
-        let tt = apply_renames(&tt, &renames);
-        let tt = apply_transform_to_load(tt);
-        Program { tt, renames }
+    pub fn parse(code: &[u8], version: TicVersion) -> Result<Program> {
+        let tokens = tokenize(code);
+        let (tokens, renames) = find_renames(tokens);
+        let mut block = parser::parse_chunk(&tokens, code)?;
+        let functions = emit::function_spans(&block);
+        apply_transform_to_load(&mut block, &tokens);
+        let mut program = Program {
+            symbols: scope::resolve(&mut block, version),
+            block,
+            tokens: vec![],
+            functions,
+            version,
+            renames: BTreeMap::new(),
+            number_forms: BTreeMap::new(),
+        };
+        program.apply_renames(&renames);
+        program.renames = renames;
+        Ok(program)
     }
 
+    /// Renames all symbols according to `renames`, starting from their source names.
     pub fn apply_renames(&mut self, renames: &Renaming) {
-        self.tt = apply_renames(&self.tt, renames);
+        let names: Vec<Vec<u8>> = self
+            .symbols
+            .iter()
+            .map(|symbol| {
+                let new_name = if symbol.fixed {
+                    None
+                } else if symbol.is_local() {
+                    renames
+                        .get(&symbol.key())
+                        .or_else(|| renames.get(&symbol.name))
+                } else {
+                    renames.get(&symbol.name)
+                };
+                new_name.unwrap_or(&symbol.name).clone()
+            })
+            .collect();
+        self.block.for_each_name_mut(&mut |name, _| {
+            if let Some(symbol) = name.symbol {
+                name.text = names[symbol].clone();
+            }
+        });
+    }
+
+    fn is_renameable(&self, symbol: &Symbol) -> bool {
+        if symbol.is_local() {
+            !symbol.fixed
+        } else {
+            !symbol.fixed && symbol.assigned && api::lookup(&symbol.name, self.version).is_none()
+        }
     }
 
+    /// Returns a warning for each rename in `renames` which would shadow a TIC-80 API
+    /// function or Lua global used by the program, or turn a global into a callback, and
+    /// for each rename which is ignored as it targets a field of the standard library.
+    pub fn check_renames(&self, renames: &Renaming) -> Vec<String> {
+        let mut warnings = vec![];
+        for (key, new_name) in renames {
+            if self
+                .symbols
+                .iter()
+                .any(|s| !s.is_local() && s.fixed && s.name == *key)
+            {
+                warnings.push(format!(
+                    "rename {}->{} is ignored, '{}' is also a field of the standard library",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(new_name),
+                    String::from_utf8_lossy(key)
+                ));
+            }
+            let kind = match api::lookup(new_name, self.version) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let used_global = self
+                .symbols
+                .iter()
+                .find(|s| !s.is_local() && s.name == *new_name && !s.uses.is_empty());
+            let shadows = self.symbols.iter().any(|symbol| {
+                let targeted = symbol.key() == *key
+                    || (symbol.is_local()
+                        && symbol.name == *key
+                        && !renames.contains_key(&symbol.key()));
+                if !targeted || symbol.fixed {
+                    return false;
+                }
+                if !symbol.is_local() && kind == api::ApiKind::Callback {
+                    return true;
+                }
+                match used_global {
+                    Some(global) => scope::conflicts(symbol, global),
+                    None => false,
+                }
+            });
+            if shadows {
+                warnings.push(format!(
+                    "rename {}->{} shadows the {} '{}'",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(new_name),
+                    match kind {
+                        api::ApiKind::Callback => "TIC-80 callback",
+                        api::ApiKind::Function => "TIC-80 API function",
+                        api::ApiKind::Lua => "Lua global",
+                    },
+                    String::from_utf8_lossy(new_name)
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Minifies the program, with `ws` between tokens which need to be separated. A
+    /// newline would end a code string, so a space is used inside code strings instead.
     pub fn serialize(&mut self, ws: u8) -> Vec<u8> {
-        serialize(&mut self.tt, ws)
+        self.tokens = self.flatten();
+        serialize(&mut self.tokens, &mut |_, in_code_string| {
+            separator(ws, in_code_string)
+        })
+    }
+
+    /// Like `serialize`, with `separators[i]` at the i-th place which needs a separator
+    /// (see `separator_sites`), and a space where `separators` ends.
+    pub fn serialize_with_separators(&mut self, separators: &[u8]) -> Vec<u8> {
+        self.tokens = self.flatten();
+        serialize(&mut self.tokens, &mut |site, in_code_string| {
+            separator(*separators.get(site).unwrap_or(&b' '), in_code_string)
+        })
     }
 
+    fn flatten(&self) -> Vec<FlatToken> {
+        let mut tokens = emit::flatten(&self.block);
+        for token in &mut tokens {
+            if let FlatToken::Token {
+                ref mut type_,
+                ref mut text,
+                ..
+            } = *token
+            {
+                if matches!(*type_, TokenType::Number | TokenType::HexNumber) {
+                    if let Some(form) = self.number_forms.get(text) {
+                        *type_ = emit::number_type(form);
+                        *text = form.clone();
+                    }
+                }
+            }
+        }
+        tokens
+    }
+
+    /// The distinct numeric literals of the program as written in the source, in order of
+    /// their first appearance.
+    pub fn number_literals(&self) -> Vec<Vec<u8>> {
+        let mut literals: Vec<Vec<u8>> = vec![];
+        for token in emit::flatten(&self.block) {
+            if let FlatToken::Token { type_, text, .. } = token {
+                if matches!(type_, TokenType::Number | TokenType::HexNumber)
+                    && !literals.contains(&text)
+                {
+                    literals.push(text);
+                }
+            }
+        }
+        literals
+    }
+
+    /// For each place in the output of the last call to `serialize` which needs a
+    /// separator, whether it is inside a code string.
+    pub fn separator_sites(&self) -> Vec<bool> {
+        let mut sites = vec![];
+        serialize(&mut self.tokens.clone(), &mut |_, in_code_string| {
+            sites.push(in_code_string);
+            b' '
+        });
+        sites
+    }
+
+    /// Formats the program as readable code, see `pretty::pretty_print`.
+    pub fn pretty_print(&self) -> Vec<u8> {
+        pretty::pretty_print(&self.block, &self.symbols)
+    }
+
+    /// Maps the output of the last call to `serialize` back to the source tokens. The
+    /// spans cover the output without gaps.
+    pub fn output_spans(&self) -> Vec<OutputSpan> {
+        fn push(spans: &mut Vec<OutputSpan>, kind: SpanKind, len: usize, source_offset: usize) {
+            let pos = spans.last().map_or(0, |span| span.range.end);
+            spans.push(OutputSpan {
+                range: pos..pos + len,
+                kind,
+                source_offset,
+                name: None,
+            });
+        }
+
+        let mut spans = vec![];
+        let mut source_offset = 0;
+        let mut delim_stack = DelimStack::empty();
+        let mut outer_stacks = vec![];
+        for token in &self.tokens {
+            match *token {
+                FlatToken::Token {
+                    type_,
+                    ref text,
+                    offset,
+                    out_offset,
+                    symbol,
+                } => {
+                    let pos = spans.last().map_or(0, |span: &OutputSpan| span.range.end);
+                    if out_offset > pos {
+                        push(
+                            &mut spans,
+                            SpanKind::Separator,
+                            out_offset - pos,
+                            source_offset,
+                        );
+                    }
+                    let (kind, name) = match type_ {
+                        TokenType::Identifier => match symbol {
+                            Some(symbol) => (
+                                SpanKind::Identifier,
+                                Some(self.symbols[symbol].name.clone()),
+                            ),
+                            None if parser::is_keyword(text) => (SpanKind::Keyword, None),
+                            None => (SpanKind::Identifier, Some(text.clone())),
+                        },
+                        TokenType::Number | TokenType::HexNumber => (SpanKind::Number, None),
+                        TokenType::String => (SpanKind::String, None),
+                        // long bracket strings
+                        _ if text.starts_with(b"[") && text.len() > 1 => (SpanKind::String, None),
+                        _ => (SpanKind::Symbol, None),
+                    };
+                    let len = text.iter().map(|&c| delim_stack.encode_length(c)).sum();
+                    push(&mut spans, kind, len, offset);
+                    spans.last_mut().unwrap().name = name;
+                    source_offset = offset;
+                }
+                FlatToken::CodeStringStart(delim) => {
+                    let len = delim_stack.encode_length(delim);
+                    push(&mut spans, SpanKind::Quote, len, source_offset);
+                    let inner = delim_stack.push(delim);
+                    outer_stacks.push(std::mem::replace(&mut delim_stack, inner));
+                }
+                FlatToken::CodeStringEnd(delim) => {
+                    delim_stack = outer_stacks.pop().unwrap();
+                    let len = delim_stack.encode_length(delim);
+                    push(&mut spans, SpanKind::Quote, len, source_offset);
+                }
+            }
+        }
+        spans
+    }
+
+    /// The functions of the source code, inner functions before the functions containing
+    /// them. Functions turned into code strings by `-- transform to load` are included.
+    pub fn functions(&self) -> &[FunctionSpan] {
+        &self.functions
+    }
+
+    /// Collects rename candidates, based on the output of the last call to `serialize`.
     pub fn get_rename_candidates(&self) -> RenameCandidates {
         let mut candidates = RenameCandidates {
             renameable: HashMap::new(),
             fixed: HashSet::new(),
             candidate_chars: Vec::new(),
+            conflicts: HashMap::new(),
         };
+        let mut renameable_symbols: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
 
-        let renameable_ids = find_renamable_identifiers(&self.tt);
-
-        fn inner(
-            candidates: &mut RenameCandidates,
-            tt: &TokenTree,
-            renameable_ids: &HashSet<Vec<u8>>,
-            delim_stack: DelimStack,
-        ) {
-            for token in tt {
-                match *token {
-                    TreeToken::Token {
-                        type_: TokenType::Comment,
-                        ..
-                    } => (),
-                    TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        offset,
-                        ref text,
-                    } => {
-                        if renameable_ids.contains(text) {
-                            candidates
-                                .renameable
-                                .entry(text.clone())
-                                .or_default()
-                                .push(offset);
-                        } else {
-                            candidates.fixed.insert(text.clone());
-                            for i in 0..text.len() {
-                                if is_valid_ident_start(text[i]) {
-                                    candidates.candidate_chars.push(offset + i);
-                                }
-                            }
+        let mut delim_stack = DelimStack::empty();
+        let mut outer_stacks = vec![];
+        for token in &self.tokens {
+            match *token {
+                FlatToken::Token {
+                    type_: TokenType::Identifier,
+                    ref text,
+                    out_offset,
+                    symbol,
+                    ..
+                } => match symbol {
+                    Some(symbol) if self.is_renameable(&self.symbols[symbol]) => {
+                        let key = self.symbols[symbol].key();
+                        candidates
+                            .renameable
+                            .entry(key.clone())
+                            .or_default()
+                            .push(out_offset..out_offset + text.len());
+                        let symbols = renameable_symbols.entry(key).or_default();
+                        if !symbols.contains(&symbol) {
+                            symbols.push(symbol);
                         }
                     }
-                    TreeToken::Token {
-                        mut offset,
-                        ref text,
-                        ..
-                    } => {
-                        for &c in text {
-                            offset += delim_stack.encode_length(c) - 1;
+                    _ => {
+                        candidates.fixed.insert(text.clone());
+                        for (i, &c) in text.iter().enumerate() {
                             if is_valid_ident_start(c) {
-                                candidates.candidate_chars.push(offset);
+                                candidates.candidate_chars.push(out_offset + i);
                             }
-                            offset += 1;
                         }
                     }
-                    TreeToken::SubTree(ref sub_tt) => {
-                        inner(candidates, sub_tt, renameable_ids, delim_stack.clone())
+                },
+                FlatToken::Token {
+                    ref text,
+                    out_offset,
+                    ..
+                } => {
+                    let mut offset = out_offset;
+                    for &c in text {
+                        offset += delim_stack.encode_length(c) - 1;
+                        if is_valid_ident_start(c) {
+                            candidates.candidate_chars.push(offset);
+                        }
+                        offset += 1;
                     }
-                    TreeToken::CodeString {
-                        tt: ref sub_tt,
-                        delim,
-                    } => inner(candidates, sub_tt, renameable_ids, delim_stack.push(delim)),
+                }
+                FlatToken::CodeStringStart(delim) => {
+                    let inner = delim_stack.push(delim);
+                    outer_stacks.push(std::mem::replace(&mut delim_stack, inner));
+                }
+                FlatToken::CodeStringEnd(_) => {
+                    delim_stack = outer_stacks.pop().unwrap();
                 }
             }
         }
 
-        inner(
-            &mut candidates,
-            &self.tt,
-            &renameable_ids,
-            DelimStack::empty(),
-        );
+        // never suggest predefined names, even if the program doesn't use them
+        candidates
+            .fixed
+            .extend(api::names(self.version).map(|name| name.to_vec()));
+
+        for (key, symbols) in &renameable_symbols {
+            let conflicts: HashSet<Vec<u8>> = renameable_symbols
+                .iter()
+                .filter(|&(other_key, other_symbols)| {
+                    other_key != key
+                        && symbols.iter().any(|&a| {
+                            other_symbols
+                                .iter()
+                                .any(|&b| scope::conflicts(&self.symbols[a], &self.symbols[b]))
+                        })
+                })
+                .map(|(other_key, _)| other_key.clone())
+                .collect();
+            candidates.conflicts.insert(key.clone(), conflicts);
+        }
 
         candidates
     }
+
+    /// Checks that `code`, the output of the last call to `serialize`, tokenizes back into
+    /// exactly the tokens it was serialized from.
+    pub fn verify(&self, code: &[u8]) -> Result<()> {
+        verify_tokens(&self.tokens, code, code, &|offset| offset)
+    }
+}
+
+/// Compares the tokens of `code` to `expected`, recursing into code strings.
+/// `out_offset` maps offsets in `code` to offsets in the complete `output`.
+fn verify_tokens(
+    expected: &[FlatToken],
+    code: &[u8],
+    output: &[u8],
+    out_offset: &dyn Fn(usize) -> usize,
+) -> Result<()> {
+    fn describe(type_: TokenType, text: &[u8]) -> String {
+        if type_ == TokenType::EOF {
+            "end of code".to_string()
+        } else {
+            format!("{:?} '{}'", type_, String::from_utf8_lossy(text))
+        }
+    }
+    let mismatch = |offset: usize, expected: &str, found: &str| {
+        let offset = out_offset(offset);
+        let context = &output[offset.saturating_sub(20)..(offset + 20).min(output.len())];
+        anyhow::anyhow!(
+            "Minified code doesn't match the source at output offset {}: expected {}, found {}\n  near: {}",
+            offset,
+            expected,
+            found,
+            String::from_utf8_lossy(context)
+        )
+    };
+
+    let mut offset = 0;
+    let mut i = 0;
+    loop {
+        let (type_, mut text, start) = next_token(code, &mut offset);
+        let mut found = describe(type_, text);
+        if matches!(type_, TokenType::Number | TokenType::HexNumber) {
+            let len = lua_numeral_len(&code[start..]);
+            if len != text.len() {
+                offset = start + len;
+                text = &code[start..offset];
+                found = format!("malformed number '{}'", String::from_utf8_lossy(text));
+            }
+        }
+        match expected.get(i) {
+            None if type_ == TokenType::EOF => return Ok(()),
+            None => return Err(mismatch(start, "end of code", &found)),
+            Some(&FlatToken::Token {
+                type_: expected_type,
+                text: ref expected_text,
+                ..
+            }) => {
+                if type_ != expected_type || text != expected_text.as_slice() {
+                    return Err(mismatch(
+                        start,
+                        &describe(expected_type, expected_text),
+                        &found,
+                    ));
+                }
+                i += 1;
+            }
+            Some(&FlatToken::CodeStringStart(delim)) => {
+                if type_ != TokenType::String || text.first() != Some(&delim) {
+                    return Err(mismatch(
+                        start,
+                        &format!("code string in {}", delim as char),
+                        &found,
+                    ));
+                }
+                let mut end = i + 1;
+                let mut depth = 0;
+                loop {
+                    match expected[end] {
+                        FlatToken::CodeStringStart(_) => depth += 1,
+                        FlatToken::CodeStringEnd(_) if depth == 0 => break,
+                        FlatToken::CodeStringEnd(_) => depth -= 1,
+                        _ => (),
+                    }
+                    end += 1;
+                }
+                let (inner, positions) = unescape_string(text);
+                verify_tokens(&expected[i + 1..end], &inner, output, &|o| {
+                    out_offset(start + positions.get(o).copied().unwrap_or(text.len() - 1))
+                })?;
+                i = end + 1;
+            }
+            Some(&FlatToken::CodeStringEnd(_)) => unreachable!(),
+        }
+    }
+}
+
+/// Returns the length of the numeral at the start of `code` as read by Lua, which is
+/// greedier than `next_token`: `1e` or `3f` are read as one (malformed) number.
+fn lua_numeral_len(code: &[u8]) -> usize {
+    let (exponent, mut pos) = if code.len() > 1 && code[0] == b'0' && code[1] | 32 == b'x' {
+        (b'p', 2)
+    } else {
+        (b'e', 0)
+    };
+    while pos < code.len() {
+        let c = code[pos];
+        if c | 32 == exponent {
+            pos += 1;
+            if pos < code.len() && (code[pos] == b'+' || code[pos] == b'-') {
+                pos += 1;
+            }
+        } else if c.is_ascii_hexdigit() || c == b'.' {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+    pos
+}
+
+/// Returns the 1-based line and column of `offset` in `source`.
+pub fn line_col(source: &[u8], offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let line_start = source[..offset]
+        .iter()
+        .rposition(|&c| c == b'\n')
+        .map_or(0, |p| p + 1);
+    let line = source[..offset].iter().filter(|&&c| c == b'\n').count() + 1;
+    (line, offset - line_start + 1)
+}
+
+/// Converts offsets to 1-based lines and columns of some code, for many lookups in the
+/// same code.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    /// The offset of the start of each line.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl LineIndex {
+    pub fn new(code: &[u8]) -> LineIndex {
+        let starts = std::iter::once(0)
+            .chain(
+                code.iter()
+                    .enumerate()
+                    .filter(|&(_, &c)| c == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+        LineIndex {
+            starts,
+            len: code.len(),
+        }
+    }
+
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.len);
+        let line = match self.starts.binary_search(&offset) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        };
+        (line, offset - self.starts[line - 1] + 1)
+    }
 }
 
 pub fn is_valid_ident_start(c: u8) -> bool {
     c == b'_' || c.is_ascii_alphabetic()
 }
 
-fn find_renames(mut tt: TokenTree) -> (TokenTree, Renaming) {
+lazy_static! {
+    static ref RENAME_RE: Regex = Regex::new(r"^--\s*rename\s*([\w.:]+)\s*->\s*(\w+)\s*$").unwrap();
+}
+
+fn find_renames(mut tokens: Vec<SourceToken>) -> (Vec<SourceToken>, Renaming) {
     let mut renames = BTreeMap::new();
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^--\s*rename\s*(\w+)\s*->\s*(\w+)\s*$").unwrap();
-    }
-    tt.retain(|tok| {
-        if let &TreeToken::Token {
-            type_: TokenType::Comment,
-            ref text,
-            ..
-        } = tok
-        {
-            if let Some(caps) = RE.captures(text) {
+    tokens.retain(|tok| {
+        if tok.type_ == TokenType::Comment {
+            if let Some(caps) = RENAME_RE.captures(&tok.text) {
                 renames.insert(caps[1].to_vec(), caps[2].to_vec());
                 return false;
             }
         }
         true
     });
-    (tt, renames)
+    (tokens, renames)
 }
 
-fn apply_renames(tt: &TokenTree, renames: &Renaming) -> TokenTree {
-    let mut new_tt = vec![];
+/// Reads the `-- rename a->b` lines of `text`, as printed by `pack`, ignoring all other
+/// lines.
+pub fn read_renames(text: &[u8]) -> Renaming {
+    text.split(|&c| c == b'\n')
+        .filter_map(|line| RENAME_RE.captures(line.trim_ascii()))
+        .map(|caps| (caps[1].to_vec(), caps[2].to_vec()))
+        .collect()
+}
 
-    for token in tt {
-        match *token {
-            TreeToken::Token {
-                type_: TokenType::Identifier,
-                ref text,
-                ..
-            } => {
-                if let Some(new_name) = renames.get(text) {
-                    new_tt.push(TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        offset: 0,
-                        text: new_name.clone(),
-                    });
-                } else {
-                    new_tt.push(token.clone());
-                }
-            }
-            TreeToken::Token { .. } => {
-                new_tt.push(token.clone());
-            }
-            TreeToken::SubTree(ref sub_tt) => {
-                new_tt.push(TreeToken::SubTree(apply_renames(sub_tt, renames)));
-            }
-            TreeToken::CodeString {
-                tt: ref sub_tt,
-                delim,
-            } => {
-                new_tt.push(TreeToken::CodeString {
-                    tt: apply_renames(sub_tt, renames),
-                    delim,
-                });
-            }
-        }
+/// Inverts `renames`, mapping each new name back to the original one. Scoped keys like
+/// `f:a` turn into plain names, so the result renames all symbols of the new name; for
+/// new names used more than once, the first rename wins.
+pub fn reverse_renames(renames: &Renaming) -> Renaming {
+    let mut reversed = Renaming::new();
+    for (key, new_name) in renames {
+        let name = key.rsplit(|&c| c == b':').next().unwrap_or(key);
+        reversed
+            .entry(new_name.clone())
+            .or_insert_with(|| name.to_vec());
     }
-
-    new_tt
+    reversed
 }
 
-fn apply_transform_to_load(tt: TokenTree) -> TokenTree {
-    let mut new_tt = vec![];
+/// Turns the first parameterless `function name()` following each `-- transform to load`
+/// comment into `name=load"..."`.
+fn apply_transform_to_load(block: &mut Block, tokens: &[SourceToken]) {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^--\s*transform\s*to\s*load\s*$").unwrap();
+    }
 
-    let mut transform_next = false;
-    for token in tt {
-        match token {
-            TreeToken::Token {
-                type_: TokenType::Comment,
-                ref text,
-                ..
-            } => {
-                lazy_static! {
-                    static ref RE: Regex = Regex::new(r"^--\s*transform\s*to\s*load\s*$").unwrap();
+    fn transform_next(block: &mut Block, after: usize) -> bool {
+        for stmt in &mut block.stmts {
+            let transformed = match stmt.kind {
+                StmtKind::Function { ref name, ref func }
+                    if stmt.offset > after
+                        && name.path.len() == 1
+                        && name.method.is_none()
+                        && func.params.is_empty()
+                        && !func.is_vararg =>
+                {
+                    Some(StmtKind::Assign {
+                        targets: vec![ast::Expr::Name(name.path[0].clone())],
+                        values: vec![load_call(stmt.offset, &func.body)],
+                    })
                 }
-                if RE.is_match(text) {
-                    transform_next = true;
-                } else {
-                    new_tt.push(token);
+                StmtKind::LocalFunction { ref name, ref func }
+                    if stmt.offset > after && func.params.is_empty() && !func.is_vararg =>
+                {
+                    Some(StmtKind::Local {
+                        names: vec![name.clone()],
+                        values: vec![load_call(stmt.offset, &func.body)],
+                    })
                 }
-            }
-            TreeToken::Token { .. } => new_tt.push(token),
-            TreeToken::SubTree(ref sub_tt) => {
-                let func_name = if transform_next && sub_tt.len() >= 5 {
-                    if sub_tt[0].text() == b"function"
-                        && !sub_tt[1].text().is_empty()
-                        && sub_tt[2].text() == b"("
-                        && sub_tt[3].text() == b")"
-                        && sub_tt[sub_tt.len() - 1].text() == b"end"
-                    {
-                        Some(sub_tt[1].text())
-                    } else {
-                        None
+                StmtKind::Do(ref mut body)
+                | StmtKind::While { ref mut body, .. }
+                | StmtKind::Repeat { ref mut body, .. }
+                | StmtKind::NumericFor { ref mut body, .. }
+                | StmtKind::GenericFor { ref mut body, .. } => {
+                    if transform_next(body, after) {
+                        return true;
+                    }
+                    None
+                }
+                StmtKind::If {
+                    ref mut branches,
+                    ref mut else_block,
+                } => {
+                    for &mut (_, ref mut body) in branches.iter_mut() {
+                        if transform_next(body, after) {
+                            return true;
+                        }
+                    }
+                    if let Some(ref mut body) = *else_block {
+                        if transform_next(body, after) {
+                            return true;
+                        }
                     }
-                } else {
                     None
-                };
-
-                if let Some(name) = func_name {
-                    let body = sub_tt[4..(sub_tt.len() - 1)].to_vec();
-                    new_tt.push(TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        offset: 0,
-                        text: name.to_vec(),
-                    });
-                    new_tt.push(TreeToken::Token {
-                        type_: TokenType::Other,
-                        offset: 0,
-                        text: b"=".to_vec(),
-                    });
-                    new_tt.push(TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        offset: 0,
-                        text: b"load".to_vec(),
-                    });
-                    new_tt.push(TreeToken::CodeString {
-                        tt: body,
-                        delim: b'"',
-                    });
-                    transform_next = false;
-                } else {
-                    new_tt.push(token);
                 }
+                _ => None,
+            };
+            if let Some(kind) = transformed {
+                // the keywords and punctuation of the function are gone
+                stmt.marks.clear();
+                stmt.kind = kind;
+                return true;
             }
-            TreeToken::CodeString { .. } => new_tt.push(token),
         }
+        false
     }
 
-    new_tt
-}
+    fn load_call(offset: usize, body: &Block) -> ast::Expr {
+        ast::Expr::Call {
+            func: Box::new(ast::Expr::Name(ast::Token {
+                text: b"load".to_vec(),
+                offset,
+                symbol: None,
+            })),
+            args: ast::Args::String(ast::StringLiteral::Code {
+                offset,
+                delim: b'"',
+                block: body.clone(),
+            }),
+        }
+    }
 
-fn find_renamable_identifiers(tt: &TokenTree) -> HashSet<Vec<u8>> {
-    fn inner(idents: &mut HashSet<Vec<u8>>, tt: &TokenTree) {
-        for (index, token) in tt.iter().enumerate() {
-            match (token, tt.get(index + 1)) {
-                (
-                    TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        text: ref id_name,
-                        ..
-                    },
-                    Some(&TreeToken::Token {
-                        type_: TokenType::Other,
-                        ref text,
-                        ..
-                    }),
-                ) if text == b"=" => match id_name.as_slice() {
-                    b"TIC" | b"SCN" | b"OVR" => (),
-                    _ => {
-                        idents.insert(id_name.clone());
-                    }
-                },
-                (
-                    TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        text: ref fn_text,
-                        ..
-                    },
-                    Some(&TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        text: ref id_name,
-                        ..
-                    }),
-                ) if fn_text == b"function" => match id_name.as_slice() {
-                    b"TIC" | b"SCN" | b"OVR" => (),
-                    _ => {
-                        idents.insert(id_name.clone());
-                    }
-                },
-                (TreeToken::SubTree(ref sub_tt), _) => inner(idents, sub_tt),
-                (TreeToken::CodeString { tt: ref sub_tt, .. }, _) => inner(idents, sub_tt),
-                _ => (),
-            }
+    for token in tokens {
+        if token.type_ == TokenType::Comment && RE.is_match(&token.text) {
+            transform_next(block, token.offset);
         }
     }
-    let mut idents = HashSet::new();
-    inner(&mut idents, tt);
-    idents
 }
 
 #[derive(Clone)]
@@ -339,250 +787,176 @@ impl DelimStack {
     }
 }
 
-fn serialize(tt: &mut [TreeToken], ws: u8) -> Vec<u8> {
-    struct LastToken {
-        type_: TokenType,
-        text: Vec<u8>,
+/// `ws`, or a space where `ws` can't be used.
+fn separator(ws: u8, in_code_string: bool) -> u8 {
+    if ws == b'\n' && in_code_string {
+        b' '
+    } else {
+        ws
     }
+}
 
-    fn inner(
-        tt: &mut [TreeToken],
-        last_token: &mut LastToken,
-        code: &mut Vec<u8>,
-        ws: u8,
-        delim_stack: DelimStack,
-    ) {
-        for token in tt {
-            match *token {
-                TreeToken::Token {
-                    type_,
-                    ref mut offset,
-                    ref text,
-                } => {
-                    if type_ == TokenType::Comment {
-                        continue;
-                    }
-
-                    match last_token.type_ {
-                        TokenType::Identifier
-                            if text[0] == b'_' || text[0].is_ascii_alphanumeric() =>
-                        {
-                            code.push(ws);
-                        }
-                        TokenType::Number
-                            if text[0] == b'.'
-                                || text[0].is_ascii_hexdigit()
-                                || (text[0].to_ascii_lowercase() == b'x'
-                                    && (last_token.text == b"0" || last_token.text == b".0")) =>
-                        {
-                            code.push(ws);
-                        }
-                        TokenType::HexNumber
-                            if text[0] == b'.'
-                                || text[0].is_ascii_hexdigit()
-                                || text[0].to_ascii_lowercase() == b'p' =>
-                        {
-                            code.push(ws);
-                        }
-                        _ => (),
-                    }
-                    *offset = code.len();
-                    for &c in text {
-                        delim_stack.encode(code, c);
-                    }
-                    last_token.type_ = type_;
-                    last_token.text = text.clone();
-                }
+/// Serializes `tokens`, with the separators returned by `separator` for each place which
+/// needs one, given its index and whether it is inside a code string.
+fn serialize(tokens: &mut [FlatToken], separator: &mut dyn FnMut(usize, bool) -> u8) -> Vec<u8> {
+    let mut code = vec![];
+    let mut sites = 0;
+    let mut last_type = TokenType::Other;
+    let mut last_text: Vec<u8> = vec![];
+    let mut delim_stack = DelimStack::empty();
+    let mut outer_stacks = vec![];
 
-                TreeToken::SubTree(ref mut sub_tt) => {
-                    inner(sub_tt, last_token, code, ws, delim_stack.clone());
+    for token in tokens {
+        match *token {
+            FlatToken::Token {
+                type_,
+                ref text,
+                ref mut out_offset,
+                ..
+            } => {
+                if needs_separator(last_type, &last_text, text) {
+                    let ws = separator(sites, !outer_stacks.is_empty());
+                    delim_stack.encode(&mut code, ws);
+                    sites += 1;
                 }
-                TreeToken::CodeString {
-                    tt: ref mut sub_tt,
-                    delim,
-                } => {
-                    delim_stack.encode(code, delim);
-                    last_token.type_ = TokenType::Other;
-                    inner(sub_tt, last_token, code, ws, delim_stack.push(delim));
-                    delim_stack.encode(code, delim);
+                *out_offset = code.len();
+                for &c in text {
+                    delim_stack.encode(&mut code, c);
                 }
+                last_type = type_;
+                last_text = text.clone();
+            }
+            FlatToken::CodeStringStart(delim) => {
+                delim_stack.encode(&mut code, delim);
+                let inner = delim_stack.push(delim);
+                outer_stacks.push(std::mem::replace(&mut delim_stack, inner));
+                last_type = TokenType::Other;
+                last_text = vec![delim];
+            }
+            FlatToken::CodeStringEnd(delim) => {
+                delim_stack = outer_stacks.pop().unwrap();
+                delim_stack.encode(&mut code, delim);
+                last_type = TokenType::Other;
+                last_text = vec![delim];
             }
         }
     }
 
-    let mut code = vec![];
-    let mut last_token = LastToken {
-        type_: TokenType::Other,
-        text: vec![],
-    };
-    inner(tt, &mut last_token, &mut code, ws, DelimStack::empty());
     code
 }
 
-fn parse(code: &[u8]) -> TokenTree {
-    fn parse_subtree(tokens: &mut TokenTree, code: &[u8], offset: &mut usize) {
-        loop {
-            let (token_type, token_text, token_start) = next_token(code, offset);
-            if token_type == TokenType::EOF {
-                return;
-            }
-            if token_type == TokenType::Identifier {
-                if token_text == b"function" {
-                    let mut sub_tokens = vec![];
-                    sub_tokens.push(TreeToken::Token {
-                        type_: token_type,
-                        offset: token_start,
-                        text: token_text.to_vec(),
-                    });
-                    parse_subtree(&mut sub_tokens, code, offset);
-                    tokens.push(TreeToken::SubTree(sub_tokens));
-                    continue;
-                }
-                if token_text == b"do" || token_text == b"if" {
-                    tokens.push(TreeToken::Token {
-                        type_: token_type,
-                        offset: token_start,
-                        text: token_text.to_vec(),
-                    });
-                    parse_subtree(tokens, code, offset);
-                    continue;
-                }
-            }
-            tokens.push(TreeToken::Token {
-                type_: token_type,
-                offset: token_start,
-                text: token_text.to_vec(),
-            });
-            if token_type == TokenType::Identifier && token_text == b"end" {
-                return;
-            }
-        }
-    }
-    let mut tokens = vec![];
-    let mut offset = 0;
-    parse_subtree(&mut tokens, code, &mut offset);
-
-    fn parse_load_functions(tt: TokenTree) -> TokenTree {
-        lazy_static! {
-            static ref CODE_STRING_COMMENT: Regex = Regex::new(r"\A--\s*code\s+string").unwrap();
+fn needs_separator(last_type: TokenType, last_text: &[u8], text: &[u8]) -> bool {
+    match last_type {
+        TokenType::Identifier => text[0] == b'_' || text[0].is_ascii_alphanumeric(),
+        TokenType::Number => {
+            text[0] == b'.'
+                || text[0].is_ascii_hexdigit()
+                || (text[0].eq_ignore_ascii_case(&b'x')
+                    && (last_text == b"0" || last_text == b".0"))
         }
-        let mut new_tt = vec![];
-
-        let mut index = 0;
-        while index < tt.len() {
-            let token = &tt[index];
-            index += 1;
-            fn make_code_string(text: &[u8], offset: usize) -> TreeToken {
-                let mut code = vec![];
-                let mut offset_map: HashMap<usize, usize> = HashMap::new();
-                let mut pos = 1;
-                while pos + 1 < text.len() {
-                    offset_map.insert(code.len(), offset + pos);
-                    code.push(match text[pos] {
-                        b'\\' => {
-                            pos += 1;
-                            match text[pos] {
-                                b'n' => b'\n',
-                                b'r' => b'\r',
-                                b't' => b'\t',
-                                b'\\' => b'\\',
-                                o => o,
-                            }
-                        }
-                        o => o,
-                    });
-                    pos += 1;
-                }
-                let mut sub_tt = parse(&code);
-                fn remap(tt: &mut TokenTree, offset_map: &HashMap<usize, usize>) {
-                    for token in tt {
-                        match token {
-                            TreeToken::Token { ref mut offset, .. } => {
-                                *offset = *offset_map.get(offset).unwrap()
-                            }
-                            TreeToken::SubTree(ref mut sub_tt) => remap(sub_tt, offset_map),
-                            TreeToken::CodeString {
-                                tt: ref mut sub_tt, ..
-                            } => remap(sub_tt, offset_map),
-                        }
-                    }
-                }
-                remap(&mut sub_tt, &offset_map);
-                TreeToken::CodeString {
-                    tt: parse_load_functions(sub_tt),
-                    delim: text[0],
-                }
-            }
-            match (token, tt.get(index)) {
-                (
-                    &TreeToken::Token {
-                        type_: TokenType::Identifier,
-                        text: ref fn_name,
-                        ..
-                    },
-                    Some(&TreeToken::Token {
-                        type_: TokenType::String,
-                        offset,
-                        ref text,
-                    }),
-                ) if fn_name == b"load" => {
-                    new_tt.push(token.clone());
-                    new_tt.push(make_code_string(text, offset));
-                    index += 1;
-                }
-                (
-                    &TreeToken::Token {
-                        type_: TokenType::Comment,
-                        text: ref comment,
-                        ..
-                    },
-                    Some(&TreeToken::Token {
-                        type_: TokenType::String,
-                        offset,
-                        ref text,
-                    }),
-                ) if CODE_STRING_COMMENT.is_match(comment) => {
-                    new_tt.push(make_code_string(text, offset));
-                    index += 1;
-                }
-                _ => new_tt.push(token.clone()),
-            }
+        TokenType::HexNumber => {
+            text[0] == b'.' || text[0].is_ascii_hexdigit() || text[0].eq_ignore_ascii_case(&b'p')
         }
-
-        new_tt
+        // keep `- -x` from turning into a comment, `.. .5` into `...5`
+        // and `t[ [[s]] ]` into a long bracket
+        _ => matches!(
+            (last_text.last(), text[0]),
+            (Some(b'-'), b'-') | (Some(b'.'), b'.') | (Some(b'['), b'[')
+        ),
     }
+}
 
-    parse_load_functions(tokens)
+/// A token of the source code. Strings holding code (passed to `load` or marked with
+/// a `-- code string` comment) carry the tokens of that code.
+pub struct SourceToken {
+    type_: TokenType,
+    text: Vec<u8>,
+    offset: usize,
+    code: Option<Vec<SourceToken>>,
 }
 
-#[derive(Debug, Clone)]
-enum TreeToken {
-    Token {
-        type_: TokenType,
-        offset: usize,
-        text: Vec<u8>,
-    },
-    SubTree(TokenTree),
-    CodeString {
-        tt: TokenTree,
-        delim: u8,
-    },
+fn tokenize(code: &[u8]) -> Vec<SourceToken> {
+    lazy_static! {
+        static ref CODE_STRING_COMMENT: Regex = Regex::new(r"\A--\s*code\s+string").unwrap();
+    }
+
+    let mut tokens: Vec<SourceToken> = vec![];
+    let mut offset = 0;
+    loop {
+        let (type_, text, start) = next_token(code, &mut offset);
+        if type_ == TokenType::EOF {
+            break;
+        }
+        let is_code_string = type_ == TokenType::String
+            && match tokens.last() {
+                Some(prev) if prev.type_ == TokenType::Identifier => prev.text == b"load",
+                Some(prev) if prev.type_ == TokenType::Comment => {
+                    CODE_STRING_COMMENT.is_match(&prev.text)
+                }
+                _ => false,
+            };
+        tokens.push(SourceToken {
+            type_,
+            text: text.to_vec(),
+            offset: start,
+            code: if is_code_string {
+                Some(tokenize_code_string(text, start))
+            } else {
+                None
+            },
+        });
+    }
+    tokens
 }
 
-impl TreeToken {
-    fn text(&self) -> &[u8] {
-        if let &TreeToken::Token { ref text, .. } = self {
-            text
-        } else {
-            b""
+fn tokenize_code_string(text: &[u8], offset: usize) -> Vec<SourceToken> {
+    let (code, positions) = unescape_string(text);
+    let offset_map: HashMap<usize, usize> = positions
+        .into_iter()
+        .enumerate()
+        .map(|(i, pos)| (i, offset + pos))
+        .collect();
+    let mut tokens = tokenize(&code);
+    fn remap(tokens: &mut [SourceToken], offset_map: &HashMap<usize, usize>) {
+        for token in tokens {
+            token.offset = *offset_map.get(&token.offset).unwrap();
+            if let Some(ref mut code) = token.code {
+                remap(code, offset_map);
+            }
         }
     }
+    remap(&mut tokens, &offset_map);
+    tokens
 }
 
-type TokenTree = Vec<TreeToken>;
+/// Returns the contents of a quoted string token with escapes resolved, together with
+/// the position in `text` each byte was decoded from.
+fn unescape_string(text: &[u8]) -> (Vec<u8>, Vec<usize>) {
+    let mut code = vec![];
+    let mut positions = vec![];
+    let mut pos = 1;
+    while pos + 1 < text.len() {
+        positions.push(pos);
+        code.push(match text[pos] {
+            b'\\' => {
+                pos += 1;
+                match text[pos] {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'\\' => b'\\',
+                    o => o,
+                }
+            }
+            o => o,
+        });
+        pos += 1;
+    }
+    (code, positions)
+}
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
-enum TokenType {
+pub enum TokenType {
     Comment,
     Identifier,
     Number,
@@ -598,11 +972,12 @@ fn next_token<'a>(code: &'a [u8], offset: &mut usize) -> (TokenType, &'a [u8], u
         static ref LONG_BRACKET_COMMENT: Regex = Regex::new(r"\A--\[=*\[").unwrap();
         static ref COMMENT: Regex = Regex::new(r"\A--.*").unwrap();
         static ref IDENTIFIER: Regex = Regex::new(r"\A[_a-zA-Z][_a-zA-Z0-9]*").unwrap();
-        static ref NUMBER: Regex = Regex::new(r"\A(\d+(\.\d*)?|\.\d+)([eE]-?\d+)?").unwrap();
+        static ref NUMBER: Regex = Regex::new(r"\A(\d+(\.\d*)?|\.\d+)([eE][-+]?\d+)?").unwrap();
         static ref HEXNUMBER: Regex =
-            Regex::new(r"\A0[xX][[:xdigit:]]*(\.[[:xdigit:]]*)?([pP]-?\d+)?").unwrap();
+            Regex::new(r"\A0[xX][[:xdigit:]]*(\.[[:xdigit:]]*)?([pP][-+]?\d+)?").unwrap();
         static ref LONG_BRACKET: Regex = Regex::new(r"\A\[=*\[").unwrap();
-        static ref COMPOUND_OPERATOR: Regex = Regex::new(r"\A(==|~=|<=|>=)").unwrap();
+        static ref COMPOUND_OPERATOR: Regex =
+            Regex::new(r"\A(\.\.\.|\.\.|==|~=|<=|>=|//|::|<<|>>)").unwrap();
     }
 
     if let Some(m) = WHITE_SPACE.find(&code[*offset..]) {
@@ -640,24 +1015,22 @@ fn next_token<'a>(code: &'a [u8], offset: &mut usize) -> (TokenType, &'a [u8], u
         return (TokenType::Number, m.as_bytes(), start_offset);
     }
 
-    if code.len() > 0 {
-        if code[0] == b'"' || code[0] == b'\'' {
-            let delim = code[0];
-            let mut pos = 1;
-            while pos < code.len() {
-                let c = code[pos];
+    if !code.is_empty() && (code[0] == b'"' || code[0] == b'\'') {
+        let delim = code[0];
+        let mut pos = 1;
+        while pos < code.len() {
+            let c = code[pos];
+            pos += 1;
+            if c == delim {
+                break;
+            }
+            if c == b'\\' && pos < code.len() {
                 pos += 1;
-                if c == delim {
-                    break;
-                }
-                if c == b'\\' && pos < code.len() {
-                    pos += 1;
-                }
             }
-            let string = &code[..pos];
-            *offset += pos;
-            return (TokenType::String, string, start_offset);
         }
+        let string = &code[..pos];
+        *offset += pos;
+        return (TokenType::String, string, start_offset);
     }
 
     if let Some(m) = LONG_BRACKET.find(code) {
@@ -672,13 +1045,13 @@ fn next_token<'a>(code: &'a [u8], offset: &mut usize) -> (TokenType, &'a [u8], u
         return (TokenType::Other, m.as_bytes(), start_offset);
     }
 
-    if code.len() > 0 {
+    if !code.is_empty() {
         let tok = &code[..1];
         *offset += 1;
         return (TokenType::Other, tok, start_offset);
     }
 
-    return (TokenType::EOF, b"", start_offset);
+    (TokenType::EOF, b"", start_offset)
 }
 
 fn find_long_bracket_end(code: &[u8], level: usize) -> usize {
@@ -702,7 +1075,7 @@ mod test {
     fn multiline_strings() {
         let input: &[u8] = b"[==[foo[=[bar]=]baz]==]...";
         let mut offset = 0;
-        let (tpe, bytes, _) = next_token(&input, &mut offset);
+        let (tpe, bytes, _) = next_token(input, &mut offset);
         assert_eq!(tpe, TokenType::Other);
         assert_eq!(bytes, b"[==[foo[=[bar]=]baz]==]");
     }
@@ -711,16 +1084,19 @@ mod test {
     fn strings() {
         let input: &[u8] = b"\"test\\\"a\\\"\"  'foo\\''";
         let mut offset = 0;
-        let (tpe, bytes, _) = next_token(&input, &mut offset);
+        let (tpe, bytes, _) = next_token(input, &mut offset);
         assert_eq!(tpe, TokenType::String);
         assert_eq!(bytes, b"\"test\\\"a\\\"\"");
-        let (tpe, bytes, _) = next_token(&input, &mut offset);
+        let (tpe, bytes, _) = next_token(input, &mut offset);
         assert_eq!(tpe, TokenType::String);
         assert_eq!(bytes, b"'foo\\''");
     }
 
     fn transform(code: &[u8]) -> Vec<u8> {
-        Program::parse(code).serialize(b' ')
+        let mut program = Program::parse(code, TicVersion::LATEST).unwrap();
+        let output = program.serialize(b' ');
+        program.verify(&output).unwrap();
+        output
     }
 
     #[test]
@@ -763,4 +1139,238 @@ mod test {
     fn rename_inside_load() {
         assert_eq!(transform(b"--rename a->b\nA=load\"a=2\""), b"A=load\"b=2\"");
     }
+
+    #[test]
+    fn scoped_renames() {
+        assert_eq!(
+            transform(
+                b"-- rename f:a->x\n-- rename b->y\nfunction f(a) return a end function g(a,b) return a+b end"
+            ),
+            b"function f(x)return x end function g(a,y)return a+y end"
+        );
+        assert_eq!(
+            transform(b"--rename TIC:x->y\nx=1 function TIC() local x=x print(x) end"),
+            b"x=1 function TIC()local y=x print(y)end"
+        );
+    }
+
+    #[test]
+    fn api_names() {
+        let code = b"BDR=function() end x=1 y=2 function f(a) cls() end function g(b) end";
+        let mut program = Program::parse(code, TicVersion::LATEST).unwrap();
+        program.serialize(b' ');
+        assert!(!program
+            .get_rename_candidates()
+            .renameable
+            .contains_key(&b"BDR"[..]));
+        let mut program = Program::parse(code, TicVersion::V0_80).unwrap();
+        program.serialize(b' ');
+        assert!(program
+            .get_rename_candidates()
+            .renameable
+            .contains_key(&b"BDR"[..]));
+
+        let program = Program::parse(code, TicVersion::LATEST).unwrap();
+        let renames: Renaming = vec![
+            (b"x".to_vec(), b"cls".to_vec()),
+            (b"y".to_vec(), b"BOOT".to_vec()),
+            (b"f:a".to_vec(), b"cls".to_vec()),
+            (b"g:b".to_vec(), b"cls".to_vec()),
+        ]
+        .into_iter()
+        .collect();
+        let warnings = program.check_renames(&renames);
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings.iter().all(|w| !w.contains("g:b")));
+    }
+
+    #[test]
+    fn library_fields() {
+        let code =
+            b"sin=math.sin sub=5 function TIC() local s=('ab'):sub(1,1) circ(sin(1),sub,s) end";
+        let mut program = Program::parse(code, TicVersion::LATEST).unwrap();
+        program.serialize(b' ');
+        let candidates = program.get_rename_candidates();
+        assert!(!candidates.renameable.contains_key(&b"sin"[..]));
+        assert!(!candidates.renameable.contains_key(&b"sub"[..]));
+        assert!(candidates.renameable.contains_key(&b"TIC:s"[..]));
+
+        let renames: Renaming = vec![(b"sin".to_vec(), b"o".to_vec())].into_iter().collect();
+        let warnings = program.check_renames(&renames);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("is ignored"));
+        assert_eq!(
+            transform(b"-- rename sin->o\nsin=math.sin print(sin(1))"),
+            b"sin=math.sin print(sin(1))"
+        );
+    }
+
+    #[test]
+    fn transform_to_load() {
+        assert_eq!(
+            transform(b"t=0\n-- transform to load\nfunction TIC() t=t+1 print('t') end"),
+            b"t=0TIC=load\"t=t+1print('t')\""
+        );
+        assert_eq!(
+            transform(b"-- transform to load\nlocal function f(a) end function g() end"),
+            b"local function f(a)end g=load\"\""
+        );
+    }
+
+    #[test]
+    fn verify_mismatch() {
+        let mut program = Program::parse(b"a=1 e=2 f=load'x=1 e=2'", TicVersion::LATEST).unwrap();
+        program.serialize(b' ');
+        let err = program
+            .verify(b"a=1e=2 f=load'x=1 e=2'")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("offset 2: expected Number '1', found malformed number '1e'"),
+            "{}",
+            err
+        );
+        let err = program
+            .verify(b"a=1 e=2 f=load'x=1e=2'")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("offset 17: expected Number '1'"), "{}", err);
+    }
+
+    #[test]
+    fn output_spans() {
+        let source = b"-- rename foo->f\nlocal foo=1\n-- transform to load\nfunction TIC() print(\"x\"..foo) end";
+        let mut program = Program::parse(source, TicVersion::LATEST).unwrap();
+        let output = program.serialize(b' ');
+        assert_eq!(output, b"local f=1TIC=load\"print(\\\"x\\\"..f)\"");
+        let spans = program.output_spans();
+        assert_eq!(spans.last().unwrap().range.end, output.len());
+        assert!(spans.windows(2).all(|w| w[0].range.end == w[1].range.start));
+
+        let describe = |span: &OutputSpan| {
+            (
+                String::from_utf8_lossy(&output[span.range.clone()]).into_owned(),
+                span.kind,
+                span.name.clone().map(|n| String::from_utf8(n).unwrap()),
+                line_col(source, span.source_offset),
+            )
+        };
+        let keyword = describe(&spans[0]);
+        assert_eq!(keyword, ("local".into(), SpanKind::Keyword, None, (2, 1)));
+        assert_eq!(describe(&spans[1]).1, SpanKind::Separator);
+        let f = describe(&spans[2]);
+        assert_eq!(
+            f,
+            ("f".into(), SpanKind::Identifier, Some("foo".into()), (2, 7))
+        );
+        let string = spans.iter().find(|s| s.kind == SpanKind::String).unwrap();
+        assert_eq!(describe(string).0, "\\\"x\\\"");
+        assert_eq!(describe(string).3, (4, 22));
+
+        let functions = program.functions();
+        assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].name, Some(b"TIC".to_vec()));
+        assert!(functions[0].source.contains(&string.source_offset));
+    }
+
+    #[test]
+    fn keyword_offsets() {
+        let source =
+            b"function TIC()\n local speed=3\n if speed>2 then\n  print(speed, {1;2;})\n end\nend";
+        let mut program = Program::parse(source, TicVersion::LATEST).unwrap();
+        let output = program.serialize(b' ');
+        assert_eq!(
+            output,
+            b"function TIC()local speed=3if speed>2then print(speed,{1,2})end end"
+        );
+        let positions: Vec<(String, (usize, usize))> = program
+            .output_spans()
+            .iter()
+            .filter(|span| span.kind != SpanKind::Separator)
+            .map(|span| {
+                (
+                    String::from_utf8_lossy(&output[span.range.clone()]).into_owned(),
+                    line_col(source, span.source_offset),
+                )
+            })
+            .collect();
+        let expected: &[(&str, (usize, usize))] = &[
+            ("function", (1, 1)),
+            ("TIC", (1, 10)),
+            ("(", (1, 13)),
+            (")", (1, 14)),
+            ("local", (2, 2)),
+            ("speed", (2, 8)),
+            ("=", (2, 13)),
+            ("3", (2, 14)),
+            ("if", (3, 2)),
+            ("speed", (3, 5)),
+            (">", (3, 10)),
+            ("2", (3, 11)),
+            ("then", (3, 13)),
+            ("print", (4, 3)),
+            ("(", (4, 8)),
+            ("speed", (4, 9)),
+            (",", (4, 14)),
+            ("{", (4, 16)),
+            ("1", (4, 17)),
+            (",", (4, 18)),
+            ("2", (4, 19)),
+            ("}", (4, 21)),
+            (")", (4, 22)),
+            ("end", (5, 2)),
+            ("end", (6, 1)),
+        ];
+        let expected: Vec<(String, (usize, usize))> = expected
+            .iter()
+            .map(|&(text, pos)| (text.to_string(), pos))
+            .collect();
+        assert_eq!(positions, expected);
+    }
+
+    #[test]
+    fn pretty_print() {
+        let source: &[u8] = b"-- rename value->v\n-- rename draw->d\nvalue=- -1\n-- transform to load\nfunction draw()\n for i=1,3 do if i>value then print(i..\"!\") end end\nend\nfunction TIC() t={x=1,[2]=draw} ;(t.x)() end";
+        let mut program = Program::parse(source, TicVersion::LATEST).unwrap();
+        let packed = program.serialize(b' ');
+
+        let mut packed_program = Program::parse(&packed, TicVersion::LATEST).unwrap();
+        let renames = read_renames(b"Suggested renames:\n-- rename value->v\n-- rename TIC:t->u\n");
+        assert_eq!(renames.len(), 2);
+        packed_program.apply_renames(&reverse_renames(&renames));
+        let pretty = packed_program.pretty_print();
+        assert_eq!(
+            String::from_utf8(pretty.clone()).unwrap(),
+            "value = - -1\n\
+             \n\
+             function d()\n\
+             \x20 for i = 1, 3 do\n\
+             \x20   if i > value then\n\
+             \x20     print(i .. \"!\")\n\
+             \x20   end\n\
+             \x20 end\n\
+             end\n\
+             \n\
+             function TIC()\n\
+             \x20 t = {x = 1, [2] = d}\n\
+             \x20 ;(t.x)()\n\
+             end\n"
+        );
+
+        // the pretty printed code is valid and stays the same when printed again
+        let program = Program::parse(&pretty, TicVersion::LATEST).unwrap();
+        assert_eq!(program.pretty_print(), pretty);
+
+        // not turned into a function, as the local would shadow the global inside
+        let program = Program::parse(b"local a=1 f=load\"a()\"", TicVersion::LATEST).unwrap();
+        assert_eq!(program.pretty_print(), b"local a = 1\nf = load\"a()\"\n");
+    }
+
+    #[test]
+    fn operator_spaces() {
+        assert_eq!(transform(b"a = - -b"), b"a=- -b");
+        assert_eq!(transform(b"a = 1 .. .5"), b"a=1 .. .5");
+        assert_eq!(transform(b"a = t[ [[x]] ]"), b"a=t[ [[x]]]");
+        assert_eq!(transform(b"a = {1; 2, x = 3,}"), b"a={1,2,x=3}");
+    }
 }
@@ -0,0 +1,254 @@
+//! Names predefined by TIC-80: the API functions, the callbacks TIC-80 calls, the
+//! globals of the Lua standard library and the fields of its library tables.
+
+use anyhow::{bail, Error};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TicVersion {
+    V0_70,
+    V0_80,
+    V0_90,
+    V1_0,
+    V1_1,
+}
+
+impl TicVersion {
+    pub const LATEST: TicVersion = TicVersion::V1_1;
+}
+
+impl FromStr for TicVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TicVersion, Error> {
+        Ok(match s {
+            "0.70" | "0.7" => TicVersion::V0_70,
+            "0.80" | "0.8" => TicVersion::V0_80,
+            "0.90" | "0.9" => TicVersion::V0_90,
+            "1.0" | "1.00" => TicVersion::V1_0,
+            "1.1" | "1.10" => TicVersion::V1_1,
+            _ => bail!(
+                "Unknown TIC-80 version '{}', expected one of 0.70, 0.80, 0.90, 1.0, 1.1",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiKind {
+    /// A function the program defines and TIC-80 calls, like `TIC`.
+    Callback,
+    /// A function provided by TIC-80, like `cls`.
+    Function,
+    /// A global of the Lua standard library, like `math`.
+    Lua,
+}
+
+use ApiKind::*;
+use TicVersion::*;
+
+const API: &[(&str, ApiKind, TicVersion)] = &[
+    ("TIC", Callback, V0_70),
+    ("SCN", Callback, V0_70),
+    ("OVR", Callback, V0_70),
+    ("BDR", Callback, V1_0),
+    ("BOOT", Callback, V1_0),
+    ("MENU", Callback, V1_0),
+    ("btn", Function, V0_70),
+    ("btnp", Function, V0_70),
+    ("circ", Function, V0_70),
+    ("circb", Function, V0_70),
+    ("clip", Function, V0_70),
+    ("cls", Function, V0_70),
+    ("exit", Function, V0_70),
+    ("font", Function, V0_70),
+    ("key", Function, V0_70),
+    ("keyp", Function, V0_70),
+    ("line", Function, V0_70),
+    ("map", Function, V0_70),
+    ("memcpy", Function, V0_70),
+    ("memset", Function, V0_70),
+    ("mget", Function, V0_70),
+    ("mouse", Function, V0_70),
+    ("mset", Function, V0_70),
+    ("music", Function, V0_70),
+    ("peek", Function, V0_70),
+    ("peek4", Function, V0_70),
+    ("pix", Function, V0_70),
+    ("pmem", Function, V0_70),
+    ("poke", Function, V0_70),
+    ("poke4", Function, V0_70),
+    ("print", Function, V0_70),
+    ("rect", Function, V0_70),
+    ("rectb", Function, V0_70),
+    ("reset", Function, V0_70),
+    ("sfx", Function, V0_70),
+    ("spr", Function, V0_70),
+    ("sync", Function, V0_70),
+    ("textri", Function, V0_70),
+    ("time", Function, V0_70),
+    ("trace", Function, V0_70),
+    ("tri", Function, V0_70),
+    ("tstamp", Function, V0_70),
+    ("fget", Function, V0_80),
+    ("fset", Function, V0_80),
+    ("peek1", Function, V0_90),
+    ("peek2", Function, V0_90),
+    ("poke1", Function, V0_90),
+    ("poke2", Function, V0_90),
+    ("elli", Function, V1_0),
+    ("ellib", Function, V1_0),
+    ("trib", Function, V1_0),
+    ("ttri", Function, V1_0),
+    ("vbank", Function, V1_0),
+    ("_G", Lua, V0_70),
+    ("_VERSION", Lua, V0_70),
+    ("_ENV", Lua, V0_70),
+    ("assert", Lua, V0_70),
+    ("collectgarbage", Lua, V0_70),
+    ("error", Lua, V0_70),
+    ("getmetatable", Lua, V0_70),
+    ("ipairs", Lua, V0_70),
+    ("load", Lua, V0_70),
+    ("next", Lua, V0_70),
+    ("pairs", Lua, V0_70),
+    ("pcall", Lua, V0_70),
+    ("rawequal", Lua, V0_70),
+    ("rawget", Lua, V0_70),
+    ("rawlen", Lua, V0_70),
+    ("rawset", Lua, V0_70),
+    ("select", Lua, V0_70),
+    ("setmetatable", Lua, V0_70),
+    ("tonumber", Lua, V0_70),
+    ("tostring", Lua, V0_70),
+    ("type", Lua, V0_70),
+    ("xpcall", Lua, V0_70),
+    ("coroutine", Lua, V0_70),
+    ("debug", Lua, V0_70),
+    ("math", Lua, V0_70),
+    ("string", Lua, V0_70),
+    ("table", Lua, V0_70),
+    ("utf8", Lua, V0_70),
+];
+
+/// The fields of the standard library tables, by table. All TIC-80 versions embed Lua 5.3.
+const LIBRARY: &[(&str, &[&str], TicVersion)] = &[
+    (
+        "coroutine",
+        &[
+            "create",
+            "isyieldable",
+            "resume",
+            "running",
+            "status",
+            "wrap",
+            "yield",
+        ],
+        V0_70,
+    ),
+    (
+        "debug",
+        &[
+            "debug",
+            "gethook",
+            "getinfo",
+            "getlocal",
+            "getmetatable",
+            "getregistry",
+            "getupvalue",
+            "getuservalue",
+            "sethook",
+            "setlocal",
+            "setmetatable",
+            "setupvalue",
+            "setuservalue",
+            "traceback",
+            "upvalueid",
+            "upvaluejoin",
+        ],
+        V0_70,
+    ),
+    (
+        "math",
+        &[
+            "abs",
+            "acos",
+            "asin",
+            "atan",
+            "ceil",
+            "cos",
+            "deg",
+            "exp",
+            "floor",
+            "fmod",
+            "huge",
+            "log",
+            "max",
+            "maxinteger",
+            "min",
+            "mininteger",
+            "modf",
+            "pi",
+            "rad",
+            "random",
+            "randomseed",
+            "sin",
+            "sqrt",
+            "tan",
+            "tointeger",
+            "type",
+            "ult",
+        ],
+        V0_70,
+    ),
+    (
+        "string",
+        &[
+            "byte", "char", "dump", "find", "format", "gmatch", "gsub", "len", "lower", "match",
+            "pack", "packsize", "rep", "reverse", "sub", "unpack", "upper",
+        ],
+        V0_70,
+    ),
+    (
+        "table",
+        &[
+            "concat", "insert", "move", "pack", "remove", "sort", "unpack",
+        ],
+        V0_70,
+    ),
+    (
+        "utf8",
+        &["char", "charpattern", "codepoint", "codes", "len", "offset"],
+        V0_70,
+    ),
+];
+
+/// Returns whether `name` is a standard library table, like `math`.
+pub fn is_library(name: &[u8], version: TicVersion) -> bool {
+    LIBRARY
+        .iter()
+        .any(|&(table, _, since)| table.as_bytes() == name && since <= version)
+}
+
+/// Returns whether `name` is a field of the standard library table `table`, like `sin`
+/// in `math`. String values have the fields of `string` as methods (`s:sub(1,1)`).
+pub fn is_library_field(table: &[u8], name: &[u8], version: TicVersion) -> bool {
+    LIBRARY.iter().any(|&(t, fields, since)| {
+        t.as_bytes() == table && since <= version && fields.iter().any(|f| f.as_bytes() == name)
+    })
+}
+
+/// Returns what kind of predefined name `name` is in TIC-80 `version`, if it is one.
+pub fn lookup(name: &[u8], version: TicVersion) -> Option<ApiKind> {
+    API.iter()
+        .find(|&&(api_name, _, since)| api_name.as_bytes() == name && since <= version)
+        .map(|&(_, kind, _)| kind)
+}
+
+/// Iterates over all predefined names in TIC-80 `version`.
+pub fn names(version: TicVersion) -> impl Iterator<Item = &'static [u8]> {
+    API.iter()
+        .filter(move |&&(_, _, since)| since <= version)
+        .map(|&(name, ..)| name.as_bytes())
+}
@@ -0,0 +1,399 @@
+//! Syntax tree of Lua 5.3 code.
+//!
+//! All names, literals and operators keep the source offset they were parsed from, and
+//! statements the offsets of their keywords and punctuation. For code inside code
+//! strings, these are offsets into the outermost source.
+
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub offset: usize,
+    /// The offsets of the keywords and punctuation of the statement (like `=`, `,`,
+    /// `then`, `(` or `end`, also those of the expressions in it), in source order.
+    /// Those of nested statements are kept in these statements.
+    pub marks: Vec<usize>,
+    pub kind: StmtKind,
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum StmtKind {
+    Empty,
+    Assign {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+    },
+    Call(Expr),
+    Label(Token),
+    Break,
+    Goto(Token),
+    Do(Block),
+    While {
+        cond: Expr,
+        body: Block,
+    },
+    Repeat {
+        body: Block,
+        cond: Expr,
+    },
+    If {
+        branches: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+    },
+    NumericFor {
+        var: Token,
+        start: Expr,
+        end: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    GenericFor {
+        names: Vec<Token>,
+        exprs: Vec<Expr>,
+        body: Block,
+    },
+    Function {
+        name: FunctionName,
+        func: FunctionBody,
+    },
+    LocalFunction {
+        name: Token,
+        func: FunctionBody,
+    },
+    Local {
+        names: Vec<Token>,
+        values: Vec<Expr>,
+    },
+    Return(Vec<Expr>),
+}
+
+/// A name, literal or operator together with its source offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: Vec<u8>,
+    pub offset: usize,
+    /// For names, the symbol they were resolved to by `scope::resolve`.
+    pub symbol: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionName {
+    pub path: Vec<Token>,
+    pub method: Option<Token>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionBody {
+    pub offset: usize,
+    pub params: Vec<Token>,
+    pub is_vararg: bool,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Nil(usize),
+    True(usize),
+    False(usize),
+    Vararg(usize),
+    Number(Token),
+    String(StringLiteral),
+    Function(FunctionBody),
+    Table(TableConstructor),
+    Binary {
+        op: Token,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Unary {
+        op: Token,
+        expr: Box<Expr>,
+    },
+    Paren(Box<Expr>),
+    Name(Token),
+    Index {
+        obj: Box<Expr>,
+        key: Box<Expr>,
+    },
+    Field {
+        obj: Box<Expr>,
+        name: Token,
+    },
+    Call {
+        func: Box<Expr>,
+        args: Args,
+    },
+    Method {
+        obj: Box<Expr>,
+        name: Token,
+        args: Args,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum StringLiteral {
+    Plain(Token),
+    /// A string holding code (passed to `load` or marked with `-- code string`),
+    /// parsed so that it can be minified and renamed together with the outer code.
+    Code {
+        offset: usize,
+        delim: u8,
+        block: Block,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TableConstructor {
+    pub offset: usize,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Field {
+    Named { name: Token, value: Expr },
+    Keyed { key: Expr, value: Expr },
+    Positional(Expr),
+}
+
+#[derive(Debug, Clone)]
+pub enum Args {
+    List(Vec<Expr>),
+    Table(TableConstructor),
+    String(StringLiteral),
+}
+
+impl Expr {
+    pub fn is_call(&self) -> bool {
+        matches!(*self, Expr::Call { .. } | Expr::Method { .. })
+    }
+}
+
+/// The role a name plays at the place it appears in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameKind {
+    /// A variable being read (or called).
+    Variable,
+    /// A variable being assigned to, or the first part of a `function a.b.c` name.
+    Assigned,
+    /// A newly declared local: `local` names, parameters and loop variables.
+    Local,
+    /// A field accessed with `.` or `:`.
+    Field,
+    /// A field being assigned to, like `x` in `t.x=1`.
+    AssignedField,
+    /// A field name in a table constructor, like `x` in `{x=1}`.
+    TableKey,
+    Label,
+}
+
+impl Block {
+    /// Calls `f` for every name in the block, including field, method and label names.
+    pub fn for_each_name_mut(&mut self, f: &mut dyn FnMut(&mut Token, NameKind)) {
+        for stmt in &mut self.stmts {
+            stmt_names_mut(stmt, f);
+        }
+    }
+}
+
+fn stmt_names_mut(stmt: &mut Stmt, f: &mut dyn FnMut(&mut Token, NameKind)) {
+    match stmt.kind {
+        StmtKind::Empty | StmtKind::Break => (),
+        StmtKind::Assign {
+            ref mut targets,
+            ref mut values,
+        } => {
+            for target in targets.iter_mut() {
+                match *target {
+                    Expr::Name(ref mut name) => f(name, NameKind::Assigned),
+                    Expr::Field {
+                        ref mut obj,
+                        ref mut name,
+                    } => {
+                        expr_names_mut(obj, f);
+                        f(name, NameKind::AssignedField);
+                    }
+                    _ => expr_names_mut(target, f),
+                }
+            }
+            values.iter_mut().for_each(|e| expr_names_mut(e, f));
+        }
+        StmtKind::Call(ref mut expr) => expr_names_mut(expr, f),
+        StmtKind::Label(ref mut name) | StmtKind::Goto(ref mut name) => f(name, NameKind::Label),
+        StmtKind::Do(ref mut body) => body.for_each_name_mut(f),
+        StmtKind::While {
+            ref mut cond,
+            ref mut body,
+        }
+        | StmtKind::Repeat {
+            ref mut cond,
+            ref mut body,
+        } => {
+            expr_names_mut(cond, f);
+            body.for_each_name_mut(f);
+        }
+        StmtKind::If {
+            ref mut branches,
+            ref mut else_block,
+        } => {
+            for &mut (ref mut cond, ref mut body) in branches {
+                expr_names_mut(cond, f);
+                body.for_each_name_mut(f);
+            }
+            if let Some(ref mut body) = *else_block {
+                body.for_each_name_mut(f);
+            }
+        }
+        StmtKind::NumericFor {
+            ref mut var,
+            ref mut start,
+            ref mut end,
+            ref mut step,
+            ref mut body,
+        } => {
+            f(var, NameKind::Local);
+            expr_names_mut(start, f);
+            expr_names_mut(end, f);
+            if let Some(ref mut step) = *step {
+                expr_names_mut(step, f);
+            }
+            body.for_each_name_mut(f);
+        }
+        StmtKind::GenericFor {
+            ref mut names,
+            ref mut exprs,
+            ref mut body,
+        } => {
+            names.iter_mut().for_each(|n| f(n, NameKind::Local));
+            exprs.iter_mut().for_each(|e| expr_names_mut(e, f));
+            body.for_each_name_mut(f);
+        }
+        StmtKind::Function {
+            ref mut name,
+            ref mut func,
+        } => {
+            for (i, part) in name.path.iter_mut().enumerate() {
+                f(
+                    part,
+                    if i == 0 {
+                        NameKind::Assigned
+                    } else {
+                        NameKind::Field
+                    },
+                );
+            }
+            if let Some(ref mut method) = name.method {
+                f(method, NameKind::Field);
+            }
+            function_names_mut(func, f);
+        }
+        StmtKind::LocalFunction {
+            ref mut name,
+            ref mut func,
+        } => {
+            f(name, NameKind::Local);
+            function_names_mut(func, f);
+        }
+        StmtKind::Local {
+            ref mut names,
+            ref mut values,
+        } => {
+            names.iter_mut().for_each(|n| f(n, NameKind::Local));
+            values.iter_mut().for_each(|e| expr_names_mut(e, f));
+        }
+        StmtKind::Return(ref mut values) => values.iter_mut().for_each(|e| expr_names_mut(e, f)),
+    }
+}
+
+fn function_names_mut(func: &mut FunctionBody, f: &mut dyn FnMut(&mut Token, NameKind)) {
+    func.params.iter_mut().for_each(|n| f(n, NameKind::Local));
+    func.body.for_each_name_mut(f);
+}
+
+fn expr_names_mut(expr: &mut Expr, f: &mut dyn FnMut(&mut Token, NameKind)) {
+    match *expr {
+        Expr::Nil(_) | Expr::True(_) | Expr::False(_) | Expr::Vararg(_) | Expr::Number(_) => (),
+        Expr::String(ref mut string) => string_names_mut(string, f),
+        Expr::Function(ref mut func) => function_names_mut(func, f),
+        Expr::Table(ref mut table) => table_names_mut(table, f),
+        Expr::Binary {
+            ref mut lhs,
+            ref mut rhs,
+            ..
+        } => {
+            expr_names_mut(lhs, f);
+            expr_names_mut(rhs, f);
+        }
+        Expr::Unary { ref mut expr, .. } | Expr::Paren(ref mut expr) => expr_names_mut(expr, f),
+        Expr::Name(ref mut name) => f(name, NameKind::Variable),
+        Expr::Index {
+            ref mut obj,
+            ref mut key,
+        } => {
+            expr_names_mut(obj, f);
+            expr_names_mut(key, f);
+        }
+        Expr::Field {
+            ref mut obj,
+            ref mut name,
+        } => {
+            expr_names_mut(obj, f);
+            f(name, NameKind::Field);
+        }
+        Expr::Call {
+            ref mut func,
+            ref mut args,
+        } => {
+            expr_names_mut(func, f);
+            args_names_mut(args, f);
+        }
+        Expr::Method {
+            ref mut obj,
+            ref mut name,
+            ref mut args,
+        } => {
+            expr_names_mut(obj, f);
+            f(name, NameKind::Field);
+            args_names_mut(args, f);
+        }
+    }
+}
+
+fn args_names_mut(args: &mut Args, f: &mut dyn FnMut(&mut Token, NameKind)) {
+    match *args {
+        Args::List(ref mut exprs) => exprs.iter_mut().for_each(|e| expr_names_mut(e, f)),
+        Args::Table(ref mut table) => table_names_mut(table, f),
+        Args::String(ref mut string) => string_names_mut(string, f),
+    }
+}
+
+fn string_names_mut(string: &mut StringLiteral, f: &mut dyn FnMut(&mut Token, NameKind)) {
+    if let StringLiteral::Code { ref mut block, .. } = *string {
+        block.for_each_name_mut(f);
+    }
+}
+
+fn table_names_mut(table: &mut TableConstructor, f: &mut dyn FnMut(&mut Token, NameKind)) {
+    for field in &mut table.fields {
+        match *field {
+            Field::Named {
+                ref mut name,
+                ref mut value,
+            } => {
+                f(name, NameKind::TableKey);
+                expr_names_mut(value, f);
+            }
+            Field::Keyed {
+                ref mut key,
+                ref mut value,
+            } => {
+                expr_names_mut(key, f);
+                expr_names_mut(value, f);
+            }
+            Field::Positional(ref mut value) => expr_names_mut(value, f),
+        }
+    }
+}
@@ -0,0 +1,470 @@
+use super::ast::*;
+use super::TokenType;
+use std::ops::Range;
+
+/// One token of the flattened program, ready to be serialized.
+#[derive(Debug, Clone)]
+pub enum FlatToken {
+    Token {
+        type_: TokenType,
+        text: Vec<u8>,
+        /// Offset of the token in the source code. Keywords and punctuation which
+        /// aren't in the source (added by transforms) inherit the offset of the
+        /// closest preceding token.
+        offset: usize,
+        /// Offset of the token in the serialized code, filled in by `serialize`.
+        out_offset: usize,
+        /// For names, the symbol they refer to.
+        symbol: Option<usize>,
+    },
+    CodeStringStart(u8),
+    CodeStringEnd(u8),
+}
+
+/// A function of the program and the source offsets of its tokens.
+#[derive(Debug, Clone)]
+pub struct FunctionSpan {
+    /// The name the function is declared or assigned to, `None` for anonymous functions.
+    pub name: Option<Vec<u8>>,
+    pub source: Range<usize>,
+}
+
+pub fn flatten(block: &Block) -> Vec<FlatToken> {
+    let mut emitter = Emitter::new();
+    emitter.block(block);
+    emitter.tokens
+}
+
+/// Flattens a single string, for code strings printed on their own.
+pub fn flatten_string(string: &StringLiteral) -> Vec<FlatToken> {
+    let mut emitter = Emitter::new();
+    emitter.string(string);
+    emitter.tokens
+}
+
+/// The token type of a numeric literal.
+pub fn number_type(text: &[u8]) -> TokenType {
+    if text.len() > 1 && text[1].eq_ignore_ascii_case(&b'x') {
+        TokenType::HexNumber
+    } else {
+        TokenType::Number
+    }
+}
+
+/// Returns the spans of all functions in the block, inner functions before the functions
+/// containing them.
+pub fn function_spans(block: &Block) -> Vec<FunctionSpan> {
+    let mut emitter = Emitter::new();
+    emitter.block(block);
+    emitter.functions
+}
+
+struct Emitter {
+    tokens: Vec<FlatToken>,
+    offset: usize,
+    /// The remaining keyword and punctuation offsets of the statements being emitted.
+    marks: Vec<std::vec::IntoIter<usize>>,
+    functions: Vec<FunctionSpan>,
+    /// The name for the function expression emitted next.
+    function_name: Option<Vec<u8>>,
+}
+
+/// The name a function assigned to `target` is known by.
+fn target_name(target: &Expr) -> Option<Vec<u8>> {
+    match *target {
+        Expr::Name(ref name) | Expr::Field { ref name, .. } => Some(name.text.clone()),
+        _ => None,
+    }
+}
+
+impl Emitter {
+    fn new() -> Emitter {
+        Emitter {
+            tokens: vec![],
+            offset: 0,
+            marks: vec![],
+            functions: vec![],
+            function_name: None,
+        }
+    }
+
+    fn push(&mut self, type_: TokenType, text: &[u8], offset: usize) {
+        self.push_symbol(type_, text, offset, None);
+    }
+
+    fn push_symbol(&mut self, type_: TokenType, text: &[u8], offset: usize, symbol: Option<usize>) {
+        self.offset = offset;
+        self.tokens.push(FlatToken::Token {
+            type_,
+            text: text.to_vec(),
+            offset,
+            out_offset: 0,
+            symbol,
+        });
+    }
+
+    fn next_mark(&mut self) -> usize {
+        match self.marks.last_mut().and_then(Iterator::next) {
+            Some(offset) => offset,
+            None => self.offset,
+        }
+    }
+
+    fn keyword(&mut self, text: &[u8]) {
+        let offset = self.next_mark();
+        self.push(TokenType::Identifier, text, offset);
+    }
+
+    fn keyword_at(&mut self, text: &[u8], offset: usize) {
+        self.push(TokenType::Identifier, text, offset);
+    }
+
+    fn symbol(&mut self, text: &[u8]) {
+        let offset = self.next_mark();
+        self.push(TokenType::Other, text, offset);
+    }
+
+    fn name(&mut self, name: &Token) {
+        self.push_symbol(TokenType::Identifier, &name.text, name.offset, name.symbol);
+    }
+
+    fn block(&mut self, block: &Block) {
+        for stmt in &block.stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn list<T>(&mut self, items: &[T], mut f: impl FnMut(&mut Self, &T)) {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.symbol(b",");
+            }
+            f(self, item);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        self.list(exprs, Self::expr);
+    }
+
+    fn names(&mut self, names: &[Token]) {
+        self.list(names, Self::name);
+    }
+
+    /// Emits the values of an assignment, naming function values after their targets.
+    fn values(&mut self, values: &[Expr], names: &[Option<Vec<u8>>]) {
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.symbol(b",");
+            }
+            if let Expr::Function(_) = *value {
+                self.function_name = names.get(i).cloned().flatten();
+            }
+            self.expr(value);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.offset = stmt.offset;
+        self.marks.push(stmt.marks.clone().into_iter());
+        self.stmt_kind(stmt);
+        self.marks.pop();
+    }
+
+    fn stmt_kind(&mut self, stmt: &Stmt) {
+        match stmt.kind {
+            StmtKind::Empty => self.symbol(b";"),
+            StmtKind::Assign {
+                ref targets,
+                ref values,
+            } => {
+                self.exprs(targets);
+                self.symbol(b"=");
+                let names: Vec<_> = targets.iter().map(target_name).collect();
+                self.values(values, &names);
+            }
+            StmtKind::Call(ref expr) => self.expr(expr),
+            StmtKind::Label(ref name) => {
+                self.symbol(b"::");
+                self.name(name);
+                self.symbol(b"::");
+            }
+            StmtKind::Break => self.keyword(b"break"),
+            StmtKind::Goto(ref name) => {
+                self.keyword(b"goto");
+                self.name(name);
+            }
+            StmtKind::Do(ref body) => {
+                self.keyword(b"do");
+                self.block(body);
+                self.keyword(b"end");
+            }
+            StmtKind::While { ref cond, ref body } => {
+                self.keyword(b"while");
+                self.expr(cond);
+                self.keyword(b"do");
+                self.block(body);
+                self.keyword(b"end");
+            }
+            StmtKind::Repeat { ref body, ref cond } => {
+                self.keyword(b"repeat");
+                self.block(body);
+                self.keyword(b"until");
+                self.expr(cond);
+            }
+            StmtKind::If {
+                ref branches,
+                ref else_block,
+            } => {
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    self.keyword(if i == 0 { b"if" } else { b"elseif" });
+                    self.expr(cond);
+                    self.keyword(b"then");
+                    self.block(body);
+                }
+                if let Some(ref body) = *else_block {
+                    self.keyword(b"else");
+                    self.block(body);
+                }
+                self.keyword(b"end");
+            }
+            StmtKind::NumericFor {
+                ref var,
+                ref start,
+                ref end,
+                ref step,
+                ref body,
+            } => {
+                self.keyword(b"for");
+                self.name(var);
+                self.symbol(b"=");
+                self.expr(start);
+                self.symbol(b",");
+                self.expr(end);
+                if let Some(ref step) = *step {
+                    self.symbol(b",");
+                    self.expr(step);
+                }
+                self.keyword(b"do");
+                self.block(body);
+                self.keyword(b"end");
+            }
+            StmtKind::GenericFor {
+                ref names,
+                ref exprs,
+                ref body,
+            } => {
+                self.keyword(b"for");
+                self.names(names);
+                self.keyword(b"in");
+                self.exprs(exprs);
+                self.keyword(b"do");
+                self.block(body);
+                self.keyword(b"end");
+            }
+            StmtKind::Function { ref name, ref func } => {
+                let mut full_name = vec![];
+                for (i, part) in name.path.iter().enumerate() {
+                    if i > 0 {
+                        full_name.push(b'.');
+                    }
+                    full_name.extend_from_slice(&part.text);
+                }
+                if let Some(ref method) = name.method {
+                    full_name.push(b':');
+                    full_name.extend_from_slice(&method.text);
+                }
+                self.function_name = Some(full_name);
+                self.keyword(b"function");
+                for (i, part) in name.path.iter().enumerate() {
+                    if i > 0 {
+                        self.symbol(b".");
+                    }
+                    self.name(part);
+                }
+                if let Some(ref method) = name.method {
+                    self.symbol(b":");
+                    self.name(method);
+                }
+                self.function_body(func);
+            }
+            StmtKind::LocalFunction { ref name, ref func } => {
+                self.keyword(b"local");
+                self.keyword_at(b"function", func.offset);
+                self.name(name);
+                self.function_name = Some(name.text.clone());
+                self.function_body(func);
+            }
+            StmtKind::Local {
+                ref names,
+                ref values,
+            } => {
+                self.keyword(b"local");
+                self.names(names);
+                if !values.is_empty() {
+                    self.symbol(b"=");
+                    let names: Vec<_> = names.iter().map(|n| Some(n.text.clone())).collect();
+                    self.values(values, &names);
+                }
+            }
+            StmtKind::Return(ref values) => {
+                self.keyword(b"return");
+                self.exprs(values);
+            }
+        }
+    }
+
+    fn function_body(&mut self, func: &FunctionBody) {
+        let name = self.function_name.take();
+        let start = self.tokens.len();
+        self.symbol(b"(");
+        self.names(&func.params);
+        if func.is_vararg {
+            if !func.params.is_empty() {
+                self.symbol(b",");
+            }
+            self.symbol(b"...");
+        }
+        self.symbol(b")");
+        self.block(&func.body);
+        self.keyword(b"end");
+
+        let mut source = func.offset..func.offset + 1;
+        for token in &self.tokens[start..] {
+            if let FlatToken::Token { offset, .. } = *token {
+                source.start = source.start.min(offset);
+                source.end = source.end.max(offset + 1);
+            }
+        }
+        self.functions.push(FunctionSpan { name, source });
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Nil(offset) => self.keyword_at(b"nil", offset),
+            Expr::True(offset) => self.keyword_at(b"true", offset),
+            Expr::False(offset) => self.keyword_at(b"false", offset),
+            Expr::Vararg(offset) => self.push(TokenType::Other, b"...", offset),
+            Expr::Number(ref number) => {
+                self.push(number_type(&number.text), &number.text, number.offset)
+            }
+            Expr::String(ref string) => self.string(string),
+            Expr::Function(ref func) => {
+                self.keyword_at(b"function", func.offset);
+                self.function_body(func);
+            }
+            Expr::Table(ref table) => self.table(table),
+            Expr::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+            } => {
+                self.expr(lhs);
+                self.operator(op);
+                self.expr(rhs);
+            }
+            Expr::Unary { ref op, ref expr } => {
+                self.operator(op);
+                self.expr(expr);
+            }
+            Expr::Paren(ref expr) => {
+                self.symbol(b"(");
+                self.expr(expr);
+                self.symbol(b")");
+            }
+            Expr::Name(ref name) => self.name(name),
+            Expr::Index { ref obj, ref key } => {
+                self.expr(obj);
+                self.symbol(b"[");
+                self.expr(key);
+                self.symbol(b"]");
+            }
+            Expr::Field { ref obj, ref name } => {
+                self.expr(obj);
+                self.symbol(b".");
+                self.name(name);
+            }
+            Expr::Call { ref func, ref args } => {
+                self.expr(func);
+                self.args(args);
+            }
+            Expr::Method {
+                ref obj,
+                ref name,
+                ref args,
+            } => {
+                self.expr(obj);
+                self.symbol(b":");
+                self.name(name);
+                self.args(args);
+            }
+        }
+    }
+
+    fn operator(&mut self, op: &Token) {
+        let type_ = if op.text[0].is_ascii_alphabetic() {
+            TokenType::Identifier
+        } else {
+            TokenType::Other
+        };
+        self.push(type_, &op.text, op.offset);
+    }
+
+    fn args(&mut self, args: &Args) {
+        match *args {
+            Args::List(ref exprs) => {
+                self.symbol(b"(");
+                self.exprs(exprs);
+                self.symbol(b")");
+            }
+            Args::Table(ref table) => self.table(table),
+            Args::String(ref string) => self.string(string),
+        }
+    }
+
+    fn string(&mut self, string: &StringLiteral) {
+        match *string {
+            StringLiteral::Plain(ref token) => {
+                let type_ = if token.text[0] == b'[' {
+                    TokenType::Other
+                } else {
+                    TokenType::String
+                };
+                self.push(type_, &token.text, token.offset);
+            }
+            StringLiteral::Code {
+                offset,
+                delim,
+                ref block,
+            } => {
+                self.offset = offset;
+                self.tokens.push(FlatToken::CodeStringStart(delim));
+                self.block(block);
+                self.tokens.push(FlatToken::CodeStringEnd(delim));
+            }
+        }
+    }
+
+    fn table(&mut self, table: &TableConstructor) {
+        self.push(TokenType::Other, b"{", table.offset);
+        self.list(&table.fields, |emitter, field| match *field {
+            Field::Named {
+                ref name,
+                ref value,
+            } => {
+                emitter.name(name);
+                emitter.symbol(b"=");
+                emitter.values(std::slice::from_ref(value), &[Some(name.text.clone())]);
+            }
+            Field::Keyed { ref key, ref value } => {
+                emitter.symbol(b"[");
+                emitter.expr(key);
+                emitter.symbol(b"]");
+                emitter.symbol(b"=");
+                emitter.expr(value);
+            }
+            Field::Positional(ref value) => emitter.expr(value),
+        });
+        self.symbol(b"}");
+    }
+}
@@ -0,0 +1,178 @@
+//! Equivalent forms of numeric literals. Lua 5.3 distinguishes integers from floats
+//! (`1e3` is the float `1000.0`), so only forms of the same type are equivalent, unless
+//! the caller accepts integers turning into floats.
+
+/// The literals with the same value and type as `text`, or with the same value as a
+/// float too if `allow_float`. Only forms at most two bytes longer than the shortest are
+/// included, shortest first, with `text` first among the ones of its length. Returns just
+/// `text` for literals which can't be rewritten safely, like hex integers wrapping around.
+pub fn equivalent_forms(text: &[u8], allow_float: bool) -> Vec<Vec<u8>> {
+    let mut forms = vec![text.to_vec()];
+    forms.extend(match parse(text) {
+        Some(Value::Integer(value)) => integer_forms(value, allow_float),
+        Some(Value::Float(value)) => float_forms(value),
+        None => vec![],
+    });
+    let shortest = forms.iter().map(Vec::len).min().unwrap();
+    let mut unique: Vec<Vec<u8>> = vec![];
+    for form in forms {
+        if form.len() <= shortest + 2 && !unique.contains(&form) {
+            unique.push(form);
+        }
+    }
+    // stable, so `text` stays first among the forms of its length
+    unique.sort_by_key(Vec::len);
+    unique
+}
+
+enum Value {
+    Integer(u64),
+    Float(f64),
+}
+
+fn parse(text: &[u8]) -> Option<Value> {
+    let text = std::str::from_utf8(text).ok()?.to_ascii_lowercase();
+    if let Some(hex) = text.strip_prefix("0x") {
+        if hex.contains('.') || hex.contains('p') {
+            return parse_hex_float(hex).map(Value::Float);
+        }
+        // longer hex integers wrap around
+        let digits = hex.trim_start_matches('0');
+        if digits.len() > 16 {
+            return None;
+        }
+        let value = u64::from_str_radix(hex, 16).ok()?;
+        return if value <= i64::MAX as u64 {
+            Some(Value::Integer(value))
+        } else {
+            None
+        };
+    }
+    if text.contains('.') || text.contains('e') {
+        return text
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .map(Value::Float);
+    }
+    // decimal integers which don't fit turn into floats
+    text.parse::<u64>()
+        .ok()
+        .filter(|&v| v <= i64::MAX as u64)
+        .map(Value::Integer)
+}
+
+/// Parses the part of a hex float after `0x`, if it converts to a float exactly.
+fn parse_hex_float(hex: &str) -> Option<f64> {
+    let (mantissa, exponent) = match hex.find('p') {
+        Some(pos) => (&hex[..pos], hex[pos + 1..].parse::<i32>().ok()?),
+        None => (hex, 0),
+    };
+    let (int, frac) = match mantissa.find('.') {
+        Some(pos) => (&mantissa[..pos], &mantissa[pos + 1..]),
+        None => (mantissa, ""),
+    };
+    let digits = format!("{}{}", int, frac);
+    if digits.is_empty() || digits.trim_start_matches('0').len() > 13 {
+        return None;
+    }
+    let value = u64::from_str_radix(&digits, 16).ok()? as f64;
+    let value = value * 2f64.powi(exponent - 4 * frac.len() as i32);
+    // reject overflows and underflows
+    let zero = digits.trim_start_matches('0').is_empty();
+    if value.is_finite() && (value != 0. || zero) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn integer_forms(value: u64, allow_float: bool) -> Vec<Vec<u8>> {
+    let mut forms = vec![
+        value.to_string().into_bytes(),
+        format!("0x{:x}", value).into_bytes(),
+    ];
+    if allow_float && value < 1 << 53 {
+        forms.extend(float_forms(value as f64));
+    }
+    forms
+}
+
+/// The decimal float literals of `value`, which have to contain a `.` or `e`.
+fn float_forms(value: f64) -> Vec<Vec<u8>> {
+    // the shortest digits which read back as `value`, `value = digits * 10^exponent`
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+    let exponent = exponent[1..].parse::<i32>().unwrap() - (digits.len() as i32 - 1);
+    let len = digits.len() as i32;
+
+    let mut forms = vec![];
+    if exponent >= 0 {
+        forms.push(format!("{}{}.", digits, "0".repeat(exponent as usize)));
+    } else if len > -exponent {
+        let (int, frac) = digits.split_at((len + exponent) as usize);
+        forms.push(format!("{}.{}", int, frac));
+    } else {
+        forms.push(format!(
+            ".{}{}",
+            "0".repeat((-exponent - len) as usize),
+            digits
+        ));
+    }
+    if exponent != 0 {
+        forms.push(format!("{}e{}", digits, exponent));
+    }
+    if len > 1 {
+        forms.push(format!(
+            "{}.{}e{}",
+            &digits[..1],
+            &digits[1..],
+            exponent + len - 1
+        ));
+    }
+    forms
+        .into_iter()
+        .filter(|form| form.parse::<f64>().ok() == Some(value))
+        .map(String::into_bytes)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn forms(text: &str, allow_float: bool) -> Vec<String> {
+        equivalent_forms(text.as_bytes(), allow_float)
+            .into_iter()
+            .map(|form| String::from_utf8(form).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn number_forms() {
+        assert_eq!(forms("0.5", false), vec![".5", "0.5", "5e-1"]);
+        assert_eq!(forms("100.0", false), vec!["1e2", "100.", "100.0"]);
+        assert_eq!(forms("0x10", false), vec!["16", "0x10"]);
+        assert_eq!(forms("0010", false), vec!["10", "0xa", "0010"]);
+        assert_eq!(forms("1.5e-4", false), vec!["15e-5", "1.5e-4", ".00015"]);
+        assert_eq!(forms("0x1p4", false), vec!["16.", "0x1p4", "1.6e1"]);
+        assert_eq!(forms("0x.8", false), vec![".5", "0x.8", "5e-1"]);
+
+        // integers only turn into floats if allowed
+        assert_eq!(forms("30000", false), vec!["30000", "0x7530"]);
+        assert_eq!(forms("30000", true), vec!["3e4", "30000"]);
+        assert_eq!(forms("1000", true), vec!["1e3", "1000", "0x3e8", "1000."]);
+        assert_eq!(forms("1.0", true), vec!["1.", "1.0"]);
+
+        // wrapping hex integers and decimal integers which don't fit are kept
+        assert_eq!(
+            forms("0xffffffffffffffff", false),
+            vec!["0xffffffffffffffff"]
+        );
+        assert_eq!(
+            forms("9223372036854775808", true),
+            vec!["9223372036854775808"]
+        );
+    }
+}
@@ -0,0 +1,692 @@
+use super::ast::*;
+use super::{SourceToken, TokenType};
+use anyhow::{anyhow, Result};
+
+const UNARY_PRIORITY: u8 = 12;
+
+/// Parses a token stream (as returned by `tokenize`) into a block.
+///
+/// `source` is only used to turn offsets into line and column numbers for error messages.
+pub fn parse_chunk(tokens: &[SourceToken], source: &[u8]) -> Result<Block> {
+    let mut parser = Parser {
+        tokens: tokens
+            .iter()
+            .filter(|t| t.type_ != TokenType::Comment)
+            .collect(),
+        pos: 0,
+        source,
+        marks: vec![],
+        is_vararg: true,
+    };
+    let block = parser.block()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error("'<eof>' expected"));
+    }
+    Ok(block)
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a SourceToken>,
+    pos: usize,
+    source: &'a [u8],
+    /// The offsets of the keywords and punctuation of the statements being parsed,
+    /// innermost last.
+    marks: Vec<Vec<usize>>,
+    /// Whether `...` may be used in the function being parsed, a chunk is always vararg.
+    is_vararg: bool,
+}
+
+pub fn is_keyword(text: &[u8]) -> bool {
+    matches!(
+        text,
+        b"and"
+            | b"break"
+            | b"do"
+            | b"else"
+            | b"elseif"
+            | b"end"
+            | b"false"
+            | b"for"
+            | b"function"
+            | b"goto"
+            | b"if"
+            | b"in"
+            | b"local"
+            | b"nil"
+            | b"not"
+            | b"or"
+            | b"repeat"
+            | b"return"
+            | b"then"
+            | b"true"
+            | b"until"
+            | b"while"
+    )
+}
+
+fn is_string(token: &SourceToken) -> bool {
+    token.type_ == TokenType::String
+        || (token.type_ == TokenType::Other
+            && token.text.len() > 1
+            && token.text[0] == b'['
+            && (token.text[1] == b'[' || token.text[1] == b'='))
+}
+
+fn binary_priority(text: &[u8]) -> Option<(u8, u8)> {
+    Some(match text {
+        b"or" => (1, 1),
+        b"and" => (2, 2),
+        b"<" | b">" | b"<=" | b">=" | b"~=" | b"==" => (3, 3),
+        b"|" => (4, 4),
+        b"~" => (5, 5),
+        b"&" => (6, 6),
+        b"<<" | b">>" => (7, 7),
+        b".." => (9, 8),
+        b"+" | b"-" => (10, 10),
+        b"*" | b"/" | b"//" | b"%" => (11, 11),
+        b"^" => (14, 13),
+        _ => return None,
+    })
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a SourceToken> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn peek_text(&self) -> &'a [u8] {
+        self.peek()
+            .filter(|t| !is_string(t))
+            .map(|t| t.text.as_slice())
+            .unwrap_or(b"")
+    }
+
+    fn check(&self, text: &[u8]) -> bool {
+        self.peek_text() == text
+    }
+
+    fn check_next(&self, text: &[u8]) -> bool {
+        match self.tokens.get(self.pos + 1) {
+            Some(t) => !is_string(t) && t.text == text,
+            None => false,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        match self.peek() {
+            Some(t) => t.offset,
+            None => self.source.len(),
+        }
+    }
+
+    fn next(&mut self) -> Result<&'a SourceToken> {
+        let token = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of code"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Consumes a keyword or punctuation token, recording its offset for the statement.
+    fn skip(&mut self) {
+        let offset = self.offset();
+        if let Some(marks) = self.marks.last_mut() {
+            marks.push(offset);
+        }
+        self.pos += 1;
+    }
+
+    fn accept(&mut self, text: &[u8]) -> bool {
+        if self.check(text) {
+            self.skip();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, text: &[u8]) -> Result<()> {
+        if self.accept(text) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}' expected", String::from_utf8_lossy(text))))
+        }
+    }
+
+    fn token(&mut self) -> Result<Token> {
+        let token = self.next()?;
+        Ok(Token {
+            text: token.text.clone(),
+            offset: token.offset,
+            symbol: None,
+        })
+    }
+
+    fn check_name(&self) -> bool {
+        matches!(self.peek(), Some(t) if t.type_ == TokenType::Identifier && !is_keyword(&t.text))
+    }
+
+    fn check_string(&self) -> bool {
+        matches!(self.peek(), Some(t) if is_string(t))
+    }
+
+    fn name(&mut self) -> Result<Token> {
+        if self.check_name() {
+            self.token()
+        } else {
+            Err(self.error("<name> expected"))
+        }
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        let (line, column) = super::line_col(self.source, self.offset());
+        let near = match self.peek() {
+            Some(t) => String::from_utf8_lossy(&t.text).into_owned(),
+            None => "<eof>".to_string(),
+        };
+        anyhow!(
+            "Lua syntax error at line {}, column {}: {} near '{}'",
+            line,
+            column,
+            msg,
+            near
+        )
+    }
+
+    fn block_follows(&self) -> bool {
+        match self.peek() {
+            None => true,
+            Some(_) => matches!(self.peek_text(), b"else" | b"elseif" | b"end" | b"until"),
+        }
+    }
+
+    fn block(&mut self) -> Result<Block> {
+        let mut stmts = vec![];
+        while !self.block_follows() {
+            if self.check(b"return") {
+                let offset = self.offset();
+                self.marks.push(vec![]);
+                self.skip();
+                let values = if self.block_follows() || self.check(b";") {
+                    vec![]
+                } else {
+                    self.expr_list()?
+                };
+                stmts.push(Stmt {
+                    offset,
+                    marks: self.marks.pop().unwrap(),
+                    kind: StmtKind::Return(values),
+                });
+                if self.check(b";") {
+                    stmts.push(Stmt {
+                        offset: self.offset(),
+                        marks: vec![self.offset()],
+                        kind: StmtKind::Empty,
+                    });
+                    self.pos += 1;
+                }
+                if !self.block_follows() {
+                    return Err(self.error("'end' expected"));
+                }
+                break;
+            }
+            stmts.push(self.statement()?);
+        }
+        Ok(Block { stmts })
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        let offset = self.offset();
+        self.marks.push(vec![]);
+        let kind = match self.peek_text() {
+            b";" => {
+                self.skip();
+                StmtKind::Empty
+            }
+            b"::" => {
+                self.skip();
+                let name = self.name()?;
+                self.expect(b"::")?;
+                StmtKind::Label(name)
+            }
+            b"break" => {
+                self.skip();
+                StmtKind::Break
+            }
+            b"goto" => {
+                self.skip();
+                StmtKind::Goto(self.name()?)
+            }
+            b"do" => {
+                self.skip();
+                let body = self.block()?;
+                self.expect(b"end")?;
+                StmtKind::Do(body)
+            }
+            b"while" => {
+                self.skip();
+                let cond = self.expr()?;
+                self.expect(b"do")?;
+                let body = self.block()?;
+                self.expect(b"end")?;
+                StmtKind::While { cond, body }
+            }
+            b"repeat" => {
+                self.skip();
+                let body = self.block()?;
+                self.expect(b"until")?;
+                let cond = self.expr()?;
+                StmtKind::Repeat { body, cond }
+            }
+            b"if" => {
+                self.skip();
+                let mut branches = vec![];
+                let mut else_block = None;
+                loop {
+                    let cond = self.expr()?;
+                    self.expect(b"then")?;
+                    branches.push((cond, self.block()?));
+                    if self.accept(b"elseif") {
+                        continue;
+                    }
+                    if self.accept(b"else") {
+                        else_block = Some(self.block()?);
+                    }
+                    self.expect(b"end")?;
+                    break;
+                }
+                StmtKind::If {
+                    branches,
+                    else_block,
+                }
+            }
+            b"for" => {
+                self.skip();
+                let var = self.name()?;
+                if self.accept(b"=") {
+                    let start = self.expr()?;
+                    self.expect(b",")?;
+                    let end = self.expr()?;
+                    let step = if self.accept(b",") {
+                        Some(self.expr()?)
+                    } else {
+                        None
+                    };
+                    self.expect(b"do")?;
+                    let body = self.block()?;
+                    self.expect(b"end")?;
+                    StmtKind::NumericFor {
+                        var,
+                        start,
+                        end,
+                        step,
+                        body,
+                    }
+                } else {
+                    let mut names = vec![var];
+                    while self.accept(b",") {
+                        names.push(self.name()?);
+                    }
+                    self.expect(b"in")?;
+                    let exprs = self.expr_list()?;
+                    self.expect(b"do")?;
+                    let body = self.block()?;
+                    self.expect(b"end")?;
+                    StmtKind::GenericFor { names, exprs, body }
+                }
+            }
+            b"function" => {
+                self.skip();
+                let mut path = vec![self.name()?];
+                while self.accept(b".") {
+                    path.push(self.name()?);
+                }
+                let method = if self.accept(b":") {
+                    Some(self.name()?)
+                } else {
+                    None
+                };
+                let func = self.function_body(offset)?;
+                StmtKind::Function {
+                    name: FunctionName { path, method },
+                    func,
+                }
+            }
+            b"local" => {
+                self.skip();
+                if self.check(b"function") {
+                    // emitted at the offset of the function body
+                    let func_offset = self.offset();
+                    self.pos += 1;
+                    let name = self.name()?;
+                    let func = self.function_body(func_offset)?;
+                    StmtKind::LocalFunction { name, func }
+                } else {
+                    let mut names = vec![self.name()?];
+                    while self.accept(b",") {
+                        names.push(self.name()?);
+                    }
+                    let values = if self.accept(b"=") {
+                        self.expr_list()?
+                    } else {
+                        vec![]
+                    };
+                    StmtKind::Local { names, values }
+                }
+            }
+            _ => {
+                let expr = self.suffixed_expr()?;
+                if self.check(b"=") || self.check(b",") {
+                    let mut targets = vec![expr];
+                    while self.accept(b",") {
+                        targets.push(self.suffixed_expr()?);
+                    }
+                    for target in &targets {
+                        if !matches!(
+                            *target,
+                            Expr::Name(_) | Expr::Index { .. } | Expr::Field { .. }
+                        ) {
+                            return Err(self.error("cannot assign to this expression"));
+                        }
+                    }
+                    self.expect(b"=")?;
+                    let values = self.expr_list()?;
+                    StmtKind::Assign { targets, values }
+                } else if expr.is_call() {
+                    StmtKind::Call(expr)
+                } else {
+                    return Err(self.error("syntax error"));
+                }
+            }
+        };
+        Ok(Stmt {
+            offset,
+            marks: self.marks.pop().unwrap(),
+            kind,
+        })
+    }
+
+    /// Parses parameter list and body of a function, the `function` keyword
+    /// (and name) having already been consumed.
+    fn function_body(&mut self, offset: usize) -> Result<FunctionBody> {
+        self.expect(b"(")?;
+        let mut params = vec![];
+        let mut is_vararg = false;
+        if !self.check(b")") {
+            loop {
+                if self.accept(b"...") {
+                    is_vararg = true;
+                    break;
+                }
+                params.push(self.name()?);
+                if !self.accept(b",") {
+                    break;
+                }
+            }
+        }
+        self.expect(b")")?;
+        let outer_vararg = std::mem::replace(&mut self.is_vararg, is_vararg);
+        let body = self.block()?;
+        self.is_vararg = outer_vararg;
+        self.expect(b"end")?;
+        Ok(FunctionBody {
+            offset,
+            params,
+            is_vararg,
+            body,
+        })
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.accept(b",") {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        self.sub_expr(0)
+    }
+
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr> {
+        let mut expr = match self.peek_text() {
+            b"not" | b"-" | b"#" | b"~" => {
+                let op = self.token()?;
+                let expr = self.sub_expr(UNARY_PRIORITY)?;
+                Expr::Unary {
+                    op,
+                    expr: Box::new(expr),
+                }
+            }
+            _ => self.simple_expr()?,
+        };
+        while let Some((left, right)) = binary_priority(self.peek_text()) {
+            if left <= limit {
+                break;
+            }
+            let op = self.token()?;
+            let rhs = self.sub_expr(right)?;
+            expr = Expr::Binary {
+                op,
+                lhs: Box::new(expr),
+                rhs: Box::new(rhs),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr> {
+        let token = match self.peek() {
+            Some(token) => token,
+            None => return Err(self.error("unexpected end of code")),
+        };
+        if is_string(token) {
+            return Ok(Expr::String(self.string()?));
+        }
+        Ok(match token.type_ {
+            TokenType::Number | TokenType::HexNumber => Expr::Number(self.token()?),
+            _ => match self.peek_text() {
+                b"nil" => Expr::Nil(self.token()?.offset),
+                b"true" => Expr::True(self.token()?.offset),
+                b"false" => Expr::False(self.token()?.offset),
+                b"..." if !self.is_vararg => {
+                    return Err(self.error("cannot use '...' outside a vararg function"))
+                }
+                b"..." => Expr::Vararg(self.token()?.offset),
+                b"{" => Expr::Table(self.table()?),
+                b"function" => {
+                    let offset = self.token()?.offset;
+                    Expr::Function(self.function_body(offset)?)
+                }
+                _ => self.suffixed_expr()?,
+            },
+        })
+    }
+
+    fn suffixed_expr(&mut self) -> Result<Expr> {
+        let mut expr = if self.check(b"(") {
+            self.skip();
+            let expr = self.expr()?;
+            self.expect(b")")?;
+            Expr::Paren(Box::new(expr))
+        } else if self.check_name() {
+            Expr::Name(self.token()?)
+        } else {
+            return Err(self.error("unexpected symbol"));
+        };
+        loop {
+            expr = match self.peek_text() {
+                b"." => {
+                    self.skip();
+                    Expr::Field {
+                        obj: Box::new(expr),
+                        name: self.name()?,
+                    }
+                }
+                b"[" => {
+                    self.skip();
+                    let key = self.expr()?;
+                    self.expect(b"]")?;
+                    Expr::Index {
+                        obj: Box::new(expr),
+                        key: Box::new(key),
+                    }
+                }
+                b":" => {
+                    self.skip();
+                    let name = self.name()?;
+                    let args = self.args()?;
+                    Expr::Method {
+                        obj: Box::new(expr),
+                        name,
+                        args,
+                    }
+                }
+                b"(" | b"{" => Expr::Call {
+                    func: Box::new(expr),
+                    args: self.args()?,
+                },
+                _ if self.check_string() => Expr::Call {
+                    func: Box::new(expr),
+                    args: self.args()?,
+                },
+                _ => return Ok(expr),
+            };
+        }
+    }
+
+    fn args(&mut self) -> Result<Args> {
+        if self.check_string() {
+            return Ok(Args::String(self.string()?));
+        }
+        if self.check(b"{") {
+            return Ok(Args::Table(self.table()?));
+        }
+        self.expect(b"(")?;
+        let args = if self.check(b")") {
+            vec![]
+        } else {
+            self.expr_list()?
+        };
+        self.expect(b")")?;
+        Ok(Args::List(args))
+    }
+
+    fn string(&mut self) -> Result<StringLiteral> {
+        let token = self.next()?;
+        Ok(match token.code {
+            Some(ref code) => StringLiteral::Code {
+                offset: token.offset,
+                delim: token.text[0],
+                block: parse_chunk(code, self.source)?,
+            },
+            None => StringLiteral::Plain(Token {
+                text: token.text.clone(),
+                offset: token.offset,
+                symbol: None,
+            }),
+        })
+    }
+
+    fn table(&mut self) -> Result<TableConstructor> {
+        // the offset of `{` is kept in the table constructor
+        let offset = self.next()?.offset;
+        let mut fields = vec![];
+        while !self.check(b"}") {
+            if self.accept(b"[") {
+                let key = self.expr()?;
+                self.expect(b"]")?;
+                self.expect(b"=")?;
+                let value = self.expr()?;
+                fields.push(Field::Keyed { key, value });
+            } else if self.check_name() && self.check_next(b"=") {
+                let name = self.name()?;
+                self.skip();
+                let value = self.expr()?;
+                fields.push(Field::Named { name, value });
+            } else {
+                fields.push(Field::Positional(self.expr()?));
+            }
+            if !self.check(b",") && !self.check(b";") {
+                break;
+            }
+            if self.check_next(b"}") {
+                // trailing separators are dropped
+                self.pos += 1;
+            } else {
+                self.skip();
+            }
+        }
+        self.expect(b"}")?;
+        Ok(TableConstructor { offset, fields })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::tokenize;
+    use super::*;
+
+    fn parse(code: &[u8]) -> Result<Block> {
+        parse_chunk(&tokenize(code), code)
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let block = parse(b"x=-a^b+c*d..e..f").unwrap();
+        let values = match block.stmts[0].kind {
+            StmtKind::Assign { ref values, .. } => values,
+            _ => panic!("expected assignment"),
+        };
+        // (-(a^b) + c*d) .. (e .. f)
+        match values[0] {
+            Expr::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+            } => {
+                assert_eq!(op.text, b"..");
+                assert!(matches!(**lhs, Expr::Binary { ref op, .. } if op.text == b"+"));
+                assert!(matches!(**rhs, Expr::Binary { ref op, .. } if op.text == b".."));
+            }
+            _ => panic!("expected binary expression"),
+        }
+    }
+
+    #[test]
+    fn statements() {
+        let block = parse(
+            b"local a,b=1,2 ::top:: while a<b do a=a+1 if a>5 then break elseif a then goto top else end end
+              repeat local c=a until c for i=1,10,2 do end for k,v in pairs(t) do end
+              function t.x.y:m(...) return ... end local function f() end obj:m{1;2,x=3,[4]=5}'s'",
+        )
+        .unwrap();
+        assert_eq!(block.stmts.len(), 9);
+        assert!(matches!(
+            block.stmts[8].kind,
+            StmtKind::Call(Expr::Call { .. })
+        ));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let err = parse(b"a=1\nb=(2").unwrap_err().to_string();
+        assert!(err.contains("line 2, column 5"), "{}", err);
+        assert!(parse(b"return 1 a=2").is_err());
+        assert!(parse(b"f()=1").is_err());
+        assert!(parse(b"x").is_err());
+
+        let err = parse(b"function f() return ... end")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("cannot use '...' outside a vararg function"),
+            "{}",
+            err
+        );
+        assert!(parse(b"function f(a) g(function() return ... end) end").is_err());
+        assert!(parse(b"return ...").is_ok());
+        assert!(parse(b"function f(a,...) g(function() end,...) end").is_ok());
+    }
+}
@@ -0,0 +1,457 @@
+//! Formatting a syntax tree as readable code: one statement per line, indented, with
+//! spaces around operators. Used to study packed code.
+
+use super::ast::*;
+use super::scope::Symbol;
+use std::collections::HashSet;
+
+const INDENT: &[u8] = b"  ";
+
+/// Formats `block`. Names assigned a `load` of a code string (as produced by
+/// `-- transform to load`) are turned back into functions, unless a global used in the
+/// code string would then be shadowed by a local of the same name.
+pub fn pretty_print(block: &Block, symbols: &[Symbol]) -> Vec<u8> {
+    let mut local_names = HashSet::new();
+    block.clone().for_each_name_mut(&mut |name, _| {
+        if let Some(symbol) = name.symbol {
+            if symbols[symbol].is_local() {
+                local_names.insert(name.text.clone());
+            }
+        }
+    });
+    let mut printer = Printer {
+        out: vec![],
+        indent: 0,
+        uses_vararg: false,
+        symbols,
+        local_names,
+    };
+    printer.chunk(block);
+    printer.out
+}
+
+struct Printer<'a> {
+    out: Vec<u8>,
+    indent: usize,
+    /// Whether `...` was used in the function printed last.
+    uses_vararg: bool,
+    symbols: &'a [Symbol],
+    /// The names of all locals, after renaming.
+    local_names: HashSet<Vec<u8>>,
+}
+
+/// Whether the statement starts with a `(`, which would continue the previous statement
+/// if not separated by a `;`.
+fn starts_with_paren(stmt: &Stmt) -> bool {
+    fn leftmost_paren(expr: &Expr) -> bool {
+        match *expr {
+            Expr::Paren(_) => true,
+            Expr::Call { ref func, .. } => leftmost_paren(func),
+            Expr::Index { ref obj, .. }
+            | Expr::Field { ref obj, .. }
+            | Expr::Method { ref obj, .. } => leftmost_paren(obj),
+            _ => false,
+        }
+    }
+    match stmt.kind {
+        StmtKind::Call(ref expr) => leftmost_paren(expr),
+        StmtKind::Assign { ref targets, .. } => leftmost_paren(&targets[0]),
+        _ => false,
+    }
+}
+
+impl<'a> Printer<'a> {
+    fn write(&mut self, text: &[u8]) {
+        self.out.extend_from_slice(text);
+    }
+
+    fn newline(&mut self) {
+        self.out.push(b'\n');
+        for _ in 0..self.indent {
+            self.out.extend_from_slice(INDENT);
+        }
+    }
+
+    /// The top level statements, separating multi-line statements by empty lines.
+    fn chunk(&mut self, block: &Block) {
+        let mut last_multiline = false;
+        for (i, stmt) in block.stmts.iter().enumerate() {
+            if let StmtKind::Empty = stmt.kind {
+                continue;
+            }
+            let start = self.out.len();
+            self.stmt(stmt);
+            let multiline = self.out[start..].contains(&b'\n');
+            if i > 0 && (multiline || last_multiline) {
+                self.out.insert(start, b'\n');
+            }
+            self.out.push(b'\n');
+            last_multiline = multiline;
+        }
+    }
+
+    /// An indented block, ending on a new line for the closing keyword.
+    fn block(&mut self, block: &Block) {
+        self.indent += 1;
+        for stmt in &block.stmts {
+            if let StmtKind::Empty = stmt.kind {
+                continue;
+            }
+            self.newline();
+            self.stmt(stmt);
+        }
+        self.indent -= 1;
+        self.newline();
+    }
+
+    fn list<T>(&mut self, items: &[T], mut f: impl FnMut(&mut Self, &T)) {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.write(b", ");
+            }
+            f(self, item);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        self.list(exprs, Self::expr);
+    }
+
+    fn names(&mut self, names: &[Token]) {
+        self.list(names, |printer, name| printer.write(&name.text));
+    }
+
+    /// The code of `value` if it is a `load` of a code string which can be turned back
+    /// into a function.
+    fn loaded_code<'b>(&self, value: &'b Expr) -> Option<&'b Block> {
+        let block = match *value {
+            Expr::Call {
+                ref func,
+                args: Args::String(StringLiteral::Code { ref block, .. }),
+            } => match **func {
+                Expr::Name(ref name) if name.text == b"load" => block,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let mut shadowed = false;
+        block.clone().for_each_name_mut(&mut |name, _| {
+            if let Some(symbol) = name.symbol {
+                shadowed |=
+                    !self.symbols[symbol].is_local() && self.local_names.contains(&name.text);
+            }
+        });
+        if shadowed {
+            None
+        } else {
+            Some(block)
+        }
+    }
+
+    /// Prints `header` as a function without parameters, `...` if the code uses it.
+    fn loaded_function(&mut self, header: &[&[u8]], body: &Block) {
+        for part in header {
+            self.write(part);
+        }
+        self.write(b"(");
+        let params = self.out.len();
+        self.write(b")");
+        let outer = std::mem::replace(&mut self.uses_vararg, false);
+        self.block(body);
+        self.write(b"end");
+        if self.uses_vararg {
+            self.out.splice(params..params, b"...".iter().copied());
+        }
+        self.uses_vararg = outer;
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        if starts_with_paren(stmt) {
+            self.write(b";");
+        }
+        match stmt.kind {
+            StmtKind::Empty => (),
+            StmtKind::Assign {
+                ref targets,
+                ref values,
+            } => {
+                if let ([Expr::Name(ref name)], [ref value]) = (&targets[..], &values[..]) {
+                    if let Some(body) = self.loaded_code(value) {
+                        self.loaded_function(&[b"function ", &name.text], body);
+                        return;
+                    }
+                }
+                self.exprs(targets);
+                self.write(b" = ");
+                self.exprs(values);
+            }
+            StmtKind::Call(ref expr) => self.expr(expr),
+            StmtKind::Label(ref name) => {
+                self.write(b"::");
+                self.write(&name.text);
+                self.write(b"::");
+            }
+            StmtKind::Break => self.write(b"break"),
+            StmtKind::Goto(ref name) => {
+                self.write(b"goto ");
+                self.write(&name.text);
+            }
+            StmtKind::Do(ref body) => {
+                self.write(b"do");
+                self.block(body);
+                self.write(b"end");
+            }
+            StmtKind::While { ref cond, ref body } => {
+                self.write(b"while ");
+                self.expr(cond);
+                self.write(b" do");
+                self.block(body);
+                self.write(b"end");
+            }
+            StmtKind::Repeat { ref body, ref cond } => {
+                self.write(b"repeat");
+                self.block(body);
+                self.write(b"until ");
+                self.expr(cond);
+            }
+            StmtKind::If {
+                ref branches,
+                ref else_block,
+            } => {
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    self.write(if i == 0 { b"if " } else { b"elseif " });
+                    self.expr(cond);
+                    self.write(b" then");
+                    self.block(body);
+                }
+                if let Some(ref body) = *else_block {
+                    self.write(b"else");
+                    self.block(body);
+                }
+                self.write(b"end");
+            }
+            StmtKind::NumericFor {
+                ref var,
+                ref start,
+                ref end,
+                ref step,
+                ref body,
+            } => {
+                self.write(b"for ");
+                self.write(&var.text);
+                self.write(b" = ");
+                self.expr(start);
+                self.write(b", ");
+                self.expr(end);
+                if let Some(ref step) = *step {
+                    self.write(b", ");
+                    self.expr(step);
+                }
+                self.write(b" do");
+                self.block(body);
+                self.write(b"end");
+            }
+            StmtKind::GenericFor {
+                ref names,
+                ref exprs,
+                ref body,
+            } => {
+                self.write(b"for ");
+                self.names(names);
+                self.write(b" in ");
+                self.exprs(exprs);
+                self.write(b" do");
+                self.block(body);
+                self.write(b"end");
+            }
+            StmtKind::Function { ref name, ref func } => {
+                self.write(b"function ");
+                for (i, part) in name.path.iter().enumerate() {
+                    if i > 0 {
+                        self.write(b".");
+                    }
+                    self.write(&part.text);
+                }
+                if let Some(ref method) = name.method {
+                    self.write(b":");
+                    self.write(&method.text);
+                }
+                self.function_body(func);
+            }
+            StmtKind::LocalFunction { ref name, ref func } => {
+                self.write(b"local function ");
+                self.write(&name.text);
+                self.function_body(func);
+            }
+            StmtKind::Local {
+                ref names,
+                ref values,
+            } => {
+                if let ([ref name], [ref value]) = (&names[..], &values[..]) {
+                    if let Some(body) = self.loaded_code(value) {
+                        self.loaded_function(&[b"local function ", &name.text], body);
+                        return;
+                    }
+                }
+                self.write(b"local ");
+                self.names(names);
+                if !values.is_empty() {
+                    self.write(b" = ");
+                    self.exprs(values);
+                }
+            }
+            StmtKind::Return(ref values) => {
+                self.write(b"return");
+                if !values.is_empty() {
+                    self.write(b" ");
+                    self.exprs(values);
+                }
+            }
+        }
+    }
+
+    fn function_body(&mut self, func: &FunctionBody) {
+        self.write(b"(");
+        self.names(&func.params);
+        if func.is_vararg {
+            if !func.params.is_empty() {
+                self.write(b", ");
+            }
+            self.write(b"...");
+        }
+        self.write(b")");
+        let outer = std::mem::replace(&mut self.uses_vararg, false);
+        self.block(&func.body);
+        self.write(b"end");
+        self.uses_vararg = outer;
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Nil(_) => self.write(b"nil"),
+            Expr::True(_) => self.write(b"true"),
+            Expr::False(_) => self.write(b"false"),
+            Expr::Vararg(_) => {
+                self.uses_vararg = true;
+                self.write(b"...");
+            }
+            Expr::Number(ref number) => self.write(&number.text),
+            Expr::String(ref string) => self.string(string),
+            Expr::Function(ref func) => {
+                self.write(b"function");
+                self.function_body(func);
+            }
+            Expr::Table(ref table) => self.table(table),
+            Expr::Binary {
+                ref op,
+                ref lhs,
+                ref rhs,
+            } => {
+                self.expr(lhs);
+                self.write(b" ");
+                self.write(&op.text);
+                self.write(b" ");
+                self.expr(rhs);
+            }
+            Expr::Unary { ref op, ref expr } => {
+                self.write(&op.text);
+                let space = match **expr {
+                    _ if op.text[0].is_ascii_alphabetic() => true,
+                    // `- -x`, not a comment
+                    Expr::Unary { op: ref inner, .. } => inner.text == op.text,
+                    _ => false,
+                };
+                if space {
+                    self.write(b" ");
+                }
+                self.expr(expr);
+            }
+            Expr::Paren(ref expr) => {
+                self.write(b"(");
+                self.expr(expr);
+                self.write(b")");
+            }
+            Expr::Name(ref name) => self.write(&name.text),
+            Expr::Index { ref obj, ref key } => {
+                self.expr(obj);
+                self.bracketed(key);
+            }
+            Expr::Field { ref obj, ref name } => {
+                self.expr(obj);
+                self.write(b".");
+                self.write(&name.text);
+            }
+            Expr::Call { ref func, ref args } => {
+                self.expr(func);
+                self.args(args);
+            }
+            Expr::Method {
+                ref obj,
+                ref name,
+                ref args,
+            } => {
+                self.expr(obj);
+                self.write(b":");
+                self.write(&name.text);
+                self.args(args);
+            }
+        }
+    }
+
+    /// `[expr]`, with a space to keep a long bracket string from merging with the `[`.
+    fn bracketed(&mut self, expr: &Expr) {
+        self.write(b"[");
+        let start = self.out.len();
+        self.expr(expr);
+        if self.out[start] == b'[' {
+            self.out.insert(start, b' ');
+        }
+        self.write(b"]");
+    }
+
+    fn args(&mut self, args: &Args) {
+        match *args {
+            Args::List(ref exprs) => {
+                self.write(b"(");
+                self.exprs(exprs);
+                self.write(b")");
+            }
+            Args::Table(ref table) => self.table(table),
+            Args::String(ref string) => self.string(string),
+        }
+    }
+
+    /// Plain strings are kept as written, code strings which aren't turned back into
+    /// functions are minified.
+    fn string(&mut self, string: &StringLiteral) {
+        match *string {
+            StringLiteral::Plain(ref token) => self.write(&token.text),
+            StringLiteral::Code { .. } => {
+                let mut tokens = super::emit::flatten_string(string);
+                let code = super::serialize(&mut tokens, &mut |_, _| b' ');
+                self.write(&code);
+            }
+        }
+    }
+
+    fn table(&mut self, table: &TableConstructor) {
+        self.write(b"{");
+        self.list(&table.fields, |printer, field| match *field {
+            Field::Named {
+                ref name,
+                ref value,
+            } => {
+                printer.write(&name.text);
+                printer.write(b" = ");
+                printer.expr(value);
+            }
+            Field::Keyed { ref key, ref value } => {
+                printer.bracketed(key);
+                printer.write(b" = ");
+                printer.expr(value);
+            }
+            Field::Positional(ref value) => printer.expr(value),
+        });
+        self.write(b"}");
+    }
+}
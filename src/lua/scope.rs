@@ -0,0 +1,546 @@
+//! Resolution of names to the variables (symbols) they refer to.
+//!
+//! Every local declaration (`local` names, parameters, loop variables) creates its own
+//! symbol. Globals and field names share one symbol per name, as a global `x` and a
+//! field `t.x` (or `{x=1}`) are all just keys into some table and are renamed together.
+//! Names which are also read from the standard library (`math.sin`, `s:sub(1,1)`) are
+//! fixed, as renaming the global `sin` in `sin=math.sin` would rename the library field.
+//!
+//! While resolving, all variable references are numbered in source order. A local is
+//! visible in a range of these positions (its region), which is what `conflicts` uses to
+//! decide whether two symbols can be given the same name.
+
+use super::api::{self, TicVersion};
+use super::ast::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    /// The name of the symbol in the source code.
+    pub name: Vec<u8>,
+    /// For locals, the name of the function they are declared in (empty at the top level).
+    pub scope: Option<Vec<u8>>,
+    /// Set for names that can never be renamed, like the implicit `self` parameter or
+    /// fields of the standard library.
+    pub fixed: bool,
+    /// For globals, whether the program assigns to the global or a field of this name.
+    pub assigned: bool,
+    /// Range of positions in which a local is visible, `None` for globals.
+    pub region: Option<(usize, usize)>,
+    /// Positions of all variable references to the symbol, including declarations.
+    pub uses: Vec<usize>,
+}
+
+impl Symbol {
+    /// The key used to target this symbol in a renaming: `name` for globals and fields,
+    /// `scope:name` for locals.
+    pub fn key(&self) -> Vec<u8> {
+        match self.scope {
+            Some(ref scope) => {
+                let mut key = scope.clone();
+                key.push(b':');
+                key.extend_from_slice(&self.name);
+                key
+            }
+            None => self.name.clone(),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.scope.is_some()
+    }
+}
+
+/// Returns whether `a` and `b` can not be given the same name without changing which
+/// variable some reference refers to.
+pub fn conflicts(a: &Symbol, b: &Symbol) -> bool {
+    fn any_in(uses: &[usize], region: (usize, usize)) -> bool {
+        uses.iter().any(|&p| p >= region.0 && p < region.1)
+    }
+    fn contains(outer: (usize, usize), inner: (usize, usize)) -> bool {
+        outer.0 <= inner.0 && inner.1 <= outer.1
+    }
+    match (a.region, b.region) {
+        (None, None) => true,
+        (Some(region), None) => any_in(&b.uses, region),
+        (None, Some(region)) => any_in(&a.uses, region),
+        (Some(ra), Some(rb)) => {
+            // the inner symbol would shadow all uses of the outer one inside its region
+            if contains(rb, ra) {
+                any_in(&b.uses, ra)
+            } else if contains(ra, rb) {
+                any_in(&a.uses, rb)
+            } else {
+                false
+            }
+        }
+    }
+}
+
+/// Resolves all names in `block`, storing the symbol index in each name token. `version`
+/// decides which fields of the standard library are fixed.
+pub fn resolve(block: &mut Block, version: TicVersion) -> Vec<Symbol> {
+    let mut resolver = Resolver {
+        version,
+        symbols: vec![],
+        globals: HashMap::new(),
+        scopes: vec![],
+        scope_name: vec![],
+        pos: 0,
+    };
+    resolver.block(block);
+    resolver.symbols
+}
+
+struct Resolver {
+    version: TicVersion,
+    symbols: Vec<Symbol>,
+    globals: HashMap<Vec<u8>, usize>,
+    /// The locals of all enclosing blocks, innermost last.
+    scopes: Vec<Vec<usize>>,
+    scope_name: Vec<u8>,
+    pos: usize,
+}
+
+impl Resolver {
+    fn global(&mut self, name: &[u8]) -> usize {
+        let symbols = &mut self.symbols;
+        *self.globals.entry(name.to_vec()).or_insert_with(|| {
+            symbols.push(Symbol {
+                name: name.to_vec(),
+                scope: None,
+                fixed: false,
+                assigned: false,
+                region: None,
+                uses: vec![],
+            });
+            symbols.len() - 1
+        })
+    }
+
+    fn lookup(&mut self, name: &[u8]) -> usize {
+        for scope in self.scopes.iter().rev() {
+            for &id in scope.iter().rev() {
+                if self.symbols[id].name == name {
+                    return id;
+                }
+            }
+        }
+        self.global(name)
+    }
+
+    fn variable(&mut self, name: &mut Token, assigned: bool) {
+        let id = self.lookup(&name.text);
+        let symbol = &mut self.symbols[id];
+        symbol.uses.push(self.pos);
+        if assigned && !symbol.is_local() {
+            symbol.assigned = true;
+        }
+        self.pos += 1;
+        name.symbol = Some(id);
+    }
+
+    fn field(&mut self, name: &mut Token, assigned: bool) {
+        let id = self.global(&name.text);
+        if assigned {
+            self.symbols[id].assigned = true;
+        }
+        name.symbol = Some(id);
+    }
+
+    /// A field `obj.name`, which is fixed if `obj` is a standard library table.
+    fn field_of(&mut self, obj: &Expr, name: &mut Token, assigned: bool) {
+        self.field(name, assigned);
+        if let Expr::Name(ref table) = *obj {
+            let is_global = table.symbol.is_some_and(|id| !self.symbols[id].is_local());
+            if is_global && api::is_library(&table.text, self.version) {
+                self.symbols[name.symbol.unwrap()].fixed = true;
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &mut Token) {
+        name.symbol = Some(self.declare_name(&name.text, false));
+    }
+
+    fn declare_name(&mut self, name: &[u8], fixed: bool) -> usize {
+        self.symbols.push(Symbol {
+            name: name.to_vec(),
+            scope: Some(self.scope_name.clone()),
+            fixed,
+            assigned: false,
+            region: Some((self.pos, usize::MAX)),
+            uses: vec![self.pos],
+        });
+        self.pos += 1;
+        let id = self.symbols.len() - 1;
+        self.scopes.last_mut().unwrap().push(id);
+        id
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(vec![]);
+        self.pos += 1;
+    }
+
+    fn pop_scope(&mut self) {
+        for id in self.scopes.pop().unwrap() {
+            if let Some((_, ref mut end)) = self.symbols[id].region {
+                *end = self.pos;
+            }
+        }
+        self.pos += 1;
+    }
+
+    fn block(&mut self, block: &mut Block) {
+        self.push_scope();
+        self.stmts(block);
+        self.pop_scope();
+    }
+
+    fn stmts(&mut self, block: &mut Block) {
+        for stmt in &mut block.stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match stmt.kind {
+            StmtKind::Empty | StmtKind::Break | StmtKind::Label(_) | StmtKind::Goto(_) => (),
+            StmtKind::Assign {
+                ref mut targets,
+                ref mut values,
+            } => {
+                for (i, value) in values.iter_mut().enumerate() {
+                    let name = match targets.get(i) {
+                        Some(Expr::Name(name)) => Some(name.text.clone()),
+                        _ => None,
+                    };
+                    self.value(value, name);
+                }
+                for target in targets.iter_mut() {
+                    match *target {
+                        Expr::Name(ref mut name) => self.variable(name, true),
+                        Expr::Field {
+                            ref mut obj,
+                            ref mut name,
+                        } => {
+                            self.expr(obj);
+                            self.field_of(obj, name, true);
+                        }
+                        _ => self.expr(target),
+                    }
+                }
+            }
+            StmtKind::Call(ref mut expr) => self.expr(expr),
+            StmtKind::Do(ref mut body) => self.block(body),
+            StmtKind::While {
+                ref mut cond,
+                ref mut body,
+            } => {
+                self.expr(cond);
+                self.block(body);
+            }
+            StmtKind::Repeat {
+                ref mut body,
+                ref mut cond,
+            } => {
+                // the condition can see the locals of the loop body
+                self.push_scope();
+                self.stmts(body);
+                self.expr(cond);
+                self.pop_scope();
+            }
+            StmtKind::If {
+                ref mut branches,
+                ref mut else_block,
+            } => {
+                for &mut (ref mut cond, ref mut body) in branches {
+                    self.expr(cond);
+                    self.block(body);
+                }
+                if let Some(ref mut body) = *else_block {
+                    self.block(body);
+                }
+            }
+            StmtKind::NumericFor {
+                ref mut var,
+                ref mut start,
+                ref mut end,
+                ref mut step,
+                ref mut body,
+            } => {
+                self.expr(start);
+                self.expr(end);
+                if let Some(ref mut step) = *step {
+                    self.expr(step);
+                }
+                self.push_scope();
+                self.declare(var);
+                self.block(body);
+                self.pop_scope();
+            }
+            StmtKind::GenericFor {
+                ref mut names,
+                ref mut exprs,
+                ref mut body,
+            } => {
+                exprs.iter_mut().for_each(|e| self.expr(e));
+                self.push_scope();
+                names.iter_mut().for_each(|n| self.declare(n));
+                self.block(body);
+                self.pop_scope();
+            }
+            StmtKind::Function {
+                ref mut name,
+                ref mut func,
+            } => {
+                let is_plain = name.path.len() == 1 && name.method.is_none();
+                let mut scope_name = name.path[0].text.clone();
+                self.variable(&mut name.path[0], is_plain);
+                for part in &mut name.path[1..] {
+                    scope_name.push(b'.');
+                    scope_name.extend_from_slice(&part.text);
+                    self.field(part, false);
+                }
+                if let Some(ref mut method) = name.method {
+                    scope_name.push(b':');
+                    scope_name.extend_from_slice(&method.text);
+                    self.field(method, false);
+                }
+                self.function(func, name.method.is_some(), Some(scope_name));
+            }
+            StmtKind::LocalFunction {
+                ref mut name,
+                ref mut func,
+            } => {
+                self.declare(name);
+                let scope_name = name.text.clone();
+                self.function(func, false, Some(scope_name));
+            }
+            StmtKind::Local {
+                ref mut names,
+                ref mut values,
+            } => {
+                for (i, value) in values.iter_mut().enumerate() {
+                    let name = names.get(i).map(|n| n.text.clone());
+                    self.value(value, name);
+                }
+                names.iter_mut().for_each(|n| self.declare(n));
+            }
+            StmtKind::Return(ref mut values) => values.iter_mut().for_each(|e| self.expr(e)),
+        }
+    }
+
+    /// Resolves an expression assigned to `name`, which names the scope of locals in
+    /// the function (or loaded code) it defines.
+    fn value(&mut self, expr: &mut Expr, name: Option<Vec<u8>>) {
+        match *expr {
+            Expr::Function(ref mut func) => self.function(func, false, name),
+            Expr::Call {
+                ref mut func,
+                args: Args::String(StringLiteral::Code { ref mut block, .. }),
+            } if matches!(**func, Expr::Name(ref n) if n.text == b"load") => {
+                self.expr(func);
+                self.code_string(block, name);
+            }
+            _ => self.expr(expr),
+        }
+    }
+
+    fn function(&mut self, func: &mut FunctionBody, is_method: bool, name: Option<Vec<u8>>) {
+        let outer_name = match name {
+            Some(name) => std::mem::replace(&mut self.scope_name, name),
+            None => self.scope_name.clone(),
+        };
+        self.push_scope();
+        if is_method {
+            self.declare_name(b"self", true);
+        }
+        func.params.iter_mut().for_each(|n| self.declare(n));
+        self.block(&mut func.body);
+        self.pop_scope();
+        self.scope_name = outer_name;
+    }
+
+    /// Code strings are compiled as separate chunks, so they can't see any outer locals.
+    fn code_string(&mut self, block: &mut Block, name: Option<Vec<u8>>) {
+        let outer_scopes = std::mem::take(&mut self.scopes);
+        let outer_name = match name {
+            Some(name) => std::mem::replace(&mut self.scope_name, name),
+            None => self.scope_name.clone(),
+        };
+        self.block(block);
+        self.scope_name = outer_name;
+        self.scopes = outer_scopes;
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match *expr {
+            Expr::Nil(_) | Expr::True(_) | Expr::False(_) | Expr::Vararg(_) | Expr::Number(_) => (),
+            Expr::String(ref mut string) => self.string(string),
+            Expr::Function(ref mut func) => self.function(func, false, None),
+            Expr::Table(ref mut table) => self.table(table),
+            Expr::Binary {
+                ref mut lhs,
+                ref mut rhs,
+                ..
+            } => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expr::Unary { ref mut expr, .. } | Expr::Paren(ref mut expr) => self.expr(expr),
+            Expr::Name(ref mut name) => self.variable(name, false),
+            Expr::Index {
+                ref mut obj,
+                ref mut key,
+            } => {
+                self.expr(obj);
+                self.expr(key);
+            }
+            Expr::Field {
+                ref mut obj,
+                ref mut name,
+            } => {
+                self.expr(obj);
+                self.field_of(obj, name, false);
+            }
+            Expr::Call {
+                ref mut func,
+                ref mut args,
+            } => {
+                self.expr(func);
+                self.args(args);
+            }
+            Expr::Method {
+                ref mut obj,
+                ref mut name,
+                ref mut args,
+            } => {
+                self.expr(obj);
+                self.field(name, false);
+                // methods of strings are the fields of `string`
+                if api::is_library_field(b"string", &name.text, self.version) {
+                    self.symbols[name.symbol.unwrap()].fixed = true;
+                }
+                self.args(args);
+            }
+        }
+    }
+
+    fn args(&mut self, args: &mut Args) {
+        match *args {
+            Args::List(ref mut exprs) => exprs.iter_mut().for_each(|e| self.expr(e)),
+            Args::Table(ref mut table) => self.table(table),
+            Args::String(ref mut string) => self.string(string),
+        }
+    }
+
+    fn string(&mut self, string: &mut StringLiteral) {
+        if let StringLiteral::Code { ref mut block, .. } = *string {
+            self.code_string(block, None);
+        }
+    }
+
+    fn table(&mut self, table: &mut TableConstructor) {
+        for field in &mut table.fields {
+            match *field {
+                Field::Named {
+                    ref mut name,
+                    ref mut value,
+                } => {
+                    self.field(name, true);
+                    self.expr(value);
+                }
+                Field::Keyed {
+                    ref mut key,
+                    ref mut value,
+                } => {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                Field::Positional(ref mut value) => self.expr(value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{parser, tokenize};
+    use super::*;
+
+    fn symbols(code: &[u8]) -> Vec<Symbol> {
+        let mut block = parser::parse_chunk(&tokenize(code), code).unwrap();
+        resolve(&mut block, TicVersion::LATEST)
+    }
+
+    fn find<'a>(symbols: &'a [Symbol], key: &[u8]) -> &'a Symbol {
+        symbols.iter().find(|s| s.key() == key).unwrap()
+    }
+
+    #[test]
+    fn scope_keys() {
+        let symbols = symbols(
+            b"local a function TIC() local b for i=1,2 do end end
+              function t:m(c) return self end f=function(d) end g=load'local e'",
+        );
+        for &key in &[
+            &b":a"[..],
+            b"TIC:b",
+            b"TIC:i",
+            b"t:m:c",
+            b"t:m:self",
+            b"f:d",
+            b"g:e",
+            b"TIC",
+            b"t",
+            b"m",
+        ] {
+            find(&symbols, key);
+        }
+        assert!(find(&symbols, b"t:m:self").fixed);
+        assert!(find(&symbols, b"TIC").assigned);
+        assert!(!find(&symbols, b"m").assigned);
+    }
+
+    #[test]
+    fn code_strings_dont_see_outer_locals() {
+        let symbols = symbols(b"local x f=load'return x'");
+        assert_eq!(find(&symbols, b"x").uses.len(), 1);
+        assert_eq!(find(&symbols, b":x").uses.len(), 1);
+    }
+
+    #[test]
+    fn shadowing_conflicts() {
+        let symbols = symbols(
+            b"local a=1 do local b=a end do local c=2 print(a) end
+              function f(x) local y=1 return x+y end function g(z) return z end",
+        );
+        let a = find(&symbols, b":a");
+        let b = find(&symbols, b":b");
+        let c = find(&symbols, b":c");
+        let print = find(&symbols, b"print");
+        // `local a=1 do local a=a end` is fine, `do local a=2 print(a) end` is not
+        assert!(!conflicts(a, b));
+        assert!(conflicts(a, c));
+        assert!(!conflicts(b, c));
+        assert!(conflicts(c, print));
+        assert!(!conflicts(b, print));
+        assert!(conflicts(find(&symbols, b"f:x"), find(&symbols, b"f:y")));
+        assert!(!conflicts(find(&symbols, b"f:x"), find(&symbols, b"g:z")));
+        assert!(conflicts(print, find(&symbols, b"f")));
+    }
+
+    #[test]
+    fn library_fields() {
+        let symbols = symbols(
+            b"sin=math.sin sub=5 s=('x'):sub(1) local math={} cos=math.cos t.abs=1 upd=1 o:upd()",
+        );
+        assert!(find(&symbols, b"sin").fixed);
+        assert!(find(&symbols, b"sub").fixed);
+        assert!(!find(&symbols, b"cos").fixed);
+        assert!(!find(&symbols, b"abs").fixed);
+        assert!(!find(&symbols, b"upd").fixed);
+    }
+}
@@ -1,18 +1,19 @@
-mod cp437;
-mod deflate;
-mod lua;
-mod tic_file;
-
 use anyhow::{anyhow, bail, Result};
 use clap::Clap;
-use flate2::write::ZlibEncoder;
-use std::{
-    cmp,
-    collections::{BTreeMap, HashSet},
-    path::PathBuf,
-    process::exit,
+use std::path::Path;
+use std::{fs::File, io::prelude::*, sync::mpsc, time::Duration};
+use std::{path::PathBuf, process::exit};
+use tic_tool::compress::{
+    compress_code, deflate_stream, find_code, unpack_code, Compressed, CostModel,
+};
+use tic_tool::number_choice::{self, choose_numbers};
+use tic_tool::rename::{
+    compute_rename_suggestions, merge_renames, search_renames, SearchOptions, Strategy,
 };
-use std::{collections::HashMap, fs::File, io::prelude::*, sync::mpsc, time::Duration};
+use tic_tool::report::{self, AnalyzeReport, ChunkAnalysis, CodeReport, CostReport, PackReport};
+use tic_tool::separators::{self, choose_separators};
+use tic_tool::source_map::SourceMap;
+use tic_tool::{cp437, deflate, encoder, lua, tic_file, vm};
 
 #[derive(Clap)]
 #[clap(version = "0.2.0", author = "Dennis Ranke <dennis.ranke@gmail.com>")]
@@ -31,6 +32,20 @@ enum SubCommand {
     Empty(CmdEmpty),
     #[clap(about = "Print out detailed information about a .tic file, incl. deflate disassembly")]
     Analyze(CmdAnalyze),
+    #[clap(about = "Shrink the huffman tables of a compressed code chunk without changing the code")]
+    Optimize(CmdOptimize),
+    #[clap(about = "Run original and packed code side by side and compare what they do")]
+    Check(CmdCheck),
+    #[clap(about = "Check a .tic file for truncated, malformed or duplicate chunks")]
+    Validate(CmdValidate),
+    #[clap(about = "Convert a .tic file to a text cart (source code with data sections)")]
+    ExportText(CmdExportText),
+    #[clap(about = "Render tiles, sprites, map and cover of a cart to .png files")]
+    ExportGfx(CmdExportGfx),
+    #[clap(about = "Convert a .png image into the tiles, sprites or screen chunk of a cart")]
+    ImportGfx(CmdImportGfx),
+    #[clap(about = "Translate a position in packed code back to the source using a source map")]
+    Resolve(CmdResolve),
 }
 
 fn main() -> Result<()> {
@@ -41,6 +56,13 @@ fn main() -> Result<()> {
         SubCommand::Extract(cmd) => cmd.exec()?,
         SubCommand::Empty(cmd) => cmd.exec()?,
         SubCommand::Analyze(cmd) => cmd.exec()?,
+        SubCommand::Optimize(cmd) => cmd.exec()?,
+        SubCommand::Check(cmd) => cmd.exec()?,
+        SubCommand::Validate(cmd) => cmd.exec()?,
+        SubCommand::ExportText(cmd) => cmd.exec()?,
+        SubCommand::ExportGfx(cmd) => cmd.exec()?,
+        SubCommand::ImportGfx(cmd) => cmd.exec()?,
+        SubCommand::Resolve(cmd) => cmd.exec()?,
     }
 
     Ok(())
@@ -58,6 +80,50 @@ struct CmdPack {
     auto_rename: bool,
     #[clap(short = 'l', long, about = "Rename iteration limit (-1 = no limit)")]
     rename_limit: Option<i32>,
+    #[clap(
+        long,
+        default_value = "greedy",
+        possible_values = Strategy::NAMES,
+        about = "Rename search strategy for --auto-rename"
+    )]
+    strategy: Strategy,
+    #[clap(long, about = "Time limit of the rename search in seconds")]
+    time: Option<f64>,
+    #[clap(long, default_value = "1", about = "Seed of the random rename search")]
+    seed: u64,
+    #[clap(
+        long,
+        default_value = "4",
+        about = "Number of renamings kept by the beam search"
+    )]
+    beam_width: usize,
+    #[clap(
+        short,
+        long,
+        about = "Number of threads compressing candidate code (default: all cores)"
+    )]
+    jobs: Option<usize>,
+    #[clap(
+        long,
+        default_value = "exact",
+        possible_values = CostModel::NAMES,
+        about = "How renames are rated: exact (final compressors), fast (zlib) or two-stage"
+    )]
+    cost_model: CostModel,
+    #[clap(
+        long,
+        default_value = "best",
+        possible_values = separators::Mode::NAMES,
+        about = "Whitespace between tokens: space, best (of space/newline/tab) or per-site"
+    )]
+    separators: separators::Mode,
+    #[clap(
+        long,
+        default_value = "keep",
+        possible_values = number_choice::Mode::NAMES,
+        about = "Number forms: keep, exact (same type) or float (integers may become floats)"
+    )]
+    numbers: number_choice::Mode,
     #[clap(short, long, about = "Strip chunks except for code and new palette")]
     strip: bool,
     #[clap(short, long, about = "Force new palette")]
@@ -73,8 +139,43 @@ struct CmdPack {
     iterations: u32,
     #[clap(long, about = "Print heatmap even if code > 1kb")]
     force_heatmap: bool,
-    #[clap(about = "Either a .tic file or source code")]
+    #[clap(
+        long,
+        about = "Check that the transformed code tokenizes back to the same tokens"
+    )]
+    verify: bool,
+    #[clap(
+        long,
+        about = "TIC-80 version whose API names are protected from renaming (default: latest)"
+    )]
+    tic_version: Option<lua::TicVersion>,
+    #[clap(
+        long,
+        about = "Cover image when writing a .png cart (default: the screen chunk)"
+    )]
+    cover: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value = "text",
+        possible_values = &["text", "json"],
+        about = "Print the report as text or as JSON"
+    )]
+    format: String,
+    #[clap(long, about = "Write an HTML heatmap of the compressed code")]
+    html: Option<PathBuf>,
+    #[clap(
+        long,
+        about = "Print the cost per identifier, token type, source line and function"
+    )]
+    costs: bool,
+    #[clap(
+        long,
+        about = "Write a map from the packed code back to the source code"
+    )]
+    source_map: Option<PathBuf>,
+    #[clap(about = "Either a .tic/.png cart or source code")]
     input: PathBuf,
+    #[clap(about = "A .tic file or .png cart")]
     output: PathBuf,
 }
 
@@ -84,6 +185,10 @@ impl CmdPack {
             eprintln!("Both --no-transform and --auto-rename specified. Auto renaming needs transforms to be active.");
             exit(1);
         }
+        if self.no_transform && self.source_map.is_some() {
+            eprintln!("Both --no-transform and --source-map specified. The code is only changed by transforms, so there is nothing to map.");
+            exit(1);
+        }
 
         self.run()?;
         if self.watch {
@@ -94,7 +199,9 @@ impl CmdPack {
             watcher.watch(&self.input, RecursiveMode::NonRecursive)?;
             loop {
                 if let DebouncedEvent::Write(_) = rx.recv()? {
-                    println!("\n---===###[...]###===---\n");
+                    if self.format == "text" {
+                        println!("\n---===###[...]###===---\n");
+                    }
                     self.run()?;
                 }
             }
@@ -104,97 +211,157 @@ impl CmdPack {
     }
 
     fn run(&self) -> Result<()> {
+        let json = self.format == "json";
         let mut out_chunks = vec![];
 
         let mut new_palette_default: Option<tic_file::Chunk> = None;
         let mut code: Option<Vec<u8>> = None;
 
-        if self.input.extension().map_or(false, |ext| ext == "tic") {
-            let chunks = tic_file::load(&self.input)?;
-            for chunk in chunks {
-                match chunk.type_ {
-                    0x11 => new_palette_default = Some(chunk),
-                    0x05 => code = Some(chunk.data),
-                    0x10 => {
-                        let mut unpacked = vec![];
-                        libflate::deflate::Decoder::new(&chunk.data[2..])
-                            .read_to_end(&mut unpacked)?;
-                        code = Some(unpacked);
-                    }
-                    _ if self.strip => (),
-                    _ => out_chunks.push(chunk),
-                }
-            }
+        let chunks = if tic_file::is_cart_file(&self.input) {
+            tic_file::load(&self.input)?
         } else {
             let mut buffer = vec![];
             File::open(&self.input)?.read_to_end(&mut buffer)?;
-            code = Some(buffer);
+            tic_file::parse_text(&buffer)?
+        };
+        for chunk in chunks {
+            match chunk.type_ {
+                0x11 => new_palette_default = Some(chunk),
+                0x05 => code = Some(chunk.data),
+                0x10 => code = Some(unpack_code(&chunk.data)?),
+                _ if self.strip => (),
+                _ => out_chunks.push(chunk),
+            }
         }
 
         let mut code = code.ok_or_else(|| anyhow!("No code chunk found"))?;
+        let mut renames = lua::Renaming::new();
+        let mut number_forms = lua::NumberForms::new();
+        let mut separator = None;
+        // the program serialized to the final code, and its source
+        let mut transformed: Option<(lua::Program, Vec<u8>)> = None;
+        let jobs = self
+            .jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |jobs| jobs.get()));
         if !self.no_transform {
-            let mut program = lua::Program::parse(&code);
-            code = program.serialize(b' ');
+            let mut program =
+                lua::Program::parse(&code, self.tic_version.unwrap_or(lua::TicVersion::LATEST))?;
+            let source = std::mem::replace(&mut code, program.serialize(b' '));
+            if self.verify {
+                program.verify(&code)?;
+            }
             let source_renames = program.renames.clone();
+            for warning in program.check_renames(&source_renames) {
+                eprintln!("Warning: {}", warning);
+            }
 
-            fn print_renames(renames: lua::Renaming) {
-                let mut renames: Vec<(Vec<u8>, Vec<u8>)> = renames.into_iter().collect();
-                renames.sort();
+            fn print_renames(renames: &lua::Renaming) {
                 for (src, dst) in renames {
                     println!(
                         "-- rename {}->{}",
-                        std::str::from_utf8(&src).unwrap(),
-                        std::str::from_utf8(&dst).unwrap()
+                        std::str::from_utf8(src).unwrap(),
+                        std::str::from_utf8(dst).unwrap()
                     );
                 }
                 println!();
             }
 
-            let mut analysis = deflate::analyze(&zopfli(&code));
-
             if self.auto_rename {
-                let mut rename: lua::Renaming = source_renames;
-                let mut best_rename = rename.clone();
-                let mut best_size = analysis.total_size();
-                let mut best_code = code;
-                let mut seen_renames: HashSet<lua::Renaming> = HashSet::new();
-                seen_renames.insert(rename.clone());
-
-                let mut renames_left = self.rename_limit.unwrap_or(15);
-                loop {
-                    let new_rename = compute_rename_suggestions(&program, &analysis);
-                    rename = merge_renames(&rename, &new_rename);
-                    if !seen_renames.insert(rename.clone()) {
-                        break;
-                    }
-                    program.apply_renames(&new_rename);
-                    let new_code = program.serialize(b' ');
-                    analysis = deflate::analyze(&zopfli(&new_code));
-                    let size = analysis.total_size();
-                    if size < best_size {
-                        best_rename = rename.clone();
-                        best_size = size;
-                        best_code = new_code;
-                    }
-
-                    renames_left -= 1;
-                    if renames_left == 0 {
-                        println!("Rename limit reached, using best found so far");
-                        break;
-                    }
+                let options = SearchOptions {
+                    strategy: self.strategy,
+                    max_evaluations: match self.rename_limit.unwrap_or(15) {
+                        limit if limit < 0 => None,
+                        limit => Some(limit as usize),
+                    },
+                    time_limit: self.time.map(Duration::from_secs_f64),
+                    seed: self.seed,
+                    beam_width: self.beam_width,
+                    jobs,
+                    verify: self.verify,
+                    cost_model: self.cost_model,
+                    iterations: self.iterations,
+                };
+                let result =
+                    search_renames(&mut program, &source_renames, &options, &mut |progress| {
+                        if !json {
+                            println!(
+                                "Rename search: {:.2} bytes after {} evaluations ({:.1}s)",
+                                progress.best_size as f32 / 8.,
+                                progress.evaluations,
+                                progress.elapsed.as_secs_f32()
+                            );
+                        }
+                    })?;
+                if result.budget_exhausted && !json {
+                    println!("Rename limit reached, using best found so far");
                 }
-
-                code = best_code;
-
-                println!("Best auto renames found:\n");
-                print_renames(best_rename);
+                renames = result.renames;
             } else {
-                println!("Suggested renames:\n");
-                print_renames(merge_renames(
+                let analysis =
+                    self.cost_model
+                        .search_model()
+                        .analyze(&code, self.iterations, jobs)?;
+                renames = merge_renames(
                     &source_renames,
                     &compute_rename_suggestions(&program, &analysis),
-                ));
+                );
+            }
+
+            for warning in program.check_renames(&renames) {
+                eprintln!("Warning: {}", warning);
+            }
+            if !json {
+                if self.auto_rename {
+                    println!("Best auto renames found:\n");
+                } else {
+                    println!("Suggested renames:\n");
+                }
+                print_renames(&renames);
+            }
+
+            if self.numbers != number_choice::Mode::Keep {
+                let choice = choose_numbers(
+                    &mut program,
+                    self.numbers,
+                    self.cost_model,
+                    self.iterations,
+                    jobs,
+                )?;
+                if self.verify {
+                    program.verify(&choice.code)?;
+                }
+                if !json && choice.forms.is_empty() {
+                    println!("Number forms: unchanged\n");
+                } else if !json {
+                    println!("Number forms:\n");
+                    for (src, dst) in &choice.forms {
+                        println!(
+                            "-- number {}->{}",
+                            std::str::from_utf8(src).unwrap(),
+                            std::str::from_utf8(dst).unwrap()
+                        );
+                    }
+                    println!();
+                }
+                number_forms = choice.forms;
+            }
+
+            let choice = choose_separators(
+                &mut program,
+                self.separators,
+                self.cost_model,
+                self.iterations,
+                jobs,
+            )?;
+            if self.verify {
+                program.verify(&choice.code)?;
             }
+            if !json {
+                println!("Separator: {}\n", choice.describe());
+            }
+            separator = Some(choice.describe());
+            code = choice.code;
+            transformed = Some((program, source));
         }
 
         if self.new_palette {
@@ -205,179 +372,79 @@ impl CmdPack {
             });
         }
 
-        out_chunks.push(compress_code(code, self.iterations as i32, self.force_heatmap));
-        out_chunks.extend(new_palette_default.into_iter());
-
-        tic_file::save(&self.output, &out_chunks)?;
-
-        Ok(())
-    }
-}
-
-fn zopfli(code: &[u8]) -> Vec<u8> {
-    let mut compressed = vec![];
-    zopfli_rs::compress(
-        &zopfli_rs::Options::default(),
-        &zopfli_rs::Format::Deflate,
-        code,
-        &mut compressed,
-    )
-    .unwrap();
-    compressed
-}
-
-fn compute_rename_suggestions(
-    program: &lua::Program,
-    analysis: &deflate::Analysis,
-) -> lua::Renaming {
-    let candidates = program.get_rename_candidates();
-    let analysis = analysis.data();
-
-    let mut renameable_count: HashMap<Vec<u8>, (f32, usize)> = HashMap::new();
-    for (id, offsets) in &candidates.renameable {
-        let count = renameable_count
-            .entry(id.clone())
-            .or_insert_with(|| (0., offsets[0]));
-        for &offset in offsets {
-            for o in offset..offset + id.len() {
-                if analysis.literal_index[o] == usize::MAX {
-                    count.0 += if analysis.block_type[o] == 2 { 1. } else { 0.1 };
-                }
-            }
+        let compressed = compress_code(code, self.iterations, jobs)?;
+        if !json {
+            print_compression(&compressed, self.force_heatmap)?;
         }
-    }
-
-    let mut renameable_ids: Vec<(Vec<u8>, f32, usize)> = renameable_count
-        .into_iter()
-        .map(|(id, (count, offset))| {
-            let count = count / id.len() as f32;
-            (id, count, offset)
-        })
-        .collect();
-    renameable_ids.sort_unstable_by(|a, b| {
-        b.1.partial_cmp(&a.1)
-            .unwrap_or(cmp::Ordering::Less)
-            .then(a.2.cmp(&b.2))
-    });
-    // print!("renameable ids:");
-    // for &(ref id, count, _) in &renameable_ids {
-    //     print!("  {}: {}", std::str::from_utf8(id).unwrap(), count.ceil());
-    // }
-    // println!();
-
-    let mut candidate_ids: HashMap<Vec<u8>, (f32, usize)> = HashMap::new();
-    for &offset in &candidates.candidate_chars {
-        if analysis.literal_index[offset] == usize::MAX {
-            let c = analysis.unpacked[offset];
-            if !lua::is_valid_ident_start(c) {
-                dbg!(std::str::from_utf8(&analysis.unpacked[offset - 10..offset]).unwrap());
-                dbg!(std::str::from_utf8(&analysis.unpacked[offset..offset + 10]).unwrap());
-            }
-            candidate_ids
-                .entry(vec![analysis.unpacked[offset]])
-                .or_insert_with(|| (0., offset))
-                .0 += if analysis.block_type[offset] == 2 {
-                1.
-            } else {
-                0.1
-            };
-        }
-    }
-    let id_count = renameable_ids.len();
-
-    let mut candidate_ids: Vec<(Vec<u8>, f32, usize)> = candidate_ids
-        .into_iter()
-        .map(|(c, (count, offset))| (c, count, offset))
-        .collect();
-    fn white_space_efficiency(c: u8) -> u8 {
-        match c | 32 {
-            b'a'..=b'f' => 0,
-            b'p' | b'x' => 1,
-            _ => 2,
+        if let Some(ref html) = self.html {
+            write_html(html, &self.input, &compressed.analysis)?;
         }
-    }
-    candidate_ids.sort_unstable_by(|a, b| {
-        b.1.partial_cmp(&a.1)
-            .unwrap_or(cmp::Ordering::Less)
-            .then(white_space_efficiency(b.0[0]).cmp(&white_space_efficiency(a.0[0])))
-            .then(a.2.cmp(&b.2))
-    });
-    // print!("candidate ids:");
-    // for &(ref id, count, _) in &candidate_ids {
-    //     print!("  {}: {}", std::str::from_utf8(id).unwrap(), count.ceil());
-    // }
-    // println!();
-
-    let mut candidate_ids: Vec<Vec<u8>> = candidate_ids.into_iter().map(|(id, ..)| id).collect();
-
-    if id_count > candidate_ids.len() {
-        let mut used_ids = candidates.fixed;
-        used_ids.extend(candidate_ids.iter().cloned());
-        for &c in b"_ghijklmnoqrstuvwyzpxabcdefGHIJKLMNOQRSTUVWYZPXABCDEF" {
-            let id = vec![c];
-            if used_ids.insert(id.clone()) {
-                candidate_ids.push(id);
-            }
+        if let (Some(path), Some((program, source))) = (&self.source_map, &transformed) {
+            let packed = &compressed.analysis.data().unpacked;
+            SourceMap::new(program, source, &self.input.to_string_lossy(), packed).save(path)?;
         }
-        let mut pos = 0usize;
-        while id_count > candidate_ids.len() {
-            let d = ((pos as f32 * 2. + 0.75).sqrt() - 0.5).floor() as usize;
-            let x = pos - d * (d + 1) / 2;
-            let y = d - x;
-            let mut id = candidate_ids[y].clone();
-            id.extend_from_slice(&candidate_ids[x]);
-            if used_ids.insert(id.clone()) {
-                candidate_ids.push(id);
+        let costs = transformed.map(|(program, source)| {
+            CostReport::new(&program, &source, &compressed.analysis.data().cost)
+        });
+        if self.costs && !json {
+            if let Some(ref costs) = costs {
+                print_costs(costs);
             }
-            pos += 1;
         }
-    }
-
-    renameable_ids
-        .into_iter()
-        .map(|(id, ..)| id)
-        .zip(candidate_ids.into_iter().take(id_count))
-        .collect()
-}
-
-fn merge_renames(a: &lua::Renaming, b: &lua::Renaming) -> lua::Renaming {
-    let reverse: BTreeMap<&Vec<u8>, &Vec<u8>> = a.iter().map(|(src, dst)| (dst, src)).collect();
-    let mut a = a.clone();
-    a.extend(b.iter().map(|(src, dst)| {
-        if let Some(&prev_src) = reverse.get(&src) {
-            (prev_src.clone(), dst.clone())
+        out_chunks.push(compressed.chunk.clone());
+        out_chunks.extend(new_palette_default);
+
+        let saved = tic_file::save_with_cover(&self.output, &out_chunks, self.cover.as_deref())?;
+        if json {
+            let report = PackReport::new(
+                &out_chunks,
+                &compressed,
+                &saved,
+                &renames,
+                &number_forms,
+                separator,
+                costs,
+            );
+            println!("{}", serde_json::to_string_pretty(&report)?);
         } else {
-            (src.clone(), dst.clone())
+            print_saved(&saved);
         }
-    }));
-    a
+
+        Ok(())
+    }
 }
 
 #[derive(Clap)]
 struct CmdExtract {
+    #[clap(
+        short,
+        long,
+        about = "Reformat the Lua code and turn `name=load\"...\"` back into functions"
+    )]
+    pretty: bool,
+    #[clap(
+        short,
+        long,
+        about = "With --pretty, undo the `-- rename a->b` lines of this file"
+    )]
+    renames: Option<PathBuf>,
     input: PathBuf,
     output: PathBuf,
 }
 
 impl CmdExtract {
     fn exec(self) -> Result<()> {
-        let chunks = tic_file::load(self.input)?;
-        fn find_code(chunks: Vec<tic_file::Chunk>) -> Result<Vec<u8>> {
-            for chunk in chunks {
-                match chunk.type_ {
-                    0x05 => return Ok(chunk.data),
-                    0x10 => {
-                        let mut unpacked = vec![];
-                        libflate::deflate::Decoder::new(&chunk.data[2..])
-                            .read_to_end(&mut unpacked)?;
-                        return Ok(unpacked);
-                    }
-                    _ => (),
-                }
+        let mut code = find_code(tic_file::load(self.input)?)?;
+        if self.pretty {
+            let mut program = lua::Program::parse(&code, lua::TicVersion::LATEST)?;
+            if let Some(ref path) = self.renames {
+                let text = std::fs::read(path)?;
+                program.apply_renames(&lua::reverse_renames(&lua::read_renames(&text)));
             }
-            bail!("No code chunk found");
+            code = program.pretty_print();
+        } else if self.renames.is_some() {
+            bail!("--renames needs --pretty");
         }
-        let code = find_code(chunks)?;
         File::create(self.output)?.write_all(&code)?;
         Ok(())
     }
@@ -404,81 +471,97 @@ impl CmdEmpty {
                 data: vec![],
             });
         }
-        tic_file::save(self.output, &chunks)?;
+        save_cart(&self.output, &chunks, None)?;
         Ok(())
     }
 }
 
-fn compress_code(code: Vec<u8>, iterations: i32, force_heatmap: bool) -> tic_file::Chunk {
-    let mut data = vec![];
-    zopfli_rs::compress(
-        &zopfli_rs::Options {
-            iterations,
-            ..Default::default()
-        },
-        &zopfli_rs::Format::Zlib,
-        &code,
-        &mut data,
-    )
-    .unwrap();
-    data.truncate(data.len() - 4);
-    let zopfli_size = data.len();
-
-    let mut zlib_encoder = ZlibEncoder::new(vec![], flate2::Compression::best());
-    zlib_encoder.write_all(&code).unwrap();
-    let mut dataz = zlib_encoder.finish().unwrap();
-    dataz.truncate(dataz.len() - 4);
-    let zlib_size = dataz.len();
-
-    if dataz.len() < data.len() {
-        data = dataz;
+fn save_cart(path: &Path, chunks: &[tic_file::Chunk], cover: Option<&Path>) -> Result<()> {
+    print_saved(&tic_file::save_with_cover(path, chunks, cover)?);
+    Ok(())
+}
+
+fn print_saved(saved: &tic_file::Saved) {
+    println!("                Total size: {:5} bytes", saved.size);
+    if let Some(png_size) = saved.png_size {
+        println!("            PNG cart size: {:5} bytes", png_size);
     }
+}
 
-    let analysis = deflate::analyze(&data[2..]);
+fn write_html(path: &Path, input: &Path, analysis: &deflate::Analysis) -> Result<()> {
+    let title = input.file_name().unwrap_or_default().to_string_lossy();
+    File::create(path)?.write_all(report::html_report(&title, analysis)?.as_bytes())?;
+    Ok(())
+}
 
+fn print_compression(compressed: &Compressed, force_heatmap: bool) -> Result<()> {
+    let analysis = &compressed.analysis;
     print_char_distribution(analysis.data());
 
-    if code.len() <= 1024 || force_heatmap {
+    if compressed.uncompressed_size <= 1024 || force_heatmap {
         println!("Heatmap:\n");
-        analysis.print_heatmap().unwrap();
+        analysis.print_heatmap()?;
         println!();
     }
 
     analysis.print_sizes();
     println!();
 
-    println!("         Uncompressed size: {:5} bytes", code.len());
-    println!("  Compressed size (Zopfli): {:5} bytes", zopfli_size);
-    println!("    Compressed size (zlib): {:5} bytes", zlib_size);
+    println!(
+        "         Uncompressed size: {:5} bytes",
+        compressed.uncompressed_size
+    );
+    println!(
+        "  Compressed size (Zopfli): {:5} bytes",
+        compressed.zopfli_size
+    );
+    println!(
+        "    Compressed size (zlib): {:5} bytes",
+        compressed.zlib_size
+    );
+    println!(
+        "Compressed size (tic-tool): {:5} bytes",
+        compressed.tic_tool_size
+    );
+    if compressed.tic_tool_size < compressed.zopfli_size {
+        println!(
+            "  tic-tool encoder beats Zopfli by {} bytes",
+            compressed.zopfli_size - compressed.tic_tool_size
+        );
+    }
+    Ok(())
+}
 
-    if code.len() <= data.len() {
-        tic_file::Chunk {
-            type_: 0x05,
-            bank: 0,
-            data: code,
-        }
-    } else {
-        tic_file::Chunk {
-            type_: 0x10,
-            bank: 0,
-            data,
+fn print_costs(costs: &CostReport) {
+    const LIMIT: usize = 10;
+    fn print_list(title: &str, costs: &[report::Cost]) {
+        println!("{}:", title);
+        for cost in costs.iter().take(LIMIT) {
+            println!(
+                "  {:8.1} bits {:5} bytes  {}",
+                cost.bits, cost.bytes, cost.name
+            );
         }
+        println!();
     }
+
+    print_list("Most expensive identifiers", &costs.identifiers);
+    print_list("Cost per token type", &costs.token_types);
+    println!("Most expensive lines:");
+    for line in costs.lines.iter().take(LIMIT) {
+        println!(
+            "  {:8.1} bits {:5} bytes  line {}",
+            line.bits, line.bytes, line.line
+        );
+    }
+    println!();
+    print_list("Most expensive functions", &costs.functions);
 }
 
 fn print_char_distribution(data: &deflate::AnalysisData) {
     use crossterm::style::Color;
-    let mut counts: HashMap<u8, usize> = HashMap::new();
-    let mut total = 0;
-    for (&c, &lit_index) in data.unpacked.iter().zip(data.literal_index.iter()) {
-        if lit_index == usize::MAX {
-            *counts.entry(c).or_default() += 1;
-            total += 1;
-        }
-    }
-    let mut counts: Vec<(u8, usize)> = counts.into_iter().collect();
-    counts.sort_by_key(|&(_, count)| count);
-    counts.reverse();
+    let counts = data.literal_counts();
+    let total: usize = counts.iter().map(|&(_, count)| count).sum();
     println!("Number of unique chars: {}", counts.len());
     print!(" ");
     for &(c, _) in &counts {
@@ -497,7 +580,7 @@ fn print_char_distribution(data: &deflate::AnalysisData) {
     let blocks = ['\u{2588}', '\u{2593}', '\u{2592}', '\u{2591}', ' '];
     for &(_, count) in &counts {
         let heat = (count as f32 * counts.len() as f32 / total as f32).ln() / 1.5f32.ln();
-        let heat = (0.5 - heat / 4.).max(0.).min(1.) * colors.len() as f32;
+        let heat = (0.5 - heat / 4.).clamp(0., 1.) * colors.len() as f32;
         let index = (heat as usize).min(colors.len() - 2);
         let frac = heat - index as f32;
         let block_index = (frac * blocks.len() as f32 - 0.5)
@@ -517,63 +600,395 @@ fn print_char_distribution(data: &deflate::AnalysisData) {
 
 #[derive(Clap)]
 struct CmdAnalyze {
+    #[clap(
+        long,
+        default_value = "text",
+        possible_values = &["text", "json"],
+        about = "Print the analysis as text or as JSON"
+    )]
+    format: String,
+    #[clap(
+        long,
+        about = "Write an HTML heatmap of the (first) compressed code chunk"
+    )]
+    html: Option<PathBuf>,
     input: PathBuf,
 }
 
-const CHUNK_NAMES: &[&'static str] = &[
-    "Tiles",
-    "Sprites",
-    "Cover Image",
-    "Map",
-    "Code (uncompressed)",
-    "Flags",
-    "Unknown",
-    "Unknown",
-    "SFX",
-    "Waveforms",
-    "Unknown",
-    "Palette",
-    "Music Patterns (old)",
-    "Music Tracks",
-    "Music Patterns",
-    "Code (compressed)",
-    "New Defaults",
-];
-
 impl CmdAnalyze {
     fn exec(self) -> Result<()> {
-        let chunks = tic_file::load(self.input)?;
+        let chunks = tic_file::load(&self.input)?;
+
+        if let Some(ref html) = self.html {
+            let chunk = chunks
+                .iter()
+                .find(|chunk| chunk.type_ == 0x10)
+                .ok_or_else(|| anyhow!("No compressed code chunk found"))?;
+            let analysis = deflate::analyze(deflate_stream(&chunk.data)?)?;
+            write_html(html, &self.input, &analysis)?;
+        }
 
-        for chunk in chunks {
-            let name = if chunk.type_ > 0 && chunk.type_ as usize <= CHUNK_NAMES.len() {
-                CHUNK_NAMES[chunk.type_ as usize - 1]
-            } else {
-                "Unknown"
+        if self.format == "json" {
+            let mut code = vec![];
+            for (index, chunk) in chunks.iter().enumerate() {
+                if chunk.type_ == 0x10 {
+                    let analysis = deflate::analyze(deflate_stream(&chunk.data)?)?;
+                    code.push(ChunkAnalysis {
+                        chunk: index,
+                        report: CodeReport::new(&analysis),
+                    });
+                }
+            }
+            let report = AnalyzeReport {
+                chunks: report::chunk_list(&chunks),
+                code,
             };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+
+        for chunk in chunks {
             println!(
                 "Chunk '{}' ({:02x}) - len {}",
-                name,
+                tic_file::chunk_name(chunk.type_),
                 chunk.type_,
                 chunk.data.len()
             );
 
-            match chunk.type_ {
-                0x10 => {
-                    use crossterm::tty::IsTty;
-                    use std::io::stdout;
-                    let analysis = deflate::analyze(&chunk.data[2..]);
-                    println!();
-                    analysis.disassemble();
-                    if stdout().is_tty() {
-                        println!();
-                        analysis.print_heatmap()?;
-                    }
+            if chunk.type_ == 0x10 {
+                use crossterm::tty::IsTty;
+                use std::io::stdout;
+                let analysis = deflate::analyze(deflate_stream(&chunk.data)?)?;
+                println!();
+                analysis.disassemble();
+                if stdout().is_tty() {
                     println!();
+                    analysis.print_heatmap()?;
+                }
+                println!();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdOptimize {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+impl CmdOptimize {
+    fn exec(self) -> Result<()> {
+        let mut chunks = tic_file::load(&self.input)?;
+
+        for chunk in &mut chunks {
+            if chunk.type_ != 0x10 {
+                continue;
+            }
+            let analysis = deflate::analyze(deflate_stream(&chunk.data)?)?;
+            let stream = encoder::reencode(analysis.lz_symbols());
+            let old_size = analysis.total_size();
+            let new_size = deflate::analyze(&stream)?.total_size();
+            println!(
+                "Deflate bitstream size: {}'{} bytes -> {}'{} bytes",
+                old_size >> 3,
+                old_size & 7,
+                new_size >> 3,
+                new_size & 7
+            );
+            if new_size < old_size {
+                chunk.data.truncate(2);
+                chunk.data.extend(stream);
+            } else {
+                println!("No improvement found, keeping code chunk as is");
+            }
+        }
+
+        save_cart(&self.output, &chunks, None)?;
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdCheck {
+    #[clap(
+        short,
+        long,
+        default_value = "60",
+        about = "Number of frames to compare"
+    )]
+    frames: u32,
+    #[clap(about = "Either a .tic/.png cart or source code")]
+    original: PathBuf,
+    #[clap(about = "Either a .tic/.png cart or source code")]
+    packed: PathBuf,
+}
+
+impl CmdCheck {
+    fn exec(self) -> Result<()> {
+        fn load_code(path: &PathBuf) -> Result<Vec<u8>> {
+            if tic_file::is_cart_file(path) {
+                find_code(tic_file::load(path)?)
+            } else {
+                let mut buffer = vec![];
+                File::open(path)?.read_to_end(&mut buffer)?;
+                Ok(buffer)
+            }
+        }
+
+        let mut original = vm::Machine::new(&load_code(&self.original)?)
+            .map_err(|err| anyhow!("Original code failed to start: {}", err))?;
+        let mut packed = vm::Machine::new(&load_code(&self.packed)?)
+            .map_err(|err| anyhow!("Packed code failed to start: {}", err))?;
+
+        for frame in 0..self.frames {
+            let expected = original
+                .frame()
+                .map_err(|err| anyhow!("Original code failed in frame {}: {}", frame, err))?;
+            let found = packed
+                .frame()
+                .map_err(|err| anyhow!("Packed code failed in frame {}: {}", frame, err))?;
+            if let Some(diff) = expected.diff(&found) {
+                bail!("Frame {}: {}", frame, diff);
+            }
+        }
+
+        println!(
+            "Original and packed code behave identically for {} frames",
+            self.frames
+        );
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdValidate {
+    input: PathBuf,
+}
+
+impl CmdValidate {
+    fn exec(self) -> Result<()> {
+        let (chunks, diagnostics) = tic_file::load_validated(&self.input)?;
+        for diagnostic in &diagnostics {
+            println!("{}", diagnostic);
+        }
+        let errors = diagnostics
+            .iter()
+            .filter(|d| d.severity == tic_file::Severity::Error)
+            .count();
+        if errors > 0 {
+            bail!("{} error(s) found in {} chunks", errors, chunks.len());
+        }
+        println!("{} chunks, no errors found", chunks.len());
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdExportText {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+impl CmdExportText {
+    fn exec(self) -> Result<()> {
+        let mut chunks = tic_file::load(&self.input)?;
+        for chunk in &mut chunks {
+            if chunk.type_ == 0x10 {
+                chunk.type_ = 0x05;
+                chunk.data = unpack_code(&chunk.data)?;
+            }
+        }
+        // the new defaults are applied by packing with --new-palette
+        chunks.retain(|chunk| chunk.type_ != 0x11);
+        let (text, warnings) = tic_file::to_text(&chunks);
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        File::create(self.output)?.write_all(&text)?;
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdExportGfx {
+    input: PathBuf,
+    #[clap(about = "Directory to write the images to")]
+    output: PathBuf,
+}
+
+impl CmdExportGfx {
+    fn exec(self) -> Result<()> {
+        use tic_file::{gfx, ChunkData};
+
+        let chunks = tic_file::load(&self.input)?;
+        let palette = tic_file::cart_palette(&chunks);
+        std::fs::create_dir_all(&self.output)?;
+        let save = |name: &str, bank: u8, width, height, rgba: &[u8]| -> Result<()> {
+            let path = match bank {
+                0 => self.output.join(format!("{}.png", name)),
+                _ => self.output.join(format!("{}{}.png", name, bank)),
+            };
+            gfx::save_png(&path, width, height, rgba)?;
+            println!("Wrote {}", path.display());
+            Ok(())
+        };
+        let save_image = |name: &str, bank: u8, image: gfx::Image| {
+            save(
+                name,
+                bank,
+                image.width,
+                image.height,
+                &image.to_rgba(&palette),
+            )
+        };
+
+        for chunk in &chunks {
+            match chunk.parse()? {
+                ChunkData::Tiles(tiles) => {
+                    save_image("tiles", chunk.bank, gfx::tile_sheet(&tiles))?
+                }
+                ChunkData::Sprites(sprites) => {
+                    save_image("sprites", chunk.bank, gfx::tile_sheet(&sprites))?
+                }
+                ChunkData::Map(map) => {
+                    let tiles = chunks
+                        .iter()
+                        .find(|c| c.type_ == 0x01 && c.bank == chunk.bank)
+                        .map(|c| c.parse())
+                        .transpose()?;
+                    let tiles = match tiles {
+                        Some(ChunkData::Tiles(tiles)) => tiles,
+                        _ => vec![],
+                    };
+                    save_image("map", chunk.bank, gfx::render_map(&map, &tiles))?
+                }
+                ChunkData::Screen(screen) => {
+                    save_image("screen", chunk.bank, gfx::screen_image(&screen))?
+                }
+                ChunkData::Cover(gif) => {
+                    let (width, height, rgba) = gfx::decode_gif(&gif)?;
+                    save("cover", chunk.bank, width, height, &rgba)?
                 }
                 _ => (),
             }
         }
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdImportGfx {
+    #[clap(
+        short,
+        long,
+        default_value = "tiles",
+        possible_values = &["tiles", "sprites", "screen"],
+        about = "Chunk to replace"
+    )]
+    kind: String,
+    #[clap(short, long, default_value = "0", about = "Bank of the chunk (0-7)")]
+    bank: u8,
+    #[clap(
+        long,
+        about = "Replace colors not in the palette by the closest palette color"
+    )]
+    nearest: bool,
+    #[clap(about = "The cart to take the palette and other chunks from")]
+    input: PathBuf,
+    #[clap(about = "A 128 pixels wide tile sheet or a 240x136 screen image")]
+    image: PathBuf,
+    output: PathBuf,
+}
 
+impl CmdImportGfx {
+    fn exec(self) -> Result<()> {
+        use tic_file::{gfx, ChunkData};
+
+        if self.bank > 7 {
+            bail!("Bank {} out of range (0-7)", self.bank);
+        }
+        let mut chunks = tic_file::load(&self.input)?;
+        let (width, height, rgba) = gfx::load_png(&self.image)?;
+        let (image, replaced) = gfx::quantize(
+            width,
+            height,
+            &rgba,
+            &tic_file::cart_palette(&chunks),
+            self.nearest,
+        )?;
+        if replaced > 0 {
+            println!("{} pixels replaced by the closest palette color", replaced);
+        }
+        let data = match self.kind.as_str() {
+            "tiles" => ChunkData::Tiles(gfx::tiles_from_sheet(&image)?),
+            "sprites" => ChunkData::Sprites(gfx::tiles_from_sheet(&image)?),
+            _ => ChunkData::Screen(gfx::screen_from_image(&image)?),
+        };
+        let chunk = tic_file::Chunk::new(self.bank, &data);
+
+        match chunks
+            .iter()
+            .position(|c| c.type_ == chunk.type_ && c.bank == chunk.bank)
+        {
+            Some(index) => chunks[index] = chunk,
+            None => {
+                // keep a 0x11 chunk at the end so that it can still be truncated
+                let index = chunks
+                    .iter()
+                    .position(|c| c.type_ == 0x11)
+                    .unwrap_or(chunks.len());
+                chunks.insert(index, chunk);
+            }
+        }
+        save_cart(&self.output, &chunks, None)?;
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct CmdResolve {
+    #[clap(about = "A source map written by pack --source-map")]
+    map: PathBuf,
+    #[clap(about = "A 0-based offset or a 1-based line:column in the packed code")]
+    position: String,
+}
+
+impl CmdResolve {
+    fn exec(self) -> Result<()> {
+        let map = SourceMap::load(&self.map)?;
+        let offset = match self.position.split_once(':') {
+            Some((line, column)) => map
+                .packed_offset(line.trim().parse()?, column.trim().parse()?)
+                .ok_or_else(|| {
+                    anyhow!("Position {} is outside of the packed code", self.position)
+                })?,
+            None => self.position.trim().parse()?,
+        };
+        let segment = map.resolve(offset).ok_or_else(|| {
+            anyhow!(
+                "Offset {} is outside of the packed code ({} bytes)",
+                offset,
+                map.packed_size
+            )
+        })?;
+        let (line, column) = map.packed_line_col(offset);
+        println!(
+            "Packed code offset {} (line {}, column {}):",
+            offset, line, column
+        );
+        match segment.name {
+            Some(ref name) => println!(
+                "  {}:{}:{}: {} '{}'",
+                map.source, segment.line, segment.column, segment.kind, name
+            ),
+            None => println!(
+                "  {}:{}:{}: {}",
+                map.source, segment.line, segment.column, segment.kind
+            ),
+        }
         Ok(())
     }
 }
@@ -0,0 +1,178 @@
+//! Choosing the forms of numeric literals (`0x10` or `16`, `0.5` or `.5`) which compress
+//! best. The shortest form isn't always the best one, a longer form repeating bytes
+//! which are already used nearby may compress better.
+
+use crate::compress::{combine_changes, parallel_map, CostModel};
+use crate::lua;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Numbers are written as in the source.
+    Keep,
+    /// Forms with the same value and type.
+    Exact,
+    /// Integers may turn into floats of the same value, like `30000` into `3e4`.
+    Float,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["keep", "exact", "float"];
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Mode> {
+        Ok(match s {
+            "keep" => Mode::Keep,
+            "exact" => Mode::Exact,
+            "float" => Mode::Float,
+            _ => bail!("Unknown number mode '{}'", s),
+        })
+    }
+}
+
+pub struct Choice {
+    /// The literals written differently than in the source.
+    pub forms: lua::NumberForms,
+    /// The code with these forms, which the program is left serialized to.
+    pub code: Vec<u8>,
+    /// The size of the compressed code in bits, according to the cost model.
+    pub size: usize,
+}
+
+/// Sets `program.number_forms` to the forms which compress best with `cost_model` and
+/// serializes the program with them. Candidates are compressed on up to `jobs` threads.
+pub fn choose_numbers(
+    program: &mut lua::Program,
+    mode: Mode,
+    cost_model: CostModel,
+    iterations: u32,
+    jobs: usize,
+) -> Result<Choice> {
+    let literals: Vec<(Vec<u8>, Vec<Vec<u8>>)> = match mode {
+        Mode::Keep => vec![],
+        _ => program
+            .number_literals()
+            .into_iter()
+            .map(|literal| {
+                let forms = lua::equivalent_forms(&literal, mode == Mode::Float);
+                (literal, forms)
+            })
+            .filter(|(_, forms)| forms.len() > 1)
+            .collect(),
+    };
+
+    // start with the shortest forms, then try each other form of each literal
+    let shortest: lua::NumberForms = literals
+        .iter()
+        .map(|(literal, forms)| (literal.clone(), forms[0].clone()))
+        .collect();
+    let best = evaluate_all(program, vec![shortest], cost_model, iterations, 1)?
+        .pop()
+        .unwrap();
+    let changes: Vec<(&Vec<u8>, &Vec<u8>)> = literals
+        .iter()
+        .flat_map(|(literal, forms)| forms[1..].iter().map(move |form| (literal, form)))
+        .collect();
+    let (mut forms, size) = combine_changes(
+        best.forms,
+        best.size,
+        &changes,
+        |forms, literal, form| {
+            forms.insert(literal.to_vec(), form.to_vec());
+        },
+        |candidates, jobs| {
+            let choices = evaluate_all(program, candidates, cost_model, iterations, jobs)?;
+            Ok(choices.iter().map(|choice| choice.size).collect())
+        },
+        jobs,
+    )?;
+
+    forms.retain(|literal, form| literal != form);
+    program.number_forms = forms.clone();
+    let code = program.serialize(b' ');
+    Ok(Choice { forms, code, size })
+}
+
+fn evaluate_all(
+    program: &mut lua::Program,
+    candidates: Vec<lua::NumberForms>,
+    cost_model: CostModel,
+    iterations: u32,
+    jobs: usize,
+) -> Result<Vec<Choice>> {
+    let codes: Vec<Vec<u8>> = candidates
+        .iter()
+        .map(|forms| {
+            program.number_forms = forms.clone();
+            program.serialize(b' ')
+        })
+        .collect();
+    let sizes = parallel_map(jobs, &codes, |code, jobs| {
+        cost_model
+            .analyze(code, iterations, jobs)
+            .map(|analysis| analysis.total_size())
+    });
+    candidates
+        .into_iter()
+        .zip(codes)
+        .zip(sizes)
+        .map(|((forms, code), size)| {
+            Ok(Choice {
+                forms,
+                code,
+                size: size?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn choose_numbers() {
+        let source = b"x=0x10 y=0.5 z=30000 w=1.0 t={0x10,30000}";
+        let mut program = lua::Program::parse(source, lua::TicVersion::LATEST).unwrap();
+        assert_eq!(
+            program.number_literals(),
+            vec![
+                b"0x10".to_vec(),
+                b"0.5".to_vec(),
+                b"30000".to_vec(),
+                b"1.0".to_vec()
+            ]
+        );
+
+        let choice =
+            super::choose_numbers(&mut program, Mode::Keep, CostModel::Fast, 15, 1).unwrap();
+        assert!(choice.forms.is_empty());
+        assert_eq!(
+            choice.code,
+            b"x=0x10y=0.5z=30000w=1.0t={0x10,30000}".to_vec()
+        );
+
+        for &mode in &[Mode::Exact, Mode::Float] {
+            let mut program = lua::Program::parse(source, lua::TicVersion::LATEST).unwrap();
+            let choice = super::choose_numbers(&mut program, mode, CostModel::Fast, 15, 2).unwrap();
+            assert_eq!(
+                choice.size,
+                CostModel::Fast
+                    .analyze(&choice.code, 15, 1)
+                    .unwrap()
+                    .total_size()
+            );
+            assert_eq!(program.serialize(b' '), choice.code);
+            program.verify(&choice.code).unwrap();
+            // only integers may become floats, and only with `Float`
+            for (literal, form) in &choice.forms {
+                assert!(lua::equivalent_forms(literal, mode == Mode::Float).contains(form));
+            }
+            assert!(choice.code.len() < source.len());
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Suggesting identifier renames that help compression, and searching for the best
+//! renames with the strategies in `search`.
+
+use crate::{deflate, lua};
+use std::cmp;
+use std::collections::HashMap;
+
+mod search;
+
+pub use search::{search_renames, Progress, SearchOptions, SearchResult, Strategy};
+
+/// Suggests new names for all renameable identifiers of `program`, based on `analysis` of
+/// its compressed code: the identifiers costing the most bits get the characters which
+/// are cheapest to encode because they already appear most often as literals.
+pub fn compute_rename_suggestions(
+    program: &lua::Program,
+    analysis: &deflate::Analysis,
+) -> lua::Renaming {
+    let candidates = program.get_rename_candidates();
+    let analysis = analysis.data();
+
+    let mut renameable_ids: Vec<(Vec<u8>, f32, usize)> = candidates
+        .renameable
+        .iter()
+        .map(|(id, ranges)| {
+            let mut count = 0.;
+            for range in ranges {
+                for o in range.clone() {
+                    if analysis.literal_index[o] == usize::MAX {
+                        count += if analysis.block_type[o] == 2 { 1. } else { 0.1 };
+                    }
+                }
+            }
+            let count = count / ranges[0].len() as f32;
+            (id.clone(), count, ranges[0].start)
+        })
+        .collect();
+    renameable_ids.sort_unstable_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(cmp::Ordering::Less)
+            .then(a.2.cmp(&b.2))
+    });
+    // print!("renameable ids:");
+    // for &(ref id, count, _) in &renameable_ids {
+    //     print!("  {}: {}", std::str::from_utf8(id).unwrap(), count.ceil());
+    // }
+    // println!();
+
+    let mut candidate_ids: HashMap<Vec<u8>, (f32, usize)> = HashMap::new();
+    for &offset in &candidates.candidate_chars {
+        if analysis.literal_index[offset] == usize::MAX
+            && !candidates
+                .fixed
+                .contains(&analysis.unpacked[offset..offset + 1])
+        {
+            debug_assert!(lua::is_valid_ident_start(analysis.unpacked[offset]));
+            candidate_ids
+                .entry(vec![analysis.unpacked[offset]])
+                .or_insert_with(|| (0., offset))
+                .0 += if analysis.block_type[offset] == 2 {
+                1.
+            } else {
+                0.1
+            };
+        }
+    }
+    let id_count = renameable_ids.len();
+
+    let mut candidate_ids: Vec<(Vec<u8>, f32, usize)> = candidate_ids
+        .into_iter()
+        .map(|(c, (count, offset))| (c, count, offset))
+        .collect();
+    fn white_space_efficiency(c: u8) -> u8 {
+        match c | 32 {
+            b'a'..=b'f' => 0,
+            b'p' | b'x' => 1,
+            _ => 2,
+        }
+    }
+    candidate_ids.sort_unstable_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(cmp::Ordering::Less)
+            .then(white_space_efficiency(b.0[0]).cmp(&white_space_efficiency(a.0[0])))
+            .then(a.2.cmp(&b.2))
+    });
+    // print!("candidate ids:");
+    // for &(ref id, count, _) in &candidate_ids {
+    //     print!("  {}: {}", std::str::from_utf8(id).unwrap(), count.ceil());
+    // }
+    // println!();
+
+    let mut candidate_ids: Vec<Vec<u8>> = candidate_ids.into_iter().map(|(id, ..)| id).collect();
+
+    if id_count > candidate_ids.len() {
+        let mut used_ids = candidates.fixed;
+        used_ids.extend(candidate_ids.iter().cloned());
+        for &c in b"_ghijklmnoqrstuvwyzpxabcdefGHIJKLMNOQRSTUVWYZPXABCDEF" {
+            let id = vec![c];
+            if used_ids.insert(id.clone()) {
+                candidate_ids.push(id);
+            }
+        }
+        let mut pos = 0usize;
+        while id_count > candidate_ids.len() {
+            let d = ((pos as f32 * 2. + 0.75).sqrt() - 0.5).floor() as usize;
+            let x = pos - d * (d + 1) / 2;
+            let y = d - x;
+            let mut id = candidate_ids[y].clone();
+            id.extend_from_slice(&candidate_ids[x]);
+            if used_ids.insert(id.clone()) {
+                candidate_ids.push(id);
+            }
+            pos += 1;
+        }
+    }
+
+    // locals in disjoint scopes can share a name, so pick the first candidate
+    // not yet taken by a conflicting id
+    let mut taken_by: HashMap<&[u8], Vec<&[u8]>> = HashMap::new();
+    let mut renames = lua::Renaming::new();
+    for (id, ..) in &renameable_ids {
+        let conflicts = &candidates.conflicts[id];
+        let new_id = candidate_ids
+            .iter()
+            .find(|&new_id| {
+                !taken_by
+                    .get(new_id.as_slice())
+                    .into_iter()
+                    .flatten()
+                    .any(|&other| conflicts.contains(other))
+            })
+            .unwrap();
+        taken_by.entry(new_id).or_default().push(id);
+        renames.insert(id.clone(), new_id.clone());
+    }
+    renames
+}
+
+/// Combines two sets of renames, with those of `b` taking precedence.
+pub fn merge_renames(a: &lua::Renaming, b: &lua::Renaming) -> lua::Renaming {
+    let mut a = a.clone();
+    a.extend(b.iter().map(|(src, dst)| (src.clone(), dst.clone())));
+    a
+}
@@ -0,0 +1,732 @@
+//! Searching for the renames which compress best. All strategies evaluate renamings by
+//! compressing the renamed code, they differ in which renamings they try. Where a
+//! strategy has several renamings to try at once, they are compressed in parallel.
+
+use super::{compute_rename_suggestions, merge_renames};
+use crate::compress::{parallel_map, CostModel};
+use crate::{deflate, lua};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Applies the suggestions for the last renaming until a renaming repeats.
+    Greedy,
+    /// Greedy, restarted from randomly perturbed versions of the best renaming.
+    Restarts,
+    /// Simulated annealing over swaps of the names of two identifiers, starting from the
+    /// greedy result.
+    Annealing,
+    /// Keeps the best renamings found so far, and tries the suggestions for each of them
+    /// together with random variations of these.
+    Beam,
+    /// Hill-climbing over swaps of the names of two identifiers, starting from the greedy
+    /// result.
+    Swap,
+}
+
+impl Strategy {
+    pub const NAMES: &'static [&'static str] = &["greedy", "restarts", "annealing", "beam", "swap"];
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        Ok(match s {
+            "greedy" => Strategy::Greedy,
+            "restarts" => Strategy::Restarts,
+            "annealing" => Strategy::Annealing,
+            "beam" => Strategy::Beam,
+            "swap" => Strategy::Swap,
+            _ => bail!("Unknown rename search strategy '{}'", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub strategy: Strategy,
+    /// The maximum number of renamings to evaluate, `None` for no limit.
+    pub max_evaluations: Option<usize>,
+    pub time_limit: Option<Duration>,
+    /// The seed of the random choices, the search is reproducible for the same seed and
+    /// number of jobs.
+    pub seed: u64,
+    /// The number of renamings kept by the beam search.
+    pub beam_width: usize,
+    /// The number of threads compressing renamed code. The random strategies try this
+    /// many renamings per step.
+    pub jobs: usize,
+    /// Check each renamed code with `Program::verify`.
+    pub verify: bool,
+    pub cost_model: CostModel,
+    /// The compression iterations of the cost model, should be the ones of the final
+    /// compression.
+    pub iterations: u32,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            strategy: Strategy::Greedy,
+            max_evaluations: Some(15),
+            time_limit: None,
+            seed: 1,
+            beam_width: 4,
+            jobs: 1,
+            verify: false,
+            cost_model: CostModel::Exact,
+            iterations: 15,
+        }
+    }
+}
+
+/// Reported whenever the search finds a better renaming.
+pub struct Progress {
+    pub evaluations: usize,
+    /// The size of the compressed code in bits.
+    pub best_size: usize,
+    pub elapsed: Duration,
+}
+
+pub struct SearchResult {
+    pub renames: lua::Renaming,
+    /// The code with the best renames, which the program is left serialized to.
+    pub code: Vec<u8>,
+    /// The size of the compressed code in bits, according to the cost model (`Exact` for
+    /// `TwoStage`).
+    pub size: usize,
+    pub evaluations: usize,
+    /// Whether the search was stopped by the iteration or time limit.
+    pub budget_exhausted: bool,
+}
+
+/// Searches for the renames (on top of `start`) for which the code of `program`
+/// compresses best. With `CostModel::TwoStage`, the search itself uses `Fast`, and the
+/// best renamings found are compressed with `Exact` to choose the result.
+pub fn search_renames(
+    program: &mut lua::Program,
+    start: &lua::Renaming,
+    options: &SearchOptions,
+    progress: &mut dyn FnMut(&Progress),
+) -> Result<SearchResult> {
+    if options.strategy != Strategy::Greedy
+        && options.max_evaluations.is_none()
+        && options.time_limit.is_none()
+    {
+        bail!(
+            "The {:?} rename search needs an iteration or time limit",
+            options.strategy
+        );
+    }
+
+    program.apply_renames(start);
+    let code = program.serialize(b' ');
+    if options.verify {
+        program.verify(&code)?;
+    }
+    let model = options.cost_model.search_model();
+    let analysis = model.analyze(&code, options.iterations, options.jobs)?;
+    let candidates = program.get_rename_candidates();
+    let mut keys: Vec<Vec<u8>> = candidates.renameable.keys().cloned().collect();
+    keys.sort_unstable();
+
+    let mut search = Search {
+        program,
+        options,
+        model,
+        progress,
+        started: Instant::now(),
+        evaluations: 0,
+        budget_exhausted: false,
+        seen: HashMap::new(),
+        codes: HashMap::new(),
+        best: (start.clone(), analysis.total_size()),
+        rng: Rng::new(options.seed),
+        keys,
+        conflicts: candidates.conflicts,
+    };
+    search.seen.insert(start.clone(), analysis.total_size());
+    search.codes.insert(code, analysis.total_size());
+
+    match options.strategy {
+        Strategy::Greedy => {
+            search.greedy(start.clone(), analysis)?;
+        }
+        Strategy::Restarts => search.restarts(start.clone(), analysis)?,
+        Strategy::Annealing => {
+            let (renames, size) = search.greedy(start.clone(), analysis)?;
+            search.annealing(renames, size)?;
+        }
+        Strategy::Beam => search.beam(start.clone(), analysis)?,
+        Strategy::Swap => {
+            search.greedy(start.clone(), analysis)?;
+            search.swap()?;
+        }
+    }
+
+    if options.cost_model == CostModel::TwoStage {
+        search.confirm(start)?;
+    }
+
+    let (renames, size) = search.best.clone();
+    search.program.apply_renames(&renames);
+    let code = search.program.serialize(b' ');
+    Ok(SearchResult {
+        renames,
+        code,
+        size,
+        evaluations: search.evaluations,
+        budget_exhausted: search.budget_exhausted,
+    })
+}
+
+/// xorshift64*, so that searches are reproducible on all platforms.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // splitmix64 to turn any seed (including 0) into a good state
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// A uniformly distributed float in [0, 1).
+    fn float(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+/// The random strategies give up after this many steps in a row which only tried
+/// renamings evaluated before, as they have probably seen all they can reach.
+const MAX_STALLED: usize = 1000;
+
+/// The number of best renamings the `TwoStage` cost model compresses with `Exact`, on
+/// top of the renaming the search starts from.
+const CONFIRMED: usize = 8;
+
+enum Evaluation {
+    /// The size of a new renaming and the analysis of its compressed code.
+    New(usize, deflate::Analysis),
+    /// The size of a renaming (or its code) evaluated before.
+    Seen(usize),
+}
+
+struct Search<'a> {
+    program: &'a mut lua::Program,
+    options: &'a SearchOptions,
+    /// The cost model of the search.
+    model: CostModel,
+    progress: &'a mut dyn FnMut(&Progress),
+    started: Instant,
+    evaluations: usize,
+    budget_exhausted: bool,
+    /// The sizes of all evaluated renamings.
+    seen: HashMap<lua::Renaming, usize>,
+    /// The sizes of all compressed code, as different renamings can give the same code.
+    codes: HashMap<Vec<u8>, usize>,
+    best: (lua::Renaming, usize),
+    rng: Rng,
+    /// The renameable symbol keys, sorted.
+    keys: Vec<Vec<u8>>,
+    /// See `RenameCandidates::conflicts`.
+    conflicts: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+}
+
+impl<'a> Search<'a> {
+    /// How much of the budget is used up, from 0 to 1.
+    fn budget_used(&self) -> f64 {
+        let evaluations = self
+            .options
+            .max_evaluations
+            .map_or(0., |max| self.evaluations as f64 / max.max(1) as f64);
+        let time = self.options.time_limit.map_or(0., |limit| {
+            self.started.elapsed().as_secs_f64() / limit.as_secs_f64().max(1e-9)
+        });
+        evaluations.max(time).min(1.)
+    }
+
+    /// Evaluates `renames`, or returns `None` if the budget is exhausted.
+    fn evaluate(&mut self, renames: &lua::Renaming) -> Result<Option<Evaluation>> {
+        let mut evaluations = self.evaluate_all(std::slice::from_ref(renames))?;
+        Ok(evaluations.pop().unwrap())
+    }
+
+    /// Evaluates all `candidates`, compressing their code in parallel. The results are
+    /// in the order of `candidates`, `None` for those exceeding the budget.
+    fn evaluate_all(&mut self, candidates: &[lua::Renaming]) -> Result<Vec<Option<Evaluation>>> {
+        if !self.budget_exhausted && self.budget_used() >= 1. {
+            self.budget_exhausted = true;
+        }
+        let mut budget = match self.options.max_evaluations {
+            _ if self.budget_exhausted => 0,
+            Some(max) => max.saturating_sub(self.evaluations),
+            None => usize::MAX,
+        };
+
+        // index into `codes` for the candidates to compress, `Err` for the others
+        let mut pending: Vec<std::result::Result<usize, Option<usize>>> = vec![];
+        let mut codes: Vec<Vec<u8>> = vec![];
+        for renames in candidates {
+            if let Some(&size) = self.seen.get(renames) {
+                pending.push(Err(Some(size)));
+                continue;
+            }
+            self.program.apply_renames(renames);
+            let code = self.program.serialize(b' ');
+            if let Some(&size) = self.codes.get(&code) {
+                self.seen.insert(renames.clone(), size);
+                pending.push(Err(Some(size)));
+            } else if let Some(index) = codes.iter().position(|c| *c == code) {
+                pending.push(Ok(index));
+            } else if budget == 0 {
+                self.budget_exhausted = true;
+                pending.push(Err(None));
+            } else {
+                if self.options.verify {
+                    self.program.verify(&code)?;
+                }
+                budget -= 1;
+                pending.push(Ok(codes.len()));
+                codes.push(code);
+            }
+        }
+
+        let (model, iterations) = (self.model, self.options.iterations);
+        let mut analyses: Vec<Option<deflate::Analysis>> =
+            parallel_map(self.options.jobs, &codes, |code, jobs| {
+                model.analyze(code, iterations, jobs)
+            })
+            .into_iter()
+            .map(|analysis| analysis.map(Some))
+            .collect::<Result<_>>()?;
+
+        let mut evaluations = vec![];
+        for (renames, pending) in candidates.iter().zip(pending) {
+            let index = match pending {
+                Ok(index) => index,
+                Err(size) => {
+                    evaluations.push(size.map(Evaluation::Seen));
+                    continue;
+                }
+            };
+            let analysis = match analyses[index].take() {
+                Some(analysis) => analysis,
+                None => {
+                    // another candidate with the same code
+                    let size = self.codes[&codes[index]];
+                    self.seen.insert(renames.clone(), size);
+                    evaluations.push(Some(Evaluation::Seen(size)));
+                    continue;
+                }
+            };
+            let size = analysis.total_size();
+            self.evaluations += 1;
+            self.seen.insert(renames.clone(), size);
+            self.codes.insert(codes[index].clone(), size);
+            if size < self.best.1 {
+                self.best = (renames.clone(), size);
+                (self.progress)(&Progress {
+                    evaluations: self.evaluations,
+                    best_size: size,
+                    elapsed: self.started.elapsed(),
+                });
+            }
+            evaluations.push(Some(Evaluation::New(size, analysis)));
+        }
+        Ok(evaluations)
+    }
+
+    /// Compresses `start` and the `CONFIRMED` best other renamings found with
+    /// `CostModel::Exact`, and makes the smallest of them the best renaming.
+    fn confirm(&mut self, start: &lua::Renaming) -> Result<()> {
+        let mut ranked: Vec<(usize, &lua::Renaming)> = self
+            .seen
+            .iter()
+            .map(|(renames, &size)| (size, renames))
+            .collect();
+        ranked.sort_unstable();
+        let mut confirmed: Vec<(lua::Renaming, Vec<u8>)> = vec![];
+        for renames in std::iter::once(start).chain(ranked.into_iter().map(|(_, r)| r)) {
+            if confirmed.len() == 1 + CONFIRMED {
+                break;
+            }
+            self.program.apply_renames(renames);
+            let code = self.program.serialize(b' ');
+            if confirmed.iter().all(|(_, c)| *c != code) {
+                confirmed.push((renames.clone(), code));
+            }
+        }
+
+        let iterations = self.options.iterations;
+        let sizes = parallel_map(self.options.jobs, &confirmed, |(_, code), jobs| {
+            CostModel::Exact
+                .analyze(code, iterations, jobs)
+                .map(|analysis| analysis.total_size())
+        });
+        self.evaluations += confirmed.len();
+        let mut best: Option<(lua::Renaming, usize)> = None;
+        for ((renames, _), size) in confirmed.into_iter().zip(sizes) {
+            let size = size?;
+            if best.as_ref().is_none_or(|&(_, best_size)| size < best_size) {
+                best = Some((renames, size));
+            }
+        }
+        self.best = best.unwrap();
+        Ok(())
+    }
+
+    /// The suggestions for `renames`, whose compressed code is described by `analysis`.
+    fn suggest(&mut self, renames: &lua::Renaming, analysis: &deflate::Analysis) -> lua::Renaming {
+        self.program.apply_renames(renames);
+        self.program.serialize(b' ');
+        merge_renames(renames, &compute_rename_suggestions(self.program, analysis))
+    }
+
+    /// Applies the suggestions until a renaming repeats, returning the best renaming
+    /// found on the way.
+    fn greedy(
+        &mut self,
+        mut renames: lua::Renaming,
+        mut analysis: deflate::Analysis,
+    ) -> Result<(lua::Renaming, usize)> {
+        let mut best = (renames.clone(), analysis.total_size());
+        loop {
+            renames = self.suggest(&renames, &analysis);
+            match self.evaluate(&renames)? {
+                Some(Evaluation::New(size, new_analysis)) => {
+                    if size < best.1 {
+                        best = (renames.clone(), size);
+                    }
+                    analysis = new_analysis;
+                }
+                Some(Evaluation::Seen(_)) | None => return Ok(best),
+            }
+        }
+    }
+
+    fn restarts(&mut self, renames: lua::Renaming, analysis: deflate::Analysis) -> Result<()> {
+        self.greedy(renames, analysis)?;
+        let mut stalled = 0;
+        while stalled < MAX_STALLED {
+            let mut candidates = vec![];
+            for _ in 0..self.options.jobs.max(1) {
+                let mut renames = self.best.0.clone();
+                for _ in 0..1 + self.rng.below(3) {
+                    renames = self.random_swap(&renames).unwrap_or(renames);
+                }
+                candidates.push(renames);
+            }
+            stalled += 1;
+            let evaluations = self.evaluate_all(&candidates)?;
+            for (renames, evaluation) in candidates.into_iter().zip(evaluations) {
+                match evaluation {
+                    Some(Evaluation::New(_, analysis)) => {
+                        stalled = 0;
+                        self.greedy(renames, analysis)?;
+                    }
+                    Some(Evaluation::Seen(_)) => (),
+                    None => return Ok(()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn annealing(&mut self, mut renames: lua::Renaming, mut size: usize) -> Result<()> {
+        // in bits: accept getting a byte worse early on, a fraction of a bit at the end
+        const START_TEMPERATURE: f64 = 8.;
+        const END_TEMPERATURE: f64 = 0.1;
+        let mut stalled = 0;
+        while stalled < MAX_STALLED {
+            // try one swap per job, and consider the best of them
+            let candidates: Vec<lua::Renaming> = (0..self.options.jobs.max(1))
+                .filter_map(|_| self.random_swap(&renames))
+                .collect();
+            if candidates.is_empty() {
+                break;
+            }
+            stalled += 1;
+            let mut best: Option<(lua::Renaming, usize)> = None;
+            let evaluations = self.evaluate_all(&candidates)?;
+            for (candidate, evaluation) in candidates.into_iter().zip(evaluations) {
+                let new_size = match evaluation {
+                    Some(Evaluation::New(size, _)) => {
+                        stalled = 0;
+                        size
+                    }
+                    Some(Evaluation::Seen(size)) => size,
+                    None => continue,
+                };
+                if best.as_ref().is_none_or(|&(_, size)| new_size < size) {
+                    best = Some((candidate, new_size));
+                }
+            }
+            let (candidate, new_size) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            let temperature =
+                START_TEMPERATURE * (END_TEMPERATURE / START_TEMPERATURE).powf(self.budget_used());
+            let delta = new_size as f64 - size as f64;
+            if delta <= 0. || self.rng.float() < (-delta / temperature).exp() {
+                renames = candidate;
+                size = new_size;
+            }
+        }
+        Ok(())
+    }
+
+    fn beam(&mut self, renames: lua::Renaming, analysis: deflate::Analysis) -> Result<()> {
+        let width = self.options.beam_width.max(1);
+        let mut beam = vec![(renames, analysis)];
+        while !beam.is_empty() && !self.budget_exhausted {
+            let mut candidates = vec![];
+            for (renames, analysis) in &beam {
+                let suggested = self.suggest(renames, analysis);
+                candidates.push(suggested.clone());
+                for _ in 1..width {
+                    if let Some(variant) = self.random_swap(&suggested) {
+                        candidates.push(variant);
+                    }
+                }
+            }
+            let mut children = vec![];
+            let evaluations = self.evaluate_all(&candidates)?;
+            for (candidate, evaluation) in candidates.into_iter().zip(evaluations) {
+                if let Some(Evaluation::New(size, analysis)) = evaluation {
+                    children.push((candidate, size, analysis));
+                }
+            }
+            children.sort_by_key(|&(_, size, _)| size);
+            beam = children
+                .into_iter()
+                .take(width)
+                .map(|(renames, _, analysis)| (renames, analysis))
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Swaps the names of pairs of identifiers of the best renaming as long as that
+    /// improves it.
+    fn swap(&mut self) -> Result<()> {
+        let mut pairs = vec![];
+        for a in 0..self.keys.len() {
+            for b in a + 1..self.keys.len() {
+                pairs.push((a, b));
+            }
+        }
+        'improved: loop {
+            self.rng.shuffle(&mut pairs);
+            let (renames, size) = self.best.clone();
+            let candidates: Vec<lua::Renaming> = pairs
+                .iter()
+                .filter_map(|&(a, b)| self.swapped(&renames, a, b))
+                .collect();
+            // the best renaming is updated by `evaluate_all`
+            for chunk in candidates.chunks(self.options.jobs.max(1)) {
+                let evaluations = self.evaluate_all(chunk)?;
+                if self.best.1 < size {
+                    continue 'improved;
+                }
+                if evaluations.iter().any(Option::is_none) {
+                    return Ok(());
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    /// `renames` with the names of two random identifiers swapped.
+    fn random_swap(&mut self, renames: &lua::Renaming) -> Option<lua::Renaming> {
+        if self.keys.len() < 2 {
+            return None;
+        }
+        for _ in 0..20 {
+            let a = self.rng.below(self.keys.len());
+            let b = self.rng.below(self.keys.len());
+            if let Some(swapped) = self.swapped(renames, a, b) {
+                return Some(swapped);
+            }
+        }
+        None
+    }
+
+    /// `renames` with the names of the identifiers `keys[a]` and `keys[b]` swapped, if
+    /// they are different and no identifier ends up with the name of a conflicting one.
+    fn swapped(&self, renames: &lua::Renaming, a: usize, b: usize) -> Option<lua::Renaming> {
+        let (key_a, key_b) = (&self.keys[a], &self.keys[b]);
+        let name_a = renames.get(key_a)?;
+        let name_b = renames.get(key_b)?;
+        if name_a == name_b {
+            return None;
+        }
+        let conflicts = |key: &Vec<u8>, name: &Vec<u8>, other_key: &Vec<u8>| {
+            self.keys.iter().any(|k| {
+                k != key
+                    && k != other_key
+                    && renames.get(k) == Some(name)
+                    && self.conflicts[key].contains(k)
+            })
+        };
+        if conflicts(key_a, name_b, key_b) || conflicts(key_b, name_a, key_a) {
+            return None;
+        }
+        let mut swapped = renames.clone();
+        swapped.insert(key_a.clone(), name_b.clone());
+        swapped.insert(key_b.clone(), name_a.clone());
+        Some(swapped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CODE: &[u8] = b"function TIC() local radius,angle=time()/99,0 for index=0,99 do angle=angle+index circ(120+radius*math.sin(angle),68,index%9,index) end end";
+
+    // fewer than by default, to keep the tests fast
+    const ITERATIONS: u32 = 3;
+
+    fn run(strategy: Strategy, cost_model: CostModel, jobs: usize) -> SearchResult {
+        run_code(CODE, strategy, cost_model, jobs)
+    }
+
+    fn run_code(
+        code: &[u8],
+        strategy: Strategy,
+        cost_model: CostModel,
+        jobs: usize,
+    ) -> SearchResult {
+        let mut program = lua::Program::parse(code, lua::TicVersion::LATEST).unwrap();
+        let options = SearchOptions {
+            strategy,
+            max_evaluations: Some(12),
+            jobs,
+            cost_model,
+            iterations: ITERATIONS,
+            ..SearchOptions::default()
+        };
+        search_renames(&mut program, &lua::Renaming::new(), &options, &mut |_| ()).unwrap()
+    }
+
+    fn size(cost_model: CostModel, code: &[u8]) -> usize {
+        cost_model
+            .analyze(code, ITERATIONS, 1)
+            .unwrap()
+            .total_size()
+    }
+
+    #[test]
+    fn strategies() {
+        let mut program = lua::Program::parse(CODE, lua::TicVersion::LATEST).unwrap();
+        let code = program.serialize(b' ');
+        let greedy = run(Strategy::Greedy, CostModel::Fast, 1);
+        assert!(greedy.size < size(CostModel::Fast, &code));
+
+        for &strategy in &[
+            Strategy::Restarts,
+            Strategy::Annealing,
+            Strategy::Beam,
+            Strategy::Swap,
+        ] {
+            let result = run(strategy, CostModel::Fast, 1);
+            assert!(result.evaluations <= 12, "{:?}", strategy);
+            assert!(
+                result.size <= greedy.size || strategy == Strategy::Beam,
+                "{:?}",
+                strategy
+            );
+            assert_eq!(size(CostModel::Fast, &result.code), result.size);
+            let mut program = lua::Program::parse(CODE, lua::TicVersion::LATEST).unwrap();
+            program.apply_renames(&result.renames);
+            assert_eq!(program.serialize(b' '), result.code);
+            assert!(program.check_renames(&result.renames).is_empty());
+
+            // same seed, same result
+            assert_eq!(
+                run(strategy, CostModel::Fast, 1).renames,
+                result.renames,
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn needs_budget() {
+        let mut program = lua::Program::parse(CODE, lua::TicVersion::LATEST).unwrap();
+        let options = SearchOptions {
+            strategy: Strategy::Annealing,
+            max_evaluations: None,
+            ..SearchOptions::default()
+        };
+        assert!(
+            search_renames(&mut program, &lua::Renaming::new(), &options, &mut |_| ()).is_err()
+        );
+    }
+
+    #[test]
+    fn parallel() {
+        // the beam search tries the same renamings with any number of jobs
+        let serial = run(Strategy::Beam, CostModel::Fast, 1);
+        let parallel = run(Strategy::Beam, CostModel::Fast, 4);
+        assert_eq!(parallel.renames, serial.renames);
+        assert_eq!(parallel.size, serial.size);
+        assert_eq!(parallel.evaluations, serial.evaluations);
+
+        for &strategy in &[Strategy::Restarts, Strategy::Annealing, Strategy::Swap] {
+            let result = run(strategy, CostModel::Fast, 4);
+            assert!(result.evaluations <= 12, "{:?}", strategy);
+            assert_eq!(
+                run(strategy, CostModel::Fast, 4).renames,
+                result.renames,
+                "{:?}",
+                strategy
+            );
+        }
+
+        let squares = parallel_map(3, &[1, 2, 3, 4, 5, 6, 7], |&x, _| x * x);
+        assert_eq!(squares, vec![1, 4, 9, 16, 25, 36, 49]);
+    }
+
+    #[test]
+    fn cost_models() {
+        // more names than `CODE`, to find more than `CONFIRMED` renamings
+        let code = b"function TIC() local alpha,beta,gamma,delta,omega=1,2,3,4,5 for index=0,alpha do beta=beta+gamma*delta-omega circ(alpha,beta,gamma,index) end end";
+        let fast = run_code(code, Strategy::Swap, CostModel::Fast, 1);
+        assert_eq!(fast.size, size(CostModel::Fast, &fast.code));
+
+        // the search is the same as with `Fast`, plus compressing the start and the
+        // `CONFIRMED` best renamings
+        let two_stage = run_code(code, Strategy::Swap, CostModel::TwoStage, 1);
+        assert_eq!(two_stage.size, size(CostModel::Exact, &two_stage.code));
+        assert!(fast.evaluations > CONFIRMED);
+        assert_eq!(two_stage.evaluations, fast.evaluations + 1 + CONFIRMED);
+        let mut program = lua::Program::parse(code, lua::TicVersion::LATEST).unwrap();
+        assert!(two_stage.size <= size(CostModel::Exact, &program.serialize(b' ')));
+    }
+}
@@ -0,0 +1,228 @@
+//! Machine-readable reports of what `pack` and `analyze` find, for tracking size
+//! regressions in scripts. Serialized as JSON by the CLI with `--format json`, or
+//! rendered as an HTML heatmap with `--html`. `CostReport` attributes the cost of packed
+//! code to its source.
+
+use crate::compress::Compressed;
+use crate::deflate::{Analysis, BlockSizes, LzEntry};
+use crate::{cp437, lua, tic_file};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+mod costs;
+mod html;
+
+pub use costs::{Cost, CostReport, LineCost};
+pub use html::html_report;
+
+#[derive(Serialize)]
+pub struct ChunkInfo {
+    #[serde(rename = "type")]
+    pub type_: u8,
+    pub name: &'static str,
+    pub bank: u8,
+    pub size: usize,
+}
+
+pub fn chunk_list(chunks: &[tic_file::Chunk]) -> Vec<ChunkInfo> {
+    chunks
+        .iter()
+        .map(|chunk| ChunkInfo {
+            type_: chunk.type_,
+            name: tic_file::chunk_name(chunk.type_),
+            bank: chunk.bank,
+            size: chunk.data.len(),
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct CharCount {
+    pub byte: u8,
+    /// The byte as displayed by TIC-80.
+    pub char: char,
+    /// How often the byte is encoded as a literal.
+    pub count: usize,
+}
+
+/// Where the bits of a compressed code chunk go.
+#[derive(Serialize)]
+pub struct CodeReport {
+    pub uncompressed_size: usize,
+    /// The size of the deflate stream in bits.
+    pub deflate_bits: usize,
+    pub blocks: Vec<BlockSizes>,
+    pub histogram: Vec<CharCount>,
+    /// The cost of each byte of the uncompressed code in bits.
+    pub cost: Vec<f32>,
+    /// The LZ parse of each block.
+    pub lz: Vec<Vec<LzEntry>>,
+}
+
+impl CodeReport {
+    pub fn new(analysis: &Analysis) -> CodeReport {
+        let data = analysis.data();
+        CodeReport {
+            uncompressed_size: data.unpacked.len(),
+            deflate_bits: analysis.total_size(),
+            blocks: analysis.sizes(),
+            histogram: data
+                .literal_counts()
+                .into_iter()
+                .map(|(byte, count)| CharCount {
+                    byte,
+                    char: cp437::MAPPING[byte as usize],
+                    count,
+                })
+                .collect(),
+            cost: data.cost.clone(),
+            lz: analysis.lz_entries(),
+        }
+    }
+}
+
+/// The size of the compressed chunk data produced by each compressor, in bytes.
+#[derive(Serialize)]
+pub struct CompressorSizes {
+    pub uncompressed: usize,
+    pub zopfli: usize,
+    pub zlib: usize,
+    pub tic_tool: usize,
+}
+
+#[derive(Serialize)]
+pub struct PackReport {
+    /// The chunks of the written cart.
+    pub chunks: Vec<ChunkInfo>,
+    pub sizes: CompressorSizes,
+    pub cart_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub png_size: Option<usize>,
+    /// The applied renames with `--auto-rename`, the suggested ones otherwise.
+    pub renames: BTreeMap<String, String>,
+    /// The numeric literals written differently with `--numbers`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub numbers: BTreeMap<String, String>,
+    /// The whitespace chosen between tokens, see `Choice::describe`, unless
+    /// `--no-transform`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub separator: Option<String>,
+    pub code: CodeReport,
+    /// The cost of the code by source identifier, line etc., unless `--no-transform`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub costs: Option<CostReport>,
+}
+
+impl PackReport {
+    pub fn new(
+        chunks: &[tic_file::Chunk],
+        compressed: &Compressed,
+        saved: &tic_file::Saved,
+        renames: &lua::Renaming,
+        number_forms: &lua::NumberForms,
+        separator: Option<String>,
+        costs: Option<CostReport>,
+    ) -> PackReport {
+        PackReport {
+            chunks: chunk_list(chunks),
+            sizes: CompressorSizes {
+                uncompressed: compressed.uncompressed_size,
+                zopfli: compressed.zopfli_size,
+                zlib: compressed.zlib_size,
+                tic_tool: compressed.tic_tool_size,
+            },
+            cart_size: saved.size,
+            png_size: saved.png_size,
+            renames: string_map(renames),
+            numbers: string_map(number_forms),
+            separator,
+            code: CodeReport::new(&compressed.analysis),
+            costs,
+        }
+    }
+}
+
+fn string_map(map: &BTreeMap<Vec<u8>, Vec<u8>>) -> BTreeMap<String, String> {
+    map.iter()
+        .map(|(src, dst)| {
+            (
+                String::from_utf8_lossy(src).into_owned(),
+                String::from_utf8_lossy(dst).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// The analysis of a compressed code chunk of a cart.
+#[derive(Serialize)]
+pub struct ChunkAnalysis {
+    /// The index of the chunk in `AnalyzeReport::chunks`.
+    pub chunk: usize,
+    #[serde(flatten)]
+    pub report: CodeReport,
+}
+
+#[derive(Serialize)]
+pub struct AnalyzeReport {
+    pub chunks: Vec<ChunkInfo>,
+    pub code: Vec<ChunkAnalysis>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compress, deflate};
+
+    #[test]
+    fn code_report() {
+        let code = b"cls(0)cls(1)cls(2)cls(3)print(\"hello\")".to_vec();
+        let analysis = deflate::analyze(&compress::zopfli(&code)).unwrap();
+        let report = CodeReport::new(&analysis);
+        assert_eq!(report.uncompressed_size, code.len());
+        assert_eq!(report.cost.len(), code.len());
+        // most frequent first, ties in byte order
+        let top: Vec<(char, usize)> = report.histogram[..3]
+            .iter()
+            .map(|c| (c.char, c.count))
+            .collect();
+        assert_eq!(top, vec![(')', 3), ('l', 3), ('"', 2)]);
+
+        // the lz items cover the code without gaps and add up to the block bodies
+        let mut offset = 0;
+        for (block, items) in report.blocks.iter().zip(&report.lz) {
+            let mut bits = 0;
+            for item in items {
+                match *item {
+                    LzEntry::Literal {
+                        offset: o,
+                        byte,
+                        bits: b,
+                        ..
+                    } => {
+                        assert_eq!((o, byte), (offset, code[offset]));
+                        offset += 1;
+                        bits += b;
+                    }
+                    LzEntry::Match {
+                        offset: o,
+                        length,
+                        bits: b,
+                        ..
+                    } => {
+                        assert_eq!(o, offset);
+                        offset += length as usize;
+                        bits += b;
+                    }
+                    LzEntry::EndOfBlock { bits: b, .. } => bits += b,
+                }
+            }
+            assert_eq!(bits, block.body);
+        }
+        assert_eq!(offset, code.len());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["lz"][0][0]["type"], "literal");
+        assert_eq!(json["lz"][0][0]["byte"], b'c');
+        assert!(json["blocks"][0]["huffman"].is_number());
+    }
+}
@@ -0,0 +1,157 @@
+//! Attributes the cost of the compressed code to the source code it was produced from,
+//! summed per identifier, token type, source line and function.
+
+use crate::lua::{self, SpanKind};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The summed cost of the output bytes produced by one identifier, token type or
+/// function.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Cost {
+    pub name: String,
+    /// The number of output bytes.
+    pub bytes: usize,
+    pub bits: f32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LineCost {
+    /// The 1-based line in the source code.
+    pub line: usize,
+    pub bytes: usize,
+    pub bits: f32,
+}
+
+/// Where the bits of the compressed code come from in the source code. All lists are
+/// sorted by cost, most expensive first.
+#[derive(Serialize)]
+pub struct CostReport {
+    /// By source name, before renames. Different locals of the same name are summed up.
+    pub identifiers: Vec<Cost>,
+    pub token_types: Vec<Cost>,
+    pub lines: Vec<LineCost>,
+    /// By innermost function. Code outside of any function is listed as `<main chunk>`.
+    pub functions: Vec<Cost>,
+}
+
+impl CostReport {
+    /// `program` has to be serialized to the code whose per byte cost is `cost` (see
+    /// `AnalysisData::cost`), `source` is the code it was parsed from.
+    pub fn new(program: &lua::Program, source: &[u8], cost: &[f32]) -> CostReport {
+        let line_index = lua::LineIndex::new(source);
+        let functions = program.functions();
+
+        let mut identifiers: HashMap<Vec<u8>, (usize, f32)> = HashMap::new();
+        let mut token_types: HashMap<SpanKind, (usize, f32)> = HashMap::new();
+        let mut lines: HashMap<usize, (usize, f32)> = HashMap::new();
+        // by index into `functions`, `functions.len()` for the main chunk
+        let mut function_costs: HashMap<usize, (usize, f32)> = HashMap::new();
+        fn add(entry: &mut (usize, f32), bytes: usize, bits: f32) {
+            entry.0 += bytes;
+            entry.1 += bits;
+        }
+
+        for span in program.output_spans() {
+            let bytes = span.range.len();
+            let bits: f32 = cost[span.range.clone()].iter().sum();
+            if let Some(ref name) = span.name {
+                add(identifiers.entry(name.clone()).or_default(), bytes, bits);
+            }
+            add(token_types.entry(span.kind).or_default(), bytes, bits);
+            let (line, _) = line_index.line_col(span.source_offset);
+            add(lines.entry(line).or_default(), bytes, bits);
+            // inner functions come first, so the first match is the innermost one
+            let function = functions
+                .iter()
+                .position(|f| f.source.contains(&span.source_offset))
+                .unwrap_or(functions.len());
+            add(function_costs.entry(function).or_default(), bytes, bits);
+        }
+
+        let function_name = |index: usize| match functions.get(index) {
+            Some(lua::FunctionSpan {
+                name: Some(ref name),
+                ..
+            }) => String::from_utf8_lossy(name).into_owned(),
+            Some(function) => format!(
+                "<anonymous> (line {})",
+                line_index.line_col(function.source.start).0
+            ),
+            None => "<main chunk>".to_string(),
+        };
+
+        let mut lines: Vec<LineCost> = lines
+            .into_iter()
+            .map(|(line, (bytes, bits))| LineCost { line, bytes, bits })
+            .collect();
+        lines.sort_by(|a, b| b.bits.total_cmp(&a.bits).then(a.line.cmp(&b.line)));
+
+        CostReport {
+            identifiers: sorted(
+                identifiers
+                    .into_iter()
+                    .map(|(name, cost)| (String::from_utf8_lossy(&name).into_owned(), cost)),
+            ),
+            token_types: sorted(
+                token_types
+                    .into_iter()
+                    .map(|(kind, cost)| (kind.name().to_string(), cost)),
+            ),
+            lines,
+            functions: sorted(
+                function_costs
+                    .into_iter()
+                    .map(|(index, cost)| (function_name(index), cost)),
+            ),
+        }
+    }
+}
+
+fn sorted(costs: impl Iterator<Item = (String, (usize, f32))>) -> Vec<Cost> {
+    let mut costs: Vec<Cost> = costs
+        .map(|(name, (bytes, bits))| Cost { name, bytes, bits })
+        .collect();
+    costs.sort_by(|a, b| b.bits.total_cmp(&a.bits).then_with(|| a.name.cmp(&b.name)));
+    costs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compress, deflate};
+
+    #[test]
+    fn cost_report() {
+        let source = b"function TIC()\n cls(0)\n local value=time()\n print(value,value)\nend\nx=function() return 1 end";
+        let mut program = lua::Program::parse(source, lua::TicVersion::LATEST).unwrap();
+        let code = program.serialize(b' ');
+        let analysis = deflate::analyze(&compress::zopfli(&code)).unwrap();
+        let cost = &analysis.data().cost;
+        let report = CostReport::new(&program, source, cost);
+
+        let total: f32 = cost.iter().sum();
+        let sum = |costs: &[Cost]| costs.iter().map(|c| c.bits).sum::<f32>();
+        assert!((sum(&report.token_types) - total).abs() < 0.01);
+        assert!((sum(&report.functions) - total).abs() < 0.01);
+        let line_bits: f32 = report.lines.iter().map(|l| l.bits).sum();
+        assert!((line_bits - total).abs() < 0.01);
+        assert!(report.lines.windows(2).all(|w| w[0].bits >= w[1].bits));
+
+        let value = report
+            .identifiers
+            .iter()
+            .find(|c| c.name == "value")
+            .unwrap();
+        assert_eq!(value.bytes, 3 * "value".len());
+
+        let mut functions: Vec<&str> = report.functions.iter().map(|f| &f.name[..]).collect();
+        functions.sort_unstable();
+        assert_eq!(functions, vec!["<main chunk>", "TIC", "x"]);
+        let bytes: usize = report.functions.iter().map(|f| f.bytes).sum();
+        assert_eq!(bytes, code.len());
+
+        let lines: Vec<usize> = report.lines.iter().map(|l| l.line).collect();
+        assert!(lines.contains(&4) && lines.contains(&6));
+    }
+}
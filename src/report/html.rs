@@ -0,0 +1,215 @@
+//! A self-contained HTML page showing the cost of each byte of the compressed code, like
+//! `Analysis::print_heatmap`, but without the size limits and with the details of each
+//! literal and match in tooltips.
+
+use super::CodeReport;
+use crate::cp437;
+use crate::deflate::Analysis;
+use anyhow::Result;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct PageData<'a> {
+    /// The code, one character per byte as displayed by TIC-80.
+    text: String,
+    code: &'a CodeReport,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders the heatmap page for the analysis of a compressed code chunk. The page draws
+/// the code from embedded JSON data, so it stays small for large code.
+pub fn html_report(title: &str, analysis: &Analysis) -> Result<String> {
+    let report = CodeReport::new(analysis);
+    let data = PageData {
+        text: analysis
+            .data()
+            .unpacked
+            .iter()
+            .map(|&b| cp437::MAPPING[b as usize])
+            .collect(),
+        code: &report,
+    };
+    // the data is embedded in a script element, which would end at a "</"
+    let json = serde_json::to_string(&data)?.replace("</", "<\\/");
+    Ok(TEMPLATE
+        .replace("{{title}}", &escape_html(title))
+        .replace("{{data}}", &json))
+}
+
+const TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{title}} - tic-tool heatmap</title>
+<style>
+body { font-family: sans-serif; background: #1a1c2c; color: #f4f4f4; margin: 2em; }
+h1 { font-size: 1.4em; }
+h2 { font-size: 1.1em; margin-top: 2em; }
+#code { font-family: monospace; font-size: 15px; line-height: 1.5; white-space: pre-wrap;
+  word-break: break-all; background: #000; padding: 1em; }
+#code span { color: #fff; cursor: default; }
+#code span.block { box-shadow: inset 2px 0 #fff; }
+#code span.cur { outline: 1px solid #fff; }
+#code span.src { outline: 2px solid #ffcd75; background: #ffcd75 !important; color: #000; }
+#tooltip { position: fixed; display: none; background: #f4f4f4; color: #1a1c2c; padding: 0.5em;
+  font-family: monospace; white-space: pre; pointer-events: none; border-radius: 3px; }
+table { border-collapse: collapse; }
+td, th { padding: 0.2em 0.8em; text-align: right; }
+#legend span { display: inline-block; width: 2.5em; text-align: center; color: #fff; }
+.bar { background: #41a6f6; height: 1em; }
+</style>
+</head>
+<body>
+<h1>{{title}}</h1>
+<p id="summary"></p>
+<div id="legend"></div>
+<h2>Code</h2>
+<p>Hover a character for its cost. Underlined characters are part of a match, its source
+is highlighted. A white line marks the start of a deflate block.</p>
+<div id="code"></div>
+<div id="tooltip"></div>
+<h2>Blocks</h2>
+<table id="blocks"><tr><th>block</th><th>header</th><th>huffman tables</th><th>body</th><th>total</th></tr></table>
+<h2>Literals</h2>
+<table id="histogram"><tr><th>char</th><th>byte</th><th>count</th><th></th></tr></table>
+<script type="application/json" id="data">{{data}}</script>
+<script>
+const data = JSON.parse(document.getElementById("data").textContent);
+const code = data.code;
+const chars = Array.from(data.text);
+const bytes = b => (b >> 3) + "'" + (b & 7) + " bytes";
+
+function color(cost) {
+  const t = Math.min(cost, 10) / 10;
+  return "hsl(" + (240 - 240 * t) + ", 70%, " + (25 + 15 * t) + "%)";
+}
+
+document.getElementById("summary").textContent = "Uncompressed: " + code.uncompressed_size +
+  " bytes, deflate stream: " + bytes(code.deflate_bits) + " (" +
+  (code.deflate_bits / Math.max(code.uncompressed_size, 1)).toFixed(2) + " bits per byte)";
+
+const legend = document.getElementById("legend");
+legend.append("Bits per byte: ");
+for (let i = 0; i <= 10; i++) {
+  const span = document.createElement("span");
+  span.style.background = color(i);
+  span.textContent = i == 10 ? "10+" : i;
+  legend.append(span);
+}
+
+// the lz item producing each byte, and the bytes starting a block
+const owner = [];
+const blockStart = new Set();
+code.lz.forEach((items, block) => {
+  items.forEach(item => {
+    if (item.type == "literal") {
+      owner[item.offset] = item;
+    } else if (item.type == "match") {
+      for (let i = 0; i < item.length; i++) owner[item.offset + i] = item;
+    }
+  });
+  const first = items.find(item => item.offset !== undefined);
+  if (first && block > 0) blockStart.add(first.offset);
+});
+
+const container = document.getElementById("code");
+const spans = chars.map((c, i) => {
+  const span = document.createElement("span");
+  span.textContent = c;
+  span.style.background = color(code.cost[i]);
+  if (owner[i] && owner[i].type == "match") span.style.textDecoration = "underline";
+  if (blockStart.has(i)) span.className = "block";
+  container.append(span);
+  // keep the line structure of the code, the newline glyph stays visible
+  if (c == "◙") container.append("\n");
+  return span;
+});
+
+const tooltip = document.getElementById("tooltip");
+let highlighted = [];
+
+function highlight(from, length, cls) {
+  for (let i = from; i < from + length; i++) {
+    spans[i].classList.add(cls);
+    highlighted.push([spans[i], cls]);
+  }
+}
+
+container.addEventListener("mouseover", event => {
+  const i = spans.indexOf(event.target);
+  if (i < 0) return;
+  highlighted.forEach(([span, cls]) => span.classList.remove(cls));
+  highlighted = [];
+  const item = owner[i];
+  let text = "offset " + i + ": '" + chars[i] + "'\ncost: " + code.cost[i].toFixed(2) + " bits";
+  if (item && item.type == "literal") {
+    text += "\nliteral byte " + item.byte + ": " + item.bits + " bits";
+  } else if (item && item.type == "match") {
+    const source = item.offset - item.distance;
+    text += "\nmatch of " + item.length + " bytes, distance " + item.distance + ": " +
+      item.bits + " bits (" + (item.bits / item.length).toFixed(2) + " per byte)" +
+      "\ncopied from offset " + source;
+    highlight(item.offset, item.length, "cur");
+    highlight(source, item.length, "src");
+  }
+  tooltip.textContent = text;
+  tooltip.style.display = "block";
+});
+container.addEventListener("mousemove", event => {
+  tooltip.style.left = event.clientX + 16 + "px";
+  tooltip.style.top = event.clientY + 16 + "px";
+});
+container.addEventListener("mouseleave", () => {
+  tooltip.style.display = "none";
+  highlighted.forEach(([span, cls]) => span.classList.remove(cls));
+  highlighted = [];
+});
+
+const blocks = document.getElementById("blocks");
+code.blocks.forEach((block, i) => {
+  const row = blocks.insertRow();
+  [i, block.header, block.huffman, block.body].forEach(v => row.insertCell().textContent = v);
+  row.insertCell().textContent = bytes(block.header + block.huffman + block.body);
+});
+
+const histogram = document.getElementById("histogram");
+const max = Math.max(1, ...code.histogram.map(c => c.count));
+code.histogram.forEach(c => {
+  const row = histogram.insertRow();
+  [c.char, c.byte, c.count].forEach(v => row.insertCell().textContent = v);
+  const bar = document.createElement("div");
+  bar.className = "bar";
+  bar.style.width = (300 * c.count / max) + "px";
+  row.insertCell().append(bar);
+});
+</script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compress, deflate};
+
+    #[test]
+    fn embedded_data() {
+        let code = b"print(\"</script>\")--<&>".to_vec();
+        let analysis = deflate::analyze(&compress::zopfli(&code)).unwrap();
+        let html = html_report("a<b", &analysis).unwrap();
+        assert!(html.contains("<title>a&lt;b - tic-tool heatmap</title>"));
+        assert_eq!(html.matches("</script>").count(), 2);
+
+        let start = html.find("id=\"data\">").unwrap() + 10;
+        let end = start + html[start..].find("</script>").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&html[start..end]).unwrap();
+        assert_eq!(json["text"], "print(\"</script>\")--<&>");
+        assert_eq!(json["code"]["cost"].as_array().unwrap().len(), code.len());
+    }
+}
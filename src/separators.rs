@@ -0,0 +1,240 @@
+//! Choosing the whitespace between tokens which compresses best. Lua accepts a space,
+//! newline or tab wherever two tokens need to be separated, and depending on the code one
+//! of them may compress better than the others.
+
+use crate::compress::{combine_changes, parallel_map, CostModel};
+use crate::lua;
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// The separators to try, in order of preference when they compress equally well.
+const SEPARATORS: &[u8] = b" \n\t";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Always a space.
+    Space,
+    /// The best of using a space, newline or tab everywhere.
+    Best,
+    /// The best of `Best`, then tries the other separators at each place.
+    PerSite,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["space", "best", "per-site"];
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Mode> {
+        Ok(match s {
+            "space" => Mode::Space,
+            "best" => Mode::Best,
+            "per-site" => Mode::PerSite,
+            _ => bail!("Unknown separator mode '{}'", s),
+        })
+    }
+}
+
+pub struct Choice {
+    /// The separator at each place which needs one, see `Program::separator_sites`.
+    pub separators: Vec<u8>,
+    /// The code with these separators, which the program is left serialized to.
+    pub code: Vec<u8>,
+    /// The size of the compressed code in bits, according to the cost model.
+    pub size: usize,
+}
+
+impl Choice {
+    /// The chosen separator, or how often each was chosen if they are mixed.
+    pub fn describe(&self) -> String {
+        let name = |c: u8| match c {
+            b' ' => "space",
+            b'\n' => "newline",
+            _ => "tab",
+        };
+        let counts: Vec<(u8, usize)> = SEPARATORS
+            .iter()
+            .map(|&c| (c, self.separators.iter().filter(|&&s| s == c).count()))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        match counts[..] {
+            [] => "none needed".to_string(),
+            [(c, _)] => name(c).to_string(),
+            _ => format!(
+                "mixed ({})",
+                counts
+                    .iter()
+                    .map(|&(c, count)| {
+                        format!("{} {}{}", count, name(c), if count == 1 { "" } else { "s" })
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Serializes `program` with the separators which compress best with `cost_model`.
+/// Candidates are compressed on up to `jobs` threads.
+pub fn choose_separators(
+    program: &mut lua::Program,
+    mode: Mode,
+    cost_model: CostModel,
+    iterations: u32,
+    jobs: usize,
+) -> Result<Choice> {
+    program.serialize(b' ');
+    let sites = program.separator_sites();
+    // where a newline would end a code string, `serialize` falls back to a space
+    let uniform = |ws: u8| -> Vec<u8> {
+        sites
+            .iter()
+            .map(|&in_code_string| {
+                if in_code_string && ws == b'\n' {
+                    b' '
+                } else {
+                    ws
+                }
+            })
+            .collect()
+    };
+    let mut candidates = vec![uniform(b' ')];
+    if mode == Mode::Space {
+        // nothing to choose, only rated for the report
+        let choice = evaluate_all(program, candidates, cost_model, iterations, jobs)?
+            .pop()
+            .unwrap();
+        program.serialize_with_separators(&choice.separators);
+        return Ok(choice);
+    }
+    if !sites.is_empty() {
+        for &ws in &SEPARATORS[1..] {
+            let separators = uniform(ws);
+            if !candidates.contains(&separators) {
+                candidates.push(separators);
+            }
+        }
+    }
+
+    let mut best = evaluate_all(program, candidates, cost_model, iterations, jobs)?
+        .into_iter()
+        .min_by_key(|choice| choice.size)
+        .unwrap();
+
+    if mode == Mode::PerSite {
+        // try each other separator at each place
+        let mut changes = vec![];
+        for (site, &in_code_string) in sites.iter().enumerate() {
+            for &ws in SEPARATORS {
+                if ws != best.separators[site] && !(in_code_string && ws == b'\n') {
+                    changes.push((site, ws));
+                }
+            }
+        }
+        let (separators, size) = combine_changes(
+            best.separators,
+            best.size,
+            &changes,
+            |separators, &site, &ws| separators[site] = ws,
+            |candidates, jobs| {
+                let choices = evaluate_all(program, candidates, cost_model, iterations, jobs)?;
+                Ok(choices.iter().map(|choice| choice.size).collect())
+            },
+            jobs,
+        )?;
+        best = Choice {
+            code: program.serialize_with_separators(&separators),
+            separators,
+            size,
+        };
+    }
+
+    program.serialize_with_separators(&best.separators);
+    Ok(best)
+}
+
+fn evaluate_all(
+    program: &mut lua::Program,
+    candidates: Vec<Vec<u8>>,
+    cost_model: CostModel,
+    iterations: u32,
+    jobs: usize,
+) -> Result<Vec<Choice>> {
+    let codes: Vec<Vec<u8>> = candidates
+        .iter()
+        .map(|separators| program.serialize_with_separators(separators))
+        .collect();
+    let sizes = parallel_map(jobs, &codes, |code, jobs| {
+        cost_model
+            .analyze(code, iterations, jobs)
+            .map(|analysis| analysis.total_size())
+    });
+    candidates
+        .into_iter()
+        .zip(codes)
+        .zip(sizes)
+        .map(|((separators, code), size)| {
+            Ok(Choice {
+                separators,
+                code,
+                size: size?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn separators() {
+        let source = b"x=load'local a=1 print(a)' for i=1,2 do print(i) end";
+        let mut program = lua::Program::parse(source, lua::TicVersion::LATEST).unwrap();
+        program.serialize(b'\n');
+        assert_eq!(program.separator_sites(), vec![true, false, false, false]);
+        assert_eq!(
+            program.serialize(b'\n'),
+            b"x=load'local a=1print(a)'for\ni=1,2\ndo\nprint(i)end".to_vec()
+        );
+        assert_eq!(
+            program.serialize_with_separators(b"\t\t "),
+            b"x=load'local\ta=1print(a)'for\ti=1,2 do print(i)end".to_vec()
+        );
+
+        for &mode in &[Mode::Space, Mode::Best, Mode::PerSite] {
+            let mut program = lua::Program::parse(source, lua::TicVersion::LATEST).unwrap();
+            let choice = choose_separators(&mut program, mode, CostModel::Fast, 15, 2).unwrap();
+            assert_eq!(choice.separators.len(), 4);
+            assert_eq!(
+                choice.size,
+                CostModel::Fast
+                    .analyze(&choice.code, 15, 1)
+                    .unwrap()
+                    .total_size()
+            );
+            assert_eq!(
+                program.serialize_with_separators(&choice.separators),
+                choice.code
+            );
+            program.verify(&choice.code).unwrap();
+            if mode == Mode::Space {
+                assert_eq!(choice.describe(), "space");
+            }
+        }
+    }
+
+    #[test]
+    fn describe() {
+        let choice = |separators: &[u8]| Choice {
+            separators: separators.to_vec(),
+            code: vec![],
+            size: 0,
+        };
+        assert_eq!(choice(b"\n\n").describe(), "newline");
+        assert_eq!(choice(b"").describe(), "none needed");
+        assert_eq!(choice(b" \t\t").describe(), "mixed (1 space, 2 tabs)");
+    }
+}
@@ -0,0 +1,174 @@
+//! Maps the packed code back to the source code it was produced from, to relate error
+//! positions reported by TIC-80 to the original file.
+
+use crate::lua;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+
+/// A range of the packed code produced from one source token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The range in the packed code, including the escapes inside code strings.
+    pub start: usize,
+    pub end: usize,
+    /// The token type, see `SpanKind::name`.
+    pub kind: String,
+    /// The 1-based line and column of the token in the source.
+    pub line: usize,
+    pub column: usize,
+    /// For identifiers, the name in the source code, before any renames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SourceMap {
+    /// The file the source code was read from.
+    pub source: String,
+    /// The offsets of the lines of the packed code after the first one.
+    pub packed_lines: Vec<usize>,
+    pub packed_size: usize,
+    /// Segments covering the packed code without gaps, in order.
+    pub segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    /// `program` has to be serialized to `packed`, `source` is the code it was parsed
+    /// from, read from the file `source_name`.
+    pub fn new(
+        program: &lua::Program,
+        source: &[u8],
+        source_name: &str,
+        packed: &[u8],
+    ) -> SourceMap {
+        let line_index = lua::LineIndex::new(source);
+        let segments = program
+            .output_spans()
+            .into_iter()
+            .map(|span| {
+                let (line, column) = line_index.line_col(span.source_offset);
+                Segment {
+                    start: span.range.start,
+                    end: span.range.end,
+                    kind: span.kind.name().to_string(),
+                    line,
+                    column,
+                    name: span
+                        .name
+                        .map(|name| String::from_utf8_lossy(&name).into_owned()),
+                }
+            })
+            .collect();
+        SourceMap {
+            source: source_name.to_string(),
+            packed_lines: packed
+                .iter()
+                .enumerate()
+                .filter(|&(_, &c)| c == b'\n')
+                .map(|(i, _)| i + 1)
+                .collect(),
+            packed_size: packed.len(),
+            segments,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<SourceMap> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// The segment containing `offset` of the packed code.
+    pub fn resolve(&self, offset: usize) -> Option<&Segment> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.end <= offset);
+        self.segments
+            .get(index)
+            .filter(|segment| segment.start <= offset)
+    }
+
+    /// The offset of a 1-based line and column of the packed code.
+    pub fn packed_offset(&self, line: usize, column: usize) -> Option<usize> {
+        let start = match line {
+            0 => return None,
+            1 => 0,
+            _ => *self.packed_lines.get(line - 2)?,
+        };
+        let end = self
+            .packed_lines
+            .get(line - 1)
+            .map_or(self.packed_size, |&next| next - 1);
+        let offset = start + column.checked_sub(1)?;
+        if offset <= end && offset < self.packed_size {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// The 1-based line and column of `offset` in the packed code.
+    pub fn packed_line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.packed_lines.partition_point(|&start| start <= offset);
+        let start = if line == 0 {
+            0
+        } else {
+            self.packed_lines[line - 1]
+        };
+        (line + 1, offset - start + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        let source = b"-- rename value->v\nlocal value=1\n-- transform to load\nfunction TIC()\n print(value)\nend";
+        let mut program = lua::Program::parse(source, lua::TicVersion::LATEST).unwrap();
+        let packed = program.serialize(b' ');
+        assert_eq!(packed, b"local v=1TIC=load\"print(v)\"");
+        let map = SourceMap::new(&program, source, "test.lua", &packed);
+        assert_eq!(map.segments.last().unwrap().end, packed.len());
+
+        // the `v` inside the code string
+        let offset = packed.iter().rposition(|&c| c == b'v').unwrap();
+        let segment = map.resolve(offset).unwrap();
+        assert_eq!(segment.name.as_deref(), Some("value"));
+        assert_eq!((segment.line, segment.column), (5, 8));
+        assert_eq!(map.resolve(packed.len()), None);
+
+        assert_eq!(map.packed_offset(1, offset + 1), Some(offset));
+        assert_eq!(map.packed_offset(2, 1), None);
+        assert_eq!(map.packed_line_col(offset), (1, offset + 1));
+
+        let json = serde_json::to_string(&map).unwrap();
+        let loaded: SourceMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.resolve(offset), Some(segment));
+    }
+
+    #[test]
+    fn packed_lines() {
+        let map = SourceMap {
+            source: String::new(),
+            packed_lines: vec![3, 5],
+            packed_size: 7,
+            segments: vec![],
+        };
+        assert_eq!(map.packed_offset(1, 3), Some(2));
+        assert_eq!(map.packed_offset(1, 4), None);
+        assert_eq!(map.packed_offset(2, 1), Some(3));
+        assert_eq!(map.packed_offset(3, 2), Some(6));
+        assert_eq!(map.packed_offset(3, 3), None);
+        assert_eq!(map.packed_offset(4, 1), None);
+        assert_eq!(map.packed_line_col(2), (1, 3));
+        assert_eq!(map.packed_line_col(3), (2, 1));
+        assert_eq!(map.packed_line_col(6), (3, 2));
+    }
+}
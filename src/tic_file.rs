@@ -1,50 +1,188 @@
+//! Reading and writing TIC-80 carts (.tic files, PNG carts and text carts) and their
+//! chunks.
+
 use anyhow::Result;
 use bytes::{Buf, BufMut, BytesMut};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
+mod data;
+pub mod gfx;
+mod png_cart;
+mod text;
+
+pub use data::*;
+pub use text::{parse_text, to_text};
+
+#[derive(Clone)]
 pub struct Chunk {
     pub type_: u8,
     pub bank: u8,
     pub data: Vec<u8>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Unusual, but done on purpose, like truncating a trailing `0x11` chunk.
+    Info,
+    /// Probably not what was intended, but TIC-80 still loads the cart.
+    Warning,
+    /// The file is corrupt.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+pub struct Diagnostic {
+    /// The file offset of the chunk header.
+    pub offset: usize,
+    /// The index of the chunk in the file.
+    pub chunk: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: chunk {} at offset 0x{:x}: {}",
+            self.severity, self.chunk, self.offset, self.message
+        )
+    }
+}
+
+/// Whether `path` names a binary cart (.tic or .png) rather than source code.
+pub fn is_cart_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("tic") | Some("png")
+    )
+}
+
 pub fn load<P: AsRef<Path>>(filename: P) -> Result<Vec<Chunk>> {
+    Ok(load_validated(filename)?.0)
+}
+
+/// Loads a .tic file like `load`, also returning everything suspicious found in it.
+pub fn load_validated<P: AsRef<Path>>(filename: P) -> Result<(Vec<Chunk>, Vec<Diagnostic>)> {
     let mut file = vec![];
     File::open(filename)?.read_to_end(&mut file)?;
-    let mut file = &file[..];
-    let mut chunks = vec![];
+    if png_cart::is_png(&file) {
+        file = png_cart::decode(&file)?;
+    }
+    Ok(parse(&file))
+}
+
+/// Splits the contents of a .tic file into chunks. Truncated chunks are kept with as much
+/// data as there is.
+pub fn parse(file: &[u8]) -> (Vec<Chunk>, Vec<Diagnostic>) {
+    let total_size = file.len();
+    let mut file = file;
+    let mut chunks: Vec<Chunk> = vec![];
+    let mut diagnostics = vec![];
 
-    while file.remaining() > 1 {
+    while file.has_remaining() {
+        let offset = total_size - file.remaining();
+        let mut report = |severity, message: String| {
+            diagnostics.push(Diagnostic {
+                offset,
+                chunk: chunks.len(),
+                severity,
+                message,
+            })
+        };
         let (type_, bank) = {
             let v = file.get_u8();
             (v & 31, v >> 5)
         };
+        let name = chunk_name(type_);
         let length = if file.remaining() >= 2 {
-            file.get_u16_le() as usize
+            Some(file.get_u16_le() as usize)
         } else {
-            0
+            None
         };
         let mut data = vec![];
-        if file.remaining() >= 1 + length {
-            file.advance(1);
-            data.resize(length, 0);
-            file.copy_to_slice(&mut data);
-        } else {
-            file.advance(file.remaining());
+        match length {
+            Some(length) if file.remaining() > length => {
+                let reserved = file.get_u8();
+                if reserved != 0 {
+                    report(
+                        Severity::Warning,
+                        format!("reserved byte is 0x{:02x} instead of 0", reserved),
+                    );
+                }
+                data.resize(length, 0);
+                file.copy_to_slice(&mut data);
+            }
+            None | Some(0) if type_ == 0x11 => {
+                file.advance(file.remaining());
+                report(
+                    Severity::Info,
+                    "truncated trailing new defaults chunk (sizecoding trick)".to_string(),
+                );
+            }
+            Some(length) if file.has_remaining() => {
+                file.advance(1);
+                report(
+                    Severity::Error,
+                    format!(
+                        "{} chunk is truncated, {} of {} bytes present",
+                        name,
+                        file.remaining(),
+                        length
+                    ),
+                );
+                data = file.to_vec();
+                file.advance(file.remaining());
+            }
+            _ => {
+                file.advance(file.remaining());
+                report(Severity::Error, "chunk header is truncated".to_string());
+            }
+        }
+        if name == "Unknown" {
+            report(
+                Severity::Warning,
+                format!("unknown chunk type 0x{:02x}", type_),
+            );
+        } else if let Err(err) = ChunkData::parse(type_, &data) {
+            report(Severity::Error, err.to_string());
         }
+        if chunks
+            .iter()
+            .any(|chunk| chunk.type_ == type_ && chunk.bank == bank)
+        {
+            report(
+                Severity::Warning,
+                format!(
+                    "duplicate {} chunk in bank {}, overriding the earlier one",
+                    name, bank
+                ),
+            );
+        }
+
         chunks.push(Chunk { type_, bank, data });
     }
 
-    Ok(chunks)
+    (chunks, diagnostics)
 }
 
-pub fn save<P: AsRef<Path>>(filename: P, chunks: &[Chunk]) -> Result<()> {
+fn serialize(chunks: &[Chunk]) -> Vec<u8> {
     let mut file = BytesMut::new();
     for (i, chunk) in chunks.iter().enumerate() {
         file.put_u8(chunk.type_ | (chunk.bank << 5));
-        if i + 1 < chunks.len() || !chunk.data.is_empty() || chunk.type_ != 0x11  {
+        if i + 1 < chunks.len() || !chunk.data.is_empty() || chunk.type_ != 0x11 {
             file.put_u16_le(chunk.data.len() as u16);
         }
         if i + 1 < chunks.len() || !chunk.data.is_empty() {
@@ -52,7 +190,89 @@ pub fn save<P: AsRef<Path>>(filename: P, chunks: &[Chunk]) -> Result<()> {
             file.put(&chunk.data[..]);
         }
     }
-    println!("                Total size: {:5} bytes", file.len());
-    File::create(filename)?.write_all(&file[..])?;
-    Ok(())
-}
\ No newline at end of file
+    file.to_vec()
+}
+
+/// The sizes of a saved cart.
+pub struct Saved {
+    /// The size of the .tic file.
+    pub size: usize,
+    /// The size of the PNG cart, if one was written.
+    pub png_size: Option<usize>,
+}
+
+/// Saves the chunks as a .tic file, or as a PNG cart with the screen chunk as cover if
+/// `filename` ends in .png.
+pub fn save<P: AsRef<Path>>(filename: P, chunks: &[Chunk]) -> Result<Saved> {
+    save_with_cover(filename, chunks, None)
+}
+
+/// Like `save`, using the image `cover` as the cover of PNG carts.
+pub fn save_with_cover<P: AsRef<Path>>(
+    filename: P,
+    chunks: &[Chunk],
+    cover: Option<&Path>,
+) -> Result<Saved> {
+    let mut file = serialize(chunks);
+    let mut saved = Saved {
+        size: file.len(),
+        png_size: None,
+    };
+    if filename.as_ref().extension().and_then(|ext| ext.to_str()) == Some("png") {
+        let (width, height, rgba) = match cover {
+            Some(cover) => gfx::load_png(cover)?,
+            None => png_cart::default_cover(chunks)?,
+        };
+        file = png_cart::encode(&file, width, height, rgba)?;
+        saved.png_size = Some(file.len());
+    }
+    File::create(filename)?.write_all(&file)?;
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn severities(file: &[u8]) -> Vec<Severity> {
+        parse(file).1.into_iter().map(|d| d.severity).collect()
+    }
+
+    #[test]
+    fn valid_file() {
+        let (chunks, diagnostics) = parse(&[0x05, 2, 0, 0, b'-', b'-', 0x24, 1, 0, 0, 7]);
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks.len(), 2);
+        assert_eq!((chunks[1].type_, chunks[1].bank), (4, 1));
+        assert_eq!(chunks[1].data, [7]);
+    }
+
+    #[test]
+    fn truncated_defaults() {
+        for &file in &[&[0x05, 0, 0, 0, 0x11][..], &[0x11, 0], &[0x11, 0, 0]] {
+            let (chunks, diagnostics) = parse(file);
+            assert_eq!(chunks.last().unwrap().type_, 0x11);
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].severity, Severity::Info);
+        }
+    }
+
+    #[test]
+    fn corruption() {
+        let (chunks, diagnostics) = parse(&[0x05, 0, 0, 0, 0x05, 5, 0, 0, b'a']);
+        assert_eq!(chunks[1].data, b"a");
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!((diagnostics[0].chunk, diagnostics[0].offset), (1, 4));
+        assert!(diagnostics[0].message.contains("1 of 5 bytes"));
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert!(diagnostics[1].message.contains("duplicate"));
+
+        assert_eq!(severities(&[0x05, 0]), [Severity::Error]);
+        assert_eq!(severities(&[0x05, 0, 0, 1]), [Severity::Warning]);
+        assert_eq!(severities(&[0x07, 0, 0, 0]), [Severity::Warning]);
+        assert_eq!(severities(&[0x0c, 49, 0, 0]), [Severity::Error]);
+        let mut palette = vec![0x0c, 97, 0, 0];
+        palette.extend(&[1; 97]);
+        assert_eq!(severities(&palette), [Severity::Error]);
+    }
+}
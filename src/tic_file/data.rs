@@ -0,0 +1,685 @@
+//! Typed models of the contents of the different chunk types.
+//!
+//! TIC-80 strips trailing zero bytes when saving a chunk, so parsing pads the data back
+//! to the full size and serializing trims it again.
+
+use super::Chunk;
+use anyhow::{bail, Result};
+
+pub const SCREEN_WIDTH: usize = 240;
+pub const SCREEN_HEIGHT: usize = 136;
+pub const MAP_WIDTH: usize = 240;
+pub const MAP_HEIGHT: usize = 136;
+
+const TILE_SIZE: usize = 32;
+const TILE_COUNT: usize = 256;
+const FLAGS_SIZE: usize = 512;
+const SAMPLE_SIZE: usize = 66;
+const SAMPLE_COUNT: usize = 64;
+const SAMPLE_FRAMES: usize = 30;
+const WAVE_SIZE: usize = 16;
+const WAVE_COUNT: usize = 16;
+const PALETTE_SIZE: usize = 48;
+const TRACK_SIZE: usize = 51;
+const TRACK_COUNT: usize = 8;
+const TRACK_FRAMES: usize = 16;
+const PATTERN_ROWS: usize = 64;
+const PATTERN_SIZE: usize = PATTERN_ROWS * 3;
+const PATTERN_COUNT: usize = 60;
+
+const CHUNK_NAMES: &[&str] = &[
+    "Tiles",
+    "Sprites",
+    "Cover Image",
+    "Map",
+    "Code (uncompressed)",
+    "Flags",
+    "Unknown",
+    "Unknown",
+    "SFX",
+    "Waveforms",
+    "Unknown",
+    "Palette",
+    "Music Patterns (old)",
+    "Music Tracks",
+    "Music Patterns",
+    "Code (compressed)",
+    "New Defaults",
+    "Screen",
+    "Binary",
+    "Language",
+];
+
+/// Returns the human readable name of chunk type `type_`.
+pub fn chunk_name(type_: u8) -> &'static str {
+    if type_ > 0 && type_ as usize <= CHUNK_NAMES.len() {
+        CHUNK_NAMES[type_ as usize - 1]
+    } else {
+        "Unknown"
+    }
+}
+
+pub type Color = [u8; 3];
+
+/// The palette of carts with a `0x11` chunk.
+pub const SWEETIE_16: [Color; 16] = [
+    [0x1a, 0x1c, 0x2c],
+    [0x5d, 0x27, 0x5d],
+    [0xb1, 0x3e, 0x53],
+    [0xef, 0x7d, 0x57],
+    [0xff, 0xcd, 0x75],
+    [0xa7, 0xf0, 0x70],
+    [0x38, 0xb7, 0x64],
+    [0x25, 0x71, 0x79],
+    [0x29, 0x36, 0x6f],
+    [0x3b, 0x5d, 0xc9],
+    [0x41, 0xa6, 0xf6],
+    [0x73, 0xef, 0xf7],
+    [0xf4, 0xf4, 0xf4],
+    [0x94, 0xb0, 0xc2],
+    [0x56, 0x6c, 0x86],
+    [0x33, 0x3c, 0x57],
+];
+
+/// The palette of carts with neither a palette nor a `0x11` chunk.
+pub const DB16: [Color; 16] = [
+    [0x14, 0x0c, 0x1c],
+    [0x44, 0x24, 0x34],
+    [0x30, 0x34, 0x6d],
+    [0x4e, 0x4a, 0x4e],
+    [0x85, 0x4c, 0x30],
+    [0x34, 0x65, 0x24],
+    [0xd0, 0x46, 0x48],
+    [0x75, 0x71, 0x61],
+    [0x59, 0x7d, 0xce],
+    [0xd2, 0x7d, 0x2c],
+    [0x85, 0x95, 0xa1],
+    [0x6d, 0xaa, 0x2c],
+    [0xd2, 0xaa, 0x99],
+    [0x6d, 0xc2, 0xca],
+    [0xda, 0xd4, 0x5e],
+    [0xde, 0xee, 0xd6],
+];
+
+/// Returns the screen palette of bank 0 the cart is displayed with.
+pub fn cart_palette(chunks: &[Chunk]) -> [Color; 16] {
+    let palette = chunks
+        .iter()
+        .rev()
+        .find(|c| c.type_ == 0x0c && c.bank == 0)
+        .and_then(|c| match c.parse() {
+            Ok(ChunkData::Palette(palettes)) => palettes.into_iter().next(),
+            _ => None,
+        });
+    match palette {
+        Some(palette) => palette,
+        None if chunks.iter().any(|c| c.type_ == 0x11) => SWEETIE_16,
+        None => DB16,
+    }
+}
+
+/// A color index for each of the 8x8 pixels, row by row.
+pub type Tile = [[u8; 8]; 8];
+
+/// The decoded contents of a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkData {
+    Tiles(Vec<Tile>),
+    Sprites(Vec<Tile>),
+    /// The cover image, as a GIF file.
+    Cover(Vec<u8>),
+    Map(Map),
+    Code(Vec<u8>),
+    /// One byte of flags for each tile followed by one for each sprite.
+    Flags(Vec<u8>),
+    Sfx(Vec<Sample>),
+    /// 32 4-bit samples for each waveform.
+    Waveforms(Vec<[u8; 32]>),
+    /// The palette for the screen, optionally followed by the one for `OVR`/vbank 1.
+    Palette(Vec<[Color; 16]>),
+    /// Music patterns in the pre-0.80 format, kept as is.
+    PatternsOld(Vec<u8>),
+    Tracks(Vec<Track>),
+    Patterns(Vec<Pattern>),
+    /// Deflate compressed code, including the two bytes of zlib header.
+    CodeCompressed(Vec<u8>),
+    /// Marks the cart as using the new default palette and waveforms. Has no contents.
+    Defaults,
+    Screen(Screen),
+    Unknown(u8, Vec<u8>),
+}
+
+/// A 240x136 map of tile indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Map {
+    pub cells: Vec<u8>,
+}
+
+impl Map {
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.cells[y * MAP_WIDTH + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, tile: u8) {
+        self.cells[y * MAP_WIDTH + x] = tile;
+    }
+}
+
+/// A 240x136 image of color indices, as used for the screen chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screen {
+    pub pixels: Vec<u8>,
+}
+
+impl Screen {
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * SCREEN_WIDTH + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: u8) {
+        self.pixels[y * SCREEN_WIDTH + x] = color;
+    }
+}
+
+/// A sound effect.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    pub frames: Vec<SampleFrame>,
+    pub octave: u8,
+    pub pitch16x: bool,
+    /// -4..=3
+    pub speed: i8,
+    pub reverse: bool,
+    pub note: u8,
+    pub stereo_left: bool,
+    pub stereo_right: bool,
+    /// The two unused bits, kept so that chunks round-trip exactly.
+    pub reserved: u8,
+    /// The loops of the wave, volume, chord and pitch envelopes.
+    pub loops: [SoundLoop; 4],
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleFrame {
+    pub volume: u8,
+    pub wave: u8,
+    pub chord: u8,
+    /// -8..=7
+    pub pitch: i8,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SoundLoop {
+    pub start: u8,
+    pub size: u8,
+}
+
+/// A music track: the pattern played on each of the four channels for each of its 16
+/// frames (0 = none, otherwise pattern index + 1).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub frames: [[u8; 4]; TRACK_FRAMES],
+    /// In beats per minute.
+    pub tempo: i32,
+    pub rows: u8,
+    pub speed: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub rows: Vec<PatternRow>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PatternRow {
+    /// 0 = no note, 1 = note off, 4..=15 = C to B
+    pub note: u8,
+    pub param1: u8,
+    pub param2: u8,
+    pub command: u8,
+    pub sfx: u8,
+    pub octave: u8,
+}
+
+/// Checks that `data` fits into `size` bytes and pads it with zeros to that size.
+fn padded(data: &[u8], size: usize, type_: u8) -> Result<Vec<u8>> {
+    if data.len() > size {
+        bail!(
+            "{} chunk is {} bytes long, expected at most {}",
+            chunk_name(type_),
+            data.len(),
+            size
+        );
+    }
+    let mut data = data.to_vec();
+    data.resize(size, 0);
+    Ok(data)
+}
+
+fn trimmed(mut data: Vec<u8>) -> Vec<u8> {
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    data
+}
+
+fn parse_4bpp(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|&b| vec![b & 15, b >> 4]).collect()
+}
+
+fn serialize_4bpp(pixels: &[u8]) -> Vec<u8> {
+    pixels
+        .chunks(2)
+        .map(|p| (p[0] & 15) | (p.get(1).unwrap_or(&0) << 4))
+        .collect()
+}
+
+/// Sign-extends the 4-bit value `v`.
+fn signed4(v: u8) -> i8 {
+    ((v << 4) as i8) >> 4
+}
+
+fn parse_tiles(data: &[u8], type_: u8) -> Result<Vec<Tile>> {
+    let data = padded(data, TILE_SIZE * TILE_COUNT, type_)?;
+    Ok(data
+        .chunks(TILE_SIZE)
+        .map(|tile| {
+            let mut pixels = [[0; 8]; 8];
+            for (y, row) in parse_4bpp(tile).chunks(8).enumerate() {
+                pixels[y].copy_from_slice(row);
+            }
+            pixels
+        })
+        .collect())
+}
+
+fn parse_sample(data: &[u8]) -> Sample {
+    let frames = data[..SAMPLE_FRAMES * 2]
+        .chunks(2)
+        .map(|f| SampleFrame {
+            volume: f[0] & 15,
+            wave: f[0] >> 4,
+            chord: f[1] & 15,
+            pitch: signed4(f[1] >> 4),
+        })
+        .collect();
+    let (a, b) = (data[SAMPLE_FRAMES * 2], data[SAMPLE_FRAMES * 2 + 1]);
+    let mut loops = [SoundLoop::default(); 4];
+    for (l, &byte) in loops.iter_mut().zip(&data[SAMPLE_FRAMES * 2 + 2..]) {
+        *l = SoundLoop {
+            start: byte & 15,
+            size: byte >> 4,
+        };
+    }
+    Sample {
+        frames,
+        octave: a & 7,
+        pitch16x: a & 8 != 0,
+        speed: ((a << 1) as i8) >> 5,
+        reverse: a & 128 != 0,
+        note: b & 15,
+        stereo_left: b & 16 != 0,
+        stereo_right: b & 32 != 0,
+        reserved: b >> 6,
+        loops,
+    }
+}
+
+fn serialize_sample(sample: &Sample, out: &mut Vec<u8>) {
+    for i in 0..SAMPLE_FRAMES {
+        let f = sample.frames.get(i).copied().unwrap_or_default();
+        out.push((f.volume & 15) | (f.wave << 4));
+        out.push((f.chord & 15) | ((f.pitch as u8) << 4));
+    }
+    out.push(
+        (sample.octave & 7)
+            | (sample.pitch16x as u8) << 3
+            | ((sample.speed as u8) & 7) << 4
+            | (sample.reverse as u8) << 7,
+    );
+    out.push(
+        (sample.note & 15)
+            | (sample.stereo_left as u8) << 4
+            | (sample.stereo_right as u8) << 5
+            | sample.reserved << 6,
+    );
+    out.extend(sample.loops.iter().map(|l| (l.start & 15) | (l.size << 4)));
+}
+
+fn parse_track(data: &[u8]) -> Track {
+    let mut frames = [[0; 4]; TRACK_FRAMES];
+    for (frame, bytes) in frames.iter_mut().zip(data.chunks(3)) {
+        let bits = bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16;
+        for (channel, pattern) in frame.iter_mut().enumerate() {
+            *pattern = ((bits >> (channel * 6)) & 63) as u8;
+        }
+    }
+    Track {
+        frames,
+        tempo: data[48] as i8 as i32 + 150,
+        rows: 64u8.wrapping_sub(data[49]),
+        speed: data[50] as i8 as i32 + 6,
+    }
+}
+
+fn serialize_track(track: &Track, out: &mut Vec<u8>) {
+    for frame in &track.frames {
+        let bits = frame
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (channel, &pattern)| {
+                bits | ((pattern as u32 & 63) << (channel * 6))
+            });
+        out.extend_from_slice(&bits.to_le_bytes()[..3]);
+    }
+    out.push((track.tempo - 150) as u8);
+    out.push(64u8.wrapping_sub(track.rows));
+    out.push((track.speed - 6) as u8);
+}
+
+fn parse_pattern(data: &[u8]) -> Pattern {
+    Pattern {
+        rows: data
+            .chunks(3)
+            .map(|r| PatternRow {
+                note: r[0] & 15,
+                param1: r[0] >> 4,
+                param2: r[1] & 15,
+                command: (r[1] >> 4) & 7,
+                sfx: (r[1] >> 7) << 5 | (r[2] & 31),
+                octave: r[2] >> 5,
+            })
+            .collect(),
+    }
+}
+
+fn serialize_pattern(pattern: &Pattern, out: &mut Vec<u8>) {
+    for i in 0..PATTERN_ROWS {
+        let r = pattern.rows.get(i).copied().unwrap_or_default();
+        out.push((r.note & 15) | r.param1 << 4);
+        out.push((r.param2 & 15) | (r.command & 7) << 4 | (r.sfx >> 5) << 7);
+        out.push((r.sfx & 31) | r.octave << 5);
+    }
+}
+
+impl ChunkData {
+    /// Decodes the data of a chunk of type `type_`.
+    pub fn parse(type_: u8, data: &[u8]) -> Result<ChunkData> {
+        Ok(match type_ {
+            0x01 => ChunkData::Tiles(parse_tiles(data, type_)?),
+            0x02 => ChunkData::Sprites(parse_tiles(data, type_)?),
+            0x03 => ChunkData::Cover(data.to_vec()),
+            0x04 => ChunkData::Map(Map {
+                cells: padded(data, MAP_WIDTH * MAP_HEIGHT, type_)?,
+            }),
+            0x05 => ChunkData::Code(data.to_vec()),
+            0x06 => ChunkData::Flags(padded(data, FLAGS_SIZE, type_)?),
+            0x09 => ChunkData::Sfx(
+                padded(data, SAMPLE_SIZE * SAMPLE_COUNT, type_)?
+                    .chunks(SAMPLE_SIZE)
+                    .map(parse_sample)
+                    .collect(),
+            ),
+            0x0a => ChunkData::Waveforms(
+                padded(data, WAVE_SIZE * WAVE_COUNT, type_)?
+                    .chunks(WAVE_SIZE)
+                    .map(|wave| {
+                        let mut samples = [0; 32];
+                        samples.copy_from_slice(&parse_4bpp(wave));
+                        samples
+                    })
+                    .collect(),
+            ),
+            0x0c => {
+                let count = if data.len() > PALETTE_SIZE { 2 } else { 1 };
+                ChunkData::Palette(
+                    padded(data, PALETTE_SIZE * count, type_)?
+                        .chunks(PALETTE_SIZE)
+                        .map(|palette| {
+                            let mut colors = [[0; 3]; 16];
+                            for (color, rgb) in colors.iter_mut().zip(palette.chunks(3)) {
+                                color.copy_from_slice(rgb);
+                            }
+                            colors
+                        })
+                        .collect(),
+                )
+            }
+            0x0d => ChunkData::PatternsOld(data.to_vec()),
+            0x0e => ChunkData::Tracks(
+                padded(data, TRACK_SIZE * TRACK_COUNT, type_)?
+                    .chunks(TRACK_SIZE)
+                    .map(parse_track)
+                    .collect(),
+            ),
+            0x0f => ChunkData::Patterns(
+                padded(data, PATTERN_SIZE * PATTERN_COUNT, type_)?
+                    .chunks(PATTERN_SIZE)
+                    .map(parse_pattern)
+                    .collect(),
+            ),
+            0x10 => ChunkData::CodeCompressed(data.to_vec()),
+            0x11 => ChunkData::Defaults,
+            0x12 => ChunkData::Screen(Screen {
+                pixels: parse_4bpp(&padded(data, SCREEN_WIDTH * SCREEN_HEIGHT / 2, type_)?),
+            }),
+            _ => ChunkData::Unknown(type_, data.to_vec()),
+        })
+    }
+
+    pub fn type_(&self) -> u8 {
+        match *self {
+            ChunkData::Tiles(_) => 0x01,
+            ChunkData::Sprites(_) => 0x02,
+            ChunkData::Cover(_) => 0x03,
+            ChunkData::Map(_) => 0x04,
+            ChunkData::Code(_) => 0x05,
+            ChunkData::Flags(_) => 0x06,
+            ChunkData::Sfx(_) => 0x09,
+            ChunkData::Waveforms(_) => 0x0a,
+            ChunkData::Palette(_) => 0x0c,
+            ChunkData::PatternsOld(_) => 0x0d,
+            ChunkData::Tracks(_) => 0x0e,
+            ChunkData::Patterns(_) => 0x0f,
+            ChunkData::CodeCompressed(_) => 0x10,
+            ChunkData::Defaults => 0x11,
+            ChunkData::Screen(_) => 0x12,
+            ChunkData::Unknown(type_, _) => type_,
+        }
+    }
+
+    /// Encodes the data the way TIC-80 saves it, with trailing zeros trimmed.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            ChunkData::Tiles(tiles) | ChunkData::Sprites(tiles) => {
+                for tile in tiles {
+                    out.extend(serialize_4bpp(&tile.concat()));
+                }
+            }
+            ChunkData::Map(map) => out.extend_from_slice(&map.cells),
+            ChunkData::Flags(flags) => out.extend_from_slice(flags),
+            ChunkData::Sfx(samples) => {
+                for sample in samples {
+                    serialize_sample(sample, &mut out);
+                }
+            }
+            ChunkData::Waveforms(waves) => {
+                for wave in waves {
+                    out.extend(serialize_4bpp(wave));
+                }
+            }
+            ChunkData::Palette(palettes) => {
+                for palette in palettes {
+                    out.extend(palette.iter().flatten());
+                }
+            }
+            ChunkData::Tracks(tracks) => {
+                for track in tracks {
+                    serialize_track(track, &mut out);
+                }
+            }
+            ChunkData::Patterns(patterns) => {
+                for pattern in patterns {
+                    serialize_pattern(pattern, &mut out);
+                }
+            }
+            ChunkData::Screen(screen) => out.extend(serialize_4bpp(&screen.pixels)),
+            ChunkData::Defaults => (),
+            // code isn't subject to zero trimming
+            ChunkData::Code(data) | ChunkData::CodeCompressed(data) => return data.clone(),
+            ChunkData::Cover(data) | ChunkData::PatternsOld(data) | ChunkData::Unknown(_, data) => {
+                out.extend_from_slice(data)
+            }
+        }
+        trimmed(out)
+    }
+}
+
+impl Chunk {
+    pub fn new(bank: u8, data: &ChunkData) -> Chunk {
+        Chunk {
+            type_: data.type_(),
+            bank,
+            data: data.serialize(),
+        }
+    }
+
+    /// Decodes the data of this chunk according to its type.
+    pub fn parse(&self) -> Result<ChunkData> {
+        ChunkData::parse(self.type_, &self.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(type_: u8, data: &[u8]) -> ChunkData {
+        let parsed = ChunkData::parse(type_, data).unwrap();
+        assert_eq!(parsed.type_(), type_);
+        assert_eq!(parsed.serialize(), data);
+        parsed
+    }
+
+    #[test]
+    fn tiles() {
+        match roundtrip(0x01, &[0x21, 0, 0, 0, 0x30]) {
+            ChunkData::Tiles(tiles) => {
+                assert_eq!(tiles.len(), 256);
+                assert_eq!(tiles[0][0][..2], [1, 2]);
+                assert_eq!(tiles[0][1][..2], [0, 3]);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(ChunkData::parse(0x02, &[0; 8193]).is_err());
+    }
+
+    #[test]
+    fn palette_and_screen() {
+        let mut palettes = vec![[[0; 3]; 16]; 2];
+        palettes[0][1] = [0x1a, 0x1c, 0x2c];
+        palettes[1][15] = [1, 2, 3];
+        let data = ChunkData::Palette(palettes.clone()).serialize();
+        assert_eq!(data.len(), 96);
+        assert_eq!(
+            ChunkData::parse(0x0c, &data).unwrap(),
+            ChunkData::Palette(palettes)
+        );
+
+        let mut screen = Screen {
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        };
+        screen.set(1, 1, 12);
+        let data = ChunkData::Screen(screen.clone()).serialize();
+        assert_eq!(data.len(), 121);
+        assert_eq!(data[120], 0xc0);
+        assert_eq!(
+            ChunkData::parse(0x12, &data).unwrap(),
+            ChunkData::Screen(screen)
+        );
+    }
+
+    #[test]
+    fn sfx() {
+        let mut data = vec![0; SAMPLE_SIZE + 2];
+        data[0] = 0x3f;
+        data[1] = 0xe2;
+        data[60] = 0b1101_1100;
+        data[61] = 0b0101_0111;
+        data[62] = 0x21;
+        data[SAMPLE_SIZE + 1] = 5;
+        match roundtrip(0x09, &data) {
+            ChunkData::Sfx(samples) => {
+                let s = &samples[0];
+                assert_eq!(
+                    s.frames[0],
+                    SampleFrame {
+                        volume: 15,
+                        wave: 3,
+                        chord: 2,
+                        pitch: -2
+                    }
+                );
+                assert_eq!(
+                    (s.octave, s.pitch16x, s.speed, s.reverse),
+                    (4, true, -3, true)
+                );
+                assert_eq!((s.note, s.stereo_left, s.stereo_right), (7, true, false));
+                assert_eq!(s.reserved, 1);
+                assert_eq!(s.loops[0], SoundLoop { start: 1, size: 2 });
+                assert_eq!(samples[1].frames[0].wave, 0);
+                assert_eq!(samples[1].frames[0].chord, 5);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn music() {
+        let mut track = vec![0; TRACK_SIZE];
+        // channel 0: pattern 1, channel 1: pattern 2, channel 3: pattern 60
+        track[..3].copy_from_slice(&[0x81, 0, 0xf0]);
+        track[48] = 10;
+        track[49] = 32;
+        track[50] = 0xff;
+        match roundtrip(0x0e, &track) {
+            ChunkData::Tracks(tracks) => {
+                assert_eq!(tracks[0].frames[0], [1, 2, 0, 60]);
+                assert_eq!(
+                    (tracks[0].tempo, tracks[0].rows, tracks[0].speed),
+                    (160, 32, 5)
+                );
+                assert_eq!((tracks[1].tempo, tracks[1].rows), (150, 64));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        match roundtrip(0x0f, &[0x54, 0xb6, 0x83]) {
+            ChunkData::Patterns(patterns) => assert_eq!(
+                patterns[0].rows[0],
+                PatternRow {
+                    note: 4,
+                    param1: 5,
+                    param2: 6,
+                    command: 3,
+                    sfx: 35,
+                    octave: 4
+                }
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn raw_chunks() {
+        roundtrip(0x05, b"cls()\0");
+        roundtrip(0x13, &[1, 2, 3]);
+        assert_eq!(ChunkData::parse(0x11, &[]).unwrap(), ChunkData::Defaults);
+        let chunk = Chunk::new(1, &ChunkData::Flags(vec![0, 3]));
+        assert_eq!(
+            (chunk.type_, chunk.bank, &chunk.data[..]),
+            (6, 1, &[0, 3][..])
+        );
+    }
+}
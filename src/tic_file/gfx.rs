@@ -0,0 +1,258 @@
+//! Conversion between the graphics chunks and images.
+
+use super::png_cart::{read_rgba, write_rgba};
+use super::{Color, Map, Screen, Tile, MAP_HEIGHT, MAP_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH};
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The width and height of a tile sheet in tiles.
+const SHEET_TILES: usize = 16;
+
+/// An image of palette indices.
+#[derive(Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    fn draw_tile(&mut self, x: usize, y: usize, tile: &Tile) {
+        for (ty, row) in tile.iter().enumerate() {
+            let start = (y + ty) * self.width + x;
+            self.pixels[start..start + 8].copy_from_slice(row);
+        }
+    }
+
+    pub fn to_rgba(&self, palette: &[Color; 16]) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|&index| {
+                let [r, g, b] = palette[index as usize & 15];
+                vec![r, g, b, 255]
+            })
+            .collect()
+    }
+}
+
+/// Loads a PNG image as RGBA.
+pub fn load_png<P: AsRef<Path>>(filename: P) -> Result<(usize, usize, Vec<u8>)> {
+    let mut file = vec![];
+    File::open(filename)?.read_to_end(&mut file)?;
+    read_rgba(&file)
+}
+
+pub fn save_png<P: AsRef<Path>>(
+    filename: P,
+    width: usize,
+    height: usize,
+    rgba: &[u8],
+) -> Result<()> {
+    File::create(filename)?.write_all(&write_rgba(width, height, rgba)?)?;
+    Ok(())
+}
+
+/// Lays out the tiles in 16 rows of 16, like the sprite editor does.
+pub fn tile_sheet(tiles: &[Tile]) -> Image {
+    let mut image = Image::new(SHEET_TILES * 8, SHEET_TILES * 8);
+    for (i, tile) in tiles.iter().enumerate().take(SHEET_TILES * SHEET_TILES) {
+        image.draw_tile(i % SHEET_TILES * 8, i / SHEET_TILES * 8, tile);
+    }
+    image
+}
+
+/// Cuts a 128 pixels wide image into tiles. The image can be less than 128 pixels high
+/// as long as it's a multiple of 8.
+pub fn tiles_from_sheet(image: &Image) -> Result<Vec<Tile>> {
+    if image.width != SHEET_TILES * 8
+        || image.height > SHEET_TILES * 8
+        || !image.height.is_multiple_of(8)
+    {
+        bail!(
+            "Tile sheet is {}x{} pixels, expected 128 pixels wide and up to 128 pixels high in multiples of 8",
+            image.width,
+            image.height
+        );
+    }
+    let mut tiles = vec![[[0; 8]; 8]; SHEET_TILES * SHEET_TILES];
+    for (i, tile) in tiles
+        .iter_mut()
+        .enumerate()
+        .take(image.height / 8 * SHEET_TILES)
+    {
+        let (x, y) = (i % SHEET_TILES * 8, i / SHEET_TILES * 8);
+        for (ty, row) in tile.iter_mut().enumerate() {
+            let start = (y + ty) * image.width + x;
+            row.copy_from_slice(&image.pixels[start..start + 8]);
+        }
+    }
+    Ok(tiles)
+}
+
+/// Renders the whole map with the given tiles.
+pub fn render_map(map: &Map, tiles: &[Tile]) -> Image {
+    let mut image = Image::new(MAP_WIDTH * 8, MAP_HEIGHT * 8);
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if let Some(tile) = tiles.get(map.get(x, y) as usize) {
+                image.draw_tile(x * 8, y * 8, tile);
+            }
+        }
+    }
+    image
+}
+
+pub fn screen_image(screen: &Screen) -> Image {
+    Image {
+        width: SCREEN_WIDTH,
+        height: SCREEN_HEIGHT,
+        pixels: screen.pixels.clone(),
+    }
+}
+
+pub fn screen_from_image(image: &Image) -> Result<Screen> {
+    if image.width != SCREEN_WIDTH || image.height != SCREEN_HEIGHT {
+        bail!(
+            "Screen image is {}x{} pixels, expected {}x{}",
+            image.width,
+            image.height,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT
+        );
+    }
+    Ok(Screen {
+        pixels: image.pixels.clone(),
+    })
+}
+
+fn distance(a: &Color, b: &[u8]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Converts an RGBA image to palette indices. Transparent pixels become color 0. Colors
+/// not in the palette are an error, unless `nearest` is set, in which case they are
+/// replaced by the closest palette color. Returns the image and the number of pixels
+/// that were replaced.
+pub fn quantize(
+    width: usize,
+    height: usize,
+    rgba: &[u8],
+    palette: &[Color; 16],
+    nearest: bool,
+) -> Result<(Image, usize)> {
+    let mut image = Image::new(width, height);
+    let mut unknown: BTreeMap<[u8; 3], (usize, usize)> = BTreeMap::new();
+    let mut replaced = 0;
+    for (i, pixel) in rgba.chunks(4).enumerate() {
+        if pixel[3] < 128 {
+            continue;
+        }
+        let (index, color) = palette
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, color)| distance(color, pixel))
+            .unwrap();
+        if color[..] != pixel[..3] {
+            replaced += 1;
+            unknown
+                .entry([pixel[0], pixel[1], pixel[2]])
+                .or_insert((i % width, i / width));
+        }
+        image.pixels[i] = index as u8;
+    }
+    if !nearest && !unknown.is_empty() {
+        let examples: Vec<String> = unknown
+            .iter()
+            .take(8)
+            .map(|(c, (x, y))| format!("#{:02x}{:02x}{:02x} at {},{}", c[0], c[1], c[2], x, y))
+            .collect();
+        bail!(
+            "{} pixels in {} colors are not in the palette: {}{}",
+            replaced,
+            unknown.len(),
+            examples.join(", "),
+            if unknown.len() > examples.len() {
+                ", ..."
+            } else {
+                ""
+            }
+        );
+    }
+    Ok((image, replaced))
+}
+
+/// Decodes the first frame of the GIF stored in the cover image chunk to RGBA.
+pub fn decode_gif(data: &[u8]) -> Result<(usize, usize, Vec<u8>)> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data)?;
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+    let frame = decoder
+        .read_next_frame()?
+        .ok_or_else(|| anyhow!("Cover image has no frames"))?;
+    let mut rgba = vec![0; width * height * 4];
+    for y in 0..(frame.height as usize).min(height.saturating_sub(frame.top as usize)) {
+        let w = (frame.width as usize).min(width.saturating_sub(frame.left as usize));
+        let src = y * frame.width as usize * 4;
+        let dst = ((y + frame.top as usize) * width + frame.left as usize) * 4;
+        rgba[dst..dst + w * 4].copy_from_slice(&frame.buffer[src..src + w * 4]);
+    }
+    Ok((width, height, rgba))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tic_file::SWEETIE_16;
+
+    #[test]
+    fn tile_sheet_roundtrip() {
+        let mut tiles = vec![[[0; 8]; 8]; 256];
+        tiles[17][2][3] = 5;
+        tiles[255][7][7] = 15;
+        let image = tile_sheet(&tiles);
+        assert_eq!(image.pixels[(8 + 2) * 128 + 8 + 3], 5);
+        assert_eq!(tiles_from_sheet(&image).unwrap(), tiles);
+
+        let half = Image::new(128, 16);
+        assert_eq!(tiles_from_sheet(&half).unwrap().len(), 256);
+        assert!(tiles_from_sheet(&Image::new(120, 16)).is_err());
+    }
+
+    #[test]
+    fn quantization() {
+        let palette = SWEETIE_16;
+        let mut rgba = Image {
+            width: 2,
+            height: 2,
+            pixels: vec![1, 2, 3, 15],
+        }
+        .to_rgba(&palette);
+        let (image, replaced) = quantize(2, 2, &rgba, &palette, false).unwrap();
+        assert_eq!((image.pixels, replaced), (vec![1, 2, 3, 15], 0));
+
+        rgba[4] += 2;
+        rgba[15] = 0;
+        let err = quantize(2, 2, &rgba, &palette, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 pixels in 1 colors are not in the palette: #b33e53 at 1,0"
+        );
+        let (image, replaced) = quantize(2, 2, &rgba, &palette, true).unwrap();
+        assert_eq!((image.pixels, replaced), (vec![1, 2, 3, 0], 1));
+    }
+}
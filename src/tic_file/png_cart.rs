@@ -0,0 +1,220 @@
+//! PNG carts: a cover image with the zlib compressed .tic file hidden in it.
+//!
+//! TIC-80 1.0 and later store the cart in the low bits of the RGBA bytes of the image, the
+//! way `png.c` of TIC-80 does it: the first 8 bytes hold 4 bits each of the 32-bit size of
+//! the compressed cart, the following bytes as few low bits each as are needed to fit the
+//! cart into the rest of the image, `ceil(size * 8 / (width * height * 4 - 8))`. Older
+//! versions store the compressed cart in a `caRt` chunk instead.
+
+use super::{cart_palette, Chunk, ChunkData, SCREEN_HEIGHT, SCREEN_WIDTH};
+use anyhow::{anyhow, bail, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use std::io::prelude::*;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const HEADER_SIZE: usize = 8;
+const HEADER_BITS: usize = 4;
+
+pub fn is_png(file: &[u8]) -> bool {
+    file.starts_with(SIGNATURE)
+}
+
+/// Copies `count` bits from bit `from` of `src` to bit `to` of `dst`, lowest bits first.
+fn copy_bits(dst: &mut [u8], to: usize, src: &[u8], from: usize, count: usize) {
+    for i in 0..count {
+        let (to, from) = (to + i, from + i);
+        let bit = src.get(from >> 3).map_or(0, |&b| (b >> (from & 7)) & 1);
+        dst[to >> 3] = (dst[to >> 3] & !(1 << (to & 7))) | (bit << (to & 7));
+    }
+}
+
+/// Reads a PNG image, converted to 8-bit RGBA.
+pub fn read_rgba(file: &[u8]) -> Result<(usize, usize, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let pixels = buffer[..info.buffer_size()].chunks(info.color_type.samples());
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buffer[..info.buffer_size()].to_vec(),
+        png::ColorType::Rgb => pixels.flat_map(|p| vec![p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => {
+            pixels.flat_map(|p| vec![p[0], p[0], p[0], p[1]]).collect()
+        }
+        png::ColorType::Grayscale => pixels.flat_map(|p| vec![p[0], p[0], p[0], 255]).collect(),
+        png::ColorType::Indexed => bail!("Unexpanded indexed PNG"),
+    };
+    Ok((info.width as usize, info.height as usize, rgba))
+}
+
+pub fn write_rgba(width: usize, height: usize, rgba: &[u8]) -> Result<Vec<u8>> {
+    let mut file = vec![];
+    let mut encoder = png::Encoder::new(&mut file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgba)?;
+    Ok(file)
+}
+
+/// Finds the data of the first chunk of type `type_` in a PNG file.
+fn find_chunk<'a>(file: &'a [u8], type_: &[u8]) -> Option<&'a [u8]> {
+    let mut pos = SIGNATURE.len();
+    while pos + 8 <= file.len() {
+        let length = u32::from_be_bytes([file[pos], file[pos + 1], file[pos + 2], file[pos + 3]]);
+        let data = file.get(pos + 8..pos + 8 + length as usize)?;
+        if &file[pos + 4..pos + 8] == type_ {
+            return Some(data);
+        }
+        pos += 12 + length as usize;
+    }
+    None
+}
+
+/// Extracts the .tic file from a PNG cart.
+pub fn decode(file: &[u8]) -> Result<Vec<u8>> {
+    let compressed = match find_chunk(file, b"caRt") {
+        Some(data) => data.to_vec(),
+        None => {
+            let (_, _, rgba) = read_rgba(file)?;
+            if rgba.len() < HEADER_SIZE {
+                bail!("PNG file doesn't contain a TIC-80 cart");
+            }
+            let mut header = [0; 4];
+            for i in 0..HEADER_SIZE {
+                copy_bits(&mut header, i * HEADER_BITS, &rgba, i * 8, HEADER_BITS);
+            }
+            let size = u32::from_le_bytes(header) as usize;
+            let capacity = rgba.len().saturating_sub(HEADER_SIZE);
+            if size == 0 || capacity == 0 || size > capacity {
+                bail!("PNG file doesn't contain a TIC-80 cart");
+            }
+            let bits = (size * 8).div_ceil(capacity);
+            let mut data = vec![0; size + 1];
+            for i in 0..(size * 8).div_ceil(bits) {
+                copy_bits(&mut data, i * bits, &rgba[HEADER_SIZE..], i * 8, bits);
+            }
+            data.truncate(size);
+            data
+        }
+    };
+    let mut cart = vec![];
+    ZlibDecoder::new(&compressed[..])
+        .read_to_end(&mut cart)
+        .map_err(|err| anyhow!("Failed to decompress PNG cart: {}", err))?;
+    Ok(cart)
+}
+
+/// Renders the screen chunk of the cart, or a black image if there is none, to use as the
+/// cover of a PNG cart.
+pub fn default_cover(chunks: &[Chunk]) -> Result<(usize, usize, Vec<u8>)> {
+    let palette = cart_palette(chunks);
+    let mut rgba = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+    match chunks.iter().find(|c| c.type_ == 0x12 && c.bank == 0) {
+        Some(chunk) => match chunk.parse()? {
+            ChunkData::Screen(screen) => {
+                for &color in &screen.pixels {
+                    rgba.extend_from_slice(&palette[color as usize]);
+                    rgba.push(255);
+                }
+            }
+            _ => unreachable!(),
+        },
+        None => {
+            for _ in 0..SCREEN_WIDTH * SCREEN_HEIGHT {
+                rgba.extend_from_slice(&[0, 0, 0, 255]);
+            }
+        }
+    }
+    Ok((SCREEN_WIDTH, SCREEN_HEIGHT, rgba))
+}
+
+/// Hides the .tic file `cart` in the RGBA image `cover` and returns the PNG file.
+pub fn encode(cart: &[u8], width: usize, height: usize, mut cover: Vec<u8>) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(vec![], Compression::best());
+    encoder.write_all(cart)?;
+    let compressed = encoder.finish()?;
+
+    let capacity = cover.len().saturating_sub(HEADER_SIZE);
+    if compressed.len() > capacity {
+        bail!(
+            "Cart ({} bytes compressed) doesn't fit into a {}x{} cover image",
+            compressed.len(),
+            width,
+            height
+        );
+    }
+    let bits = (compressed.len() * 8).div_ceil(capacity);
+    let header = (compressed.len() as u32).to_le_bytes();
+    for i in 0..HEADER_SIZE {
+        copy_bits(&mut cover, i * 8, &header, i * HEADER_BITS, HEADER_BITS);
+    }
+    for i in 0..(compressed.len() * 8).div_ceil(bits) {
+        copy_bits(
+            &mut cover[HEADER_SIZE..],
+            i * 8,
+            &compressed,
+            i * bits,
+            bits,
+        );
+    }
+    write_rgba(width, height, &cover)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let cart: Vec<u8> = (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let (width, height, cover) = default_cover(&[]).unwrap();
+        let png = encode(&cart, width, height, cover).unwrap();
+        assert!(is_png(&png));
+        assert_eq!(decode(&png).unwrap(), cart);
+
+        // the cart only replaces the lowest bit of each byte of the cover (4 in the header)
+        let (_, _, rgba) = read_rgba(&png).unwrap();
+        assert!(rgba.iter().enumerate().all(|(i, &b)| {
+            let (bits, cover) = (
+                if i < HEADER_SIZE { 4 } else { 1 },
+                if i % 4 == 3 { 255 } else { 0 },
+            );
+            b >> bits == cover >> bits
+        }));
+
+        assert!(encode(&cart, 4, 4, vec![0; 64]).is_err());
+        assert!(encode(&cart, 1, 1, vec![0; 4]).is_err());
+        assert!(decode(&write_rgba(1, 1, &[0; 4]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn tic80_layout() {
+        // an empty cart (zlib: 78 da 03 00 00 00 00 01) in a 4x4 image, laid out by hand
+        // as TIC-80's `png.c` does: the size 8 in the low nibbles of the first 8 bytes,
+        // then ceil(8 * 8 / (64 - 8)) = 2 bits per byte, lowest bits first
+        let mut rgba = vec![0; 64];
+        rgba[0] = 8;
+        let data = [0, 2, 3, 1, 2, 2, 1, 3, 3, 0, 0, 0];
+        rgba[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(&data);
+        rgba[HEADER_SIZE + 28] = 1;
+        let png = write_rgba(4, 4, &rgba).unwrap();
+        assert_eq!(decode(&png).unwrap(), b"");
+        assert_eq!(encode(b"", 4, 4, vec![0; 64]).unwrap(), png);
+    }
+
+    #[test]
+    fn cart_chunk() {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::best());
+        encoder.write_all(b"\x05\x01\x00\x00x").unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut png = write_rgba(1, 1, &[0; 4]).unwrap();
+        // insert the caRt chunk after IHDR (signature + 25 bytes)
+        let mut chunk = (compressed.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"caRt");
+        chunk.extend_from_slice(&compressed);
+        chunk.extend_from_slice(&[0; 4]);
+        png.splice(33..33, chunk);
+        assert_eq!(decode(&png).unwrap(), b"\x05\x01\x00\x00x");
+    }
+}
@@ -0,0 +1,327 @@
+//! The text cart format TIC-80 uses when saving a cart as source code: the code, followed
+//! by the other chunks as hex dumps in comment blocks like
+//!
+//! ```text
+//! -- <TILES>
+//! -- 001:eccccccccc888888caaaaaaaca888888cacccccccacc0ccccacc0ccccacc0ccc
+//! -- </TILES>
+//! ```
+//!
+//! Each line holds one row (a tile, a line of the map, a sound effect, ...) with its index,
+//! all-zero rows are left out. Chunks in other banks than 0 get the bank number appended
+//! to the tag, like `<TILES1>`.
+
+use super::{chunk_name, Chunk};
+use anyhow::{anyhow, Result};
+
+struct Section {
+    type_: u8,
+    tag: &'static str,
+    row_size: usize,
+    rows: usize,
+    /// Whether the nibbles of each byte are swapped, so each hex digit is one 4bpp pixel.
+    flip: bool,
+}
+
+const SECTIONS: &[Section] = &[
+    Section {
+        type_: 0x01,
+        tag: "TILES",
+        row_size: 32,
+        rows: 256,
+        flip: true,
+    },
+    Section {
+        type_: 0x02,
+        tag: "SPRITES",
+        row_size: 32,
+        rows: 256,
+        flip: true,
+    },
+    Section {
+        type_: 0x04,
+        tag: "MAP",
+        row_size: 240,
+        rows: 136,
+        flip: false,
+    },
+    Section {
+        type_: 0x0a,
+        tag: "WAVES",
+        row_size: 16,
+        rows: 16,
+        flip: true,
+    },
+    Section {
+        type_: 0x09,
+        tag: "SFX",
+        row_size: 66,
+        rows: 64,
+        flip: true,
+    },
+    Section {
+        type_: 0x0f,
+        tag: "PATTERNS",
+        row_size: 192,
+        rows: 60,
+        flip: true,
+    },
+    Section {
+        type_: 0x0e,
+        tag: "TRACKS",
+        row_size: 51,
+        rows: 8,
+        flip: true,
+    },
+    Section {
+        type_: 0x06,
+        tag: "FLAGS",
+        row_size: 256,
+        rows: 2,
+        flip: false,
+    },
+    Section {
+        type_: 0x12,
+        tag: "SCREEN",
+        row_size: 120,
+        rows: 136,
+        flip: true,
+    },
+    Section {
+        type_: 0x0c,
+        tag: "PALETTE",
+        row_size: 48,
+        rows: 2,
+        flip: false,
+    },
+];
+
+/// Splits `-- <TAG>` into the section it starts and the bank.
+fn parse_tag(line: &[u8]) -> Option<(&'static Section, u8)> {
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    let tag = line.strip_prefix("-- <")?.strip_suffix('>')?;
+    SECTIONS.iter().find_map(|section| {
+        let bank = tag.strip_prefix(section.tag)?;
+        if bank.is_empty() {
+            Some((section, 0))
+        } else {
+            bank.parse().ok().filter(|&b| b < 8).map(|b| (section, b))
+        }
+    })
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Parses a text cart into its code (as a code chunk, always the first one) and the
+/// chunks of the data sections. A tag without a matching end tag is just a comment, so
+/// plain source code is returned unchanged as the code chunk.
+pub fn parse_text(source: &[u8]) -> Result<Vec<Chunk>> {
+    let mut code = vec![];
+    let mut chunks = vec![];
+    let lines: Vec<&[u8]> = source.split_inclusive(|&c| c == b'\n').collect();
+    let is_line = |line: &[u8], text: &str| String::from_utf8_lossy(line).trim_end() == text;
+
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+        let (section, bank) = match parse_tag(line) {
+            Some(tag) => tag,
+            None => {
+                code.extend_from_slice(line);
+                continue;
+            }
+        };
+        let end_tag = match bank {
+            0 => format!("-- </{}>", section.tag),
+            _ => format!("-- </{}{}>", section.tag, bank),
+        };
+        let end = match lines[index..].iter().position(|l| is_line(l, &end_tag)) {
+            Some(rows) => index + rows,
+            None => {
+                code.extend_from_slice(line);
+                continue;
+            }
+        };
+        let mut data = vec![0; section.row_size * section.rows];
+        for (index, line) in lines.iter().enumerate().take(end).skip(index) {
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim_end();
+            let error = || {
+                anyhow!(
+                    "Malformed {} row in line {}: {}",
+                    section.tag,
+                    index + 1,
+                    line
+                )
+            };
+            let (row, hex) = line
+                .strip_prefix("-- ")
+                .and_then(|l| l.split_once(':'))
+                .ok_or_else(error)?;
+            let row: usize = row.parse().map_err(|_| error())?;
+            if row >= section.rows || hex.len() > section.row_size * 2 || hex.len() % 2 != 0 {
+                return Err(error());
+            }
+            for (i, pair) in hex.as_bytes().chunks(2).enumerate() {
+                let (high, low) = match (hex_digit(pair[0]), hex_digit(pair[1])) {
+                    (Some(a), Some(b)) if section.flip => (b, a),
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Err(error()),
+                };
+                data[row * section.row_size + i] = high << 4 | low;
+            }
+        }
+        index = end + 1;
+        while data.last() == Some(&0) {
+            data.pop();
+        }
+        chunks.push(Chunk {
+            type_: section.type_,
+            bank,
+            data,
+        });
+    }
+
+    // the blank lines separating the code from the data sections
+    if !chunks.is_empty() {
+        while matches!(code.last(), Some(c) if c.is_ascii_whitespace()) {
+            code.pop();
+        }
+    }
+    chunks.insert(
+        0,
+        Chunk {
+            type_: 0x05,
+            bank: 0,
+            data: code,
+        },
+    );
+    Ok(chunks)
+}
+
+/// Writes the chunks as a text cart. Returns the text and a warning for each chunk that
+/// has no representation in text carts and was left out.
+pub fn to_text(chunks: &[Chunk]) -> (Vec<u8>, Vec<String>) {
+    let mut warnings = vec![];
+    let mut code_chunks: Vec<&Chunk> = chunks.iter().filter(|c| c.type_ == 0x05).collect();
+    code_chunks.sort_by_key(|c| c.bank);
+    let mut text: Vec<u8> = code_chunks.iter().flat_map(|c| c.data.clone()).collect();
+    if !text.ends_with(b"\n") {
+        text.push(b'\n');
+    }
+
+    for chunk in chunks {
+        if chunk.type_ != 0x05 && !SECTIONS.iter().any(|s| s.type_ == chunk.type_) {
+            warnings.push(format!(
+                "{} chunk ({:02x}) in bank {} can't be stored in a text cart",
+                chunk_name(chunk.type_),
+                chunk.type_,
+                chunk.bank
+            ));
+        }
+    }
+
+    for bank in 0..8 {
+        for section in SECTIONS {
+            let chunk = match chunks
+                .iter()
+                .rev()
+                .find(|c| c.type_ == section.type_ && c.bank == bank)
+            {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let tag = match bank {
+                0 => section.tag.to_string(),
+                _ => format!("{}{}", section.tag, bank),
+            };
+            text.extend_from_slice(format!("\n-- <{}>\n", tag).as_bytes());
+            for (index, row) in chunk.data.chunks(section.row_size).enumerate() {
+                if row.iter().all(|&b| b == 0) {
+                    continue;
+                }
+                let mut row = row.to_vec();
+                row.resize(section.row_size, 0);
+                text.extend_from_slice(format!("-- {:03}:", index).as_bytes());
+                for byte in row {
+                    let byte = if section.flip {
+                        byte.rotate_left(4)
+                    } else {
+                        byte
+                    };
+                    text.extend_from_slice(format!("{:02x}", byte).as_bytes());
+                }
+                text.push(b'\n');
+            }
+            text.extend_from_slice(format!("-- </{}>\n", tag).as_bytes());
+        }
+    }
+
+    (text, warnings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut tiles = vec![0; 33];
+        tiles[32] = 0xce;
+        let chunks = vec![
+            Chunk {
+                type_: 0x05,
+                bank: 0,
+                data: b"function TIC()cls()end".to_vec(),
+            },
+            Chunk {
+                type_: 0x01,
+                bank: 0,
+                data: tiles,
+            },
+            Chunk {
+                type_: 0x04,
+                bank: 2,
+                data: vec![0x12, 0, 0xff],
+            },
+            Chunk {
+                type_: 0x03,
+                bank: 0,
+                data: b"GIF89a".to_vec(),
+            },
+        ];
+        let (text, warnings) = to_text(&chunks);
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("function TIC()cls()end\n\n-- <TILES>\n-- 001:ec00"));
+        assert!(text.contains("-- <MAP2>\n-- 000:1200ff00"));
+        assert!(text.ends_with("-- </MAP2>\n"));
+        assert_eq!(warnings.len(), 1);
+
+        let parsed = parse_text(text.as_bytes()).unwrap();
+        assert_eq!(parsed.len(), 3);
+        for (a, b) in parsed.iter().zip(&chunks) {
+            assert_eq!((a.type_, a.bank, &a.data), (b.type_, b.bank, &b.data));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_text(b"-- <SFX>\n-- 064:00\n-- </SFX>\n").is_err());
+        assert!(parse_text(b"-- <PALETTE>\n-- 000:0g\n-- </PALETTE>\n").is_err());
+        // unknown tags are just comments
+        let chunks = parse_text(b"-- <COVER>\n-- </COVER>\n").unwrap();
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[test]
+    fn plain_source() {
+        // tags without end tags are comments, plain source is kept as it is
+        let source = b"-- <MAP>\nx=1 -- <SFX>\n-- <SFX>\n-- 000:00\nprint(x)  \n\n";
+        let chunks = parse_text(source).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data, source.to_vec());
+    }
+}
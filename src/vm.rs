@@ -0,0 +1,561 @@
+//! A minimal stand-in for the TIC-80 runtime, used to check that packed code behaves
+//! exactly like the original.
+//!
+//! Code runs in an embedded Lua 5.3 VM with a stubbed API: the memory functions
+//! (`peek*`, `poke*`, `memcpy`, `memset`) and the simple drawing functions (`cls`, `pix`,
+//! `rect`, `rectb`) work on 96KB of RAM which includes the 4bpp framebuffer, `time`
+//! advances by 1/60 second per frame, no buttons or keys are ever pressed and
+//! `math.random` is deterministic. All other API functions are only recorded together
+//! with their arguments.
+
+use anyhow::{anyhow, Result};
+use mlua::{Function, HookTriggers, Lua, MultiValue, Value, Variadic};
+use std::{
+    cell::{Cell, RefCell},
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    rc::Rc,
+};
+
+const RAM_SIZE: usize = 0x18000;
+const WIDTH: i64 = 240;
+const HEIGHT: i64 = 136;
+const SCREEN_SIZE: usize = (WIDTH * HEIGHT / 2) as usize;
+/// The number of instructions a frame may execute before it's considered stuck.
+const INSTRUCTION_LIMIT: u64 = 500_000_000;
+
+/// API functions which are only recorded, with the value they return.
+const RECORDED: &[(&str, Recorded)] = &[
+    ("circ", Recorded::Nil),
+    ("circb", Recorded::Nil),
+    ("clip", Recorded::Nil),
+    ("elli", Recorded::Nil),
+    ("ellib", Recorded::Nil),
+    ("exit", Recorded::Nil),
+    ("fget", Recorded::False),
+    ("fset", Recorded::Nil),
+    ("font", Recorded::Zero),
+    ("line", Recorded::Nil),
+    ("map", Recorded::Nil),
+    ("mget", Recorded::Zero),
+    ("mset", Recorded::Nil),
+    ("music", Recorded::Nil),
+    ("pmem", Recorded::Zero),
+    ("reset", Recorded::Nil),
+    ("sfx", Recorded::Nil),
+    ("spr", Recorded::Nil),
+    ("sync", Recorded::Nil),
+    ("textri", Recorded::Nil),
+    ("trace", Recorded::Nil),
+    ("tri", Recorded::Nil),
+    ("trib", Recorded::Nil),
+    ("ttri", Recorded::Nil),
+    ("vbank", Recorded::Zero),
+];
+
+#[derive(Clone, Copy)]
+enum Recorded {
+    Nil,
+    False,
+    Zero,
+}
+
+/// Everything observable a frame did.
+pub struct Frame {
+    pub screen_hash: u64,
+    /// All bytes written by the memory functions, in order.
+    pub writes: Vec<(usize, u8)>,
+    /// All calls to recorded API functions, in order.
+    pub calls: Vec<String>,
+}
+
+impl Frame {
+    /// Describes the first difference to `other`, if there is any.
+    pub fn diff(&self, other: &Frame) -> Option<String> {
+        fn first_diff<T: PartialEq>(a: &[T], b: &[T]) -> Option<usize> {
+            (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i))
+        }
+        if let Some(i) = first_diff(&self.writes, &other.writes) {
+            let describe = |write: Option<&(usize, u8)>| match write {
+                Some(&(addr, value)) => format!("[0x{:05x}]={}", addr, value),
+                None => "nothing".to_string(),
+            };
+            return Some(format!(
+                "memory write #{} differs: {} vs. {}",
+                i + 1,
+                describe(self.writes.get(i)),
+                describe(other.writes.get(i))
+            ));
+        }
+        if let Some(i) = first_diff(&self.calls, &other.calls) {
+            let describe =
+                |call: Option<&String>| call.cloned().unwrap_or_else(|| "nothing".into());
+            return Some(format!(
+                "API call #{} differs: {} vs. {}",
+                i + 1,
+                describe(self.calls.get(i)),
+                describe(other.calls.get(i))
+            ));
+        }
+        if self.screen_hash != other.screen_hash {
+            return Some("framebuffer differs".to_string());
+        }
+        None
+    }
+}
+
+struct State {
+    ram: Vec<u8>,
+    frame: u32,
+    rng: u64,
+    writes: Vec<(usize, u8)>,
+    calls: Vec<String>,
+}
+
+impl State {
+    /// Reads a value of `bits` (1, 2, 4 or 8) bits, `addr` counting in units of that size.
+    fn peek(&self, addr: i64, bits: u32) -> u8 {
+        let per_byte = 8 / bits as i64;
+        match self.ram.get((addr / per_byte) as usize) {
+            Some(&byte) if addr >= 0 => {
+                (byte >> ((addr % per_byte) as u32 * bits)) & ((1u16 << bits) - 1) as u8
+            }
+            _ => 0,
+        }
+    }
+
+    fn poke(&mut self, addr: i64, bits: u32, value: i64) {
+        let per_byte = 8 / bits as i64;
+        let index = (addr / per_byte) as usize;
+        if addr < 0 || index >= RAM_SIZE {
+            return;
+        }
+        let shift = (addr % per_byte) as u32 * bits;
+        let mask = (((1u16 << bits) - 1) << shift) as u8;
+        let byte = (self.ram[index] & !mask) | (((value as u8) << shift) & mask);
+        self.ram[index] = byte;
+        self.writes.push((index, byte));
+    }
+
+    fn set_pixel(&mut self, x: i64, y: i64, color: i64) {
+        if (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y) {
+            let addr = (y * WIDTH + x) as usize;
+            let shift = (addr & 1) * 4;
+            let byte = &mut self.ram[addr / 2];
+            *byte = (*byte & !(15 << shift)) | (((color & 15) as u8) << shift);
+        }
+    }
+
+    fn fill_rect(&mut self, x: i64, y: i64, w: i64, h: i64, color: i64) {
+        for py in y.max(0)..(y + h).min(HEIGHT) {
+            for px in x.max(0)..(x + w).min(WIDTH) {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+pub struct Machine {
+    lua: Lua,
+    state: Rc<RefCell<State>>,
+    instructions: Rc<Cell<u64>>,
+}
+
+fn int(args: &[Value], index: usize, default: i64) -> i64 {
+    match args.get(index) {
+        Some(&Value::Integer(i)) => i,
+        Some(&Value::Number(n)) => n as i64,
+        _ => default,
+    }
+}
+
+/// Describes an argument the way TIC-80 uses it: numbers are converted as needed, so `2`
+/// and `2.0` are the same.
+fn describe_value(value: &Value) -> String {
+    match *value {
+        Value::Nil => "nil".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => (i as f64).to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(ref s) => format!("{:?}", String::from_utf8_lossy(s.as_bytes())),
+        ref other => other.type_name().to_string(),
+    }
+}
+
+/// Describes the arguments of a call, of which the first `text_args` are printed as text.
+/// Numbers printed as text are formatted by Lua's `tostring`, where `1000` and `1000.0`
+/// (as well as `1e3`) differ.
+fn describe_args(lua: &Lua, args: &[Value], text_args: usize) -> mlua::Result<String> {
+    let mut described = vec![];
+    for (i, value) in args.iter().enumerate() {
+        described.push(match *value {
+            Value::Integer(_) | Value::Number(_) if i < text_args => lua
+                .coerce_string(value.clone())?
+                .map_or_else(String::new, |s| s.to_string_lossy().into_owned()),
+            _ => describe_value(value),
+        });
+    }
+    Ok(described.join(","))
+}
+
+impl Machine {
+    /// Runs the main chunk of `code` (and the `BOOT` callback, if defined).
+    pub fn new(code: &[u8]) -> Result<Machine> {
+        let machine = Machine {
+            lua: Lua::new(),
+            state: Rc::new(RefCell::new(State {
+                ram: vec![0; RAM_SIZE],
+                frame: 0,
+                rng: 0x2545_f491,
+                writes: vec![],
+                calls: vec![],
+            })),
+            instructions: Rc::new(Cell::new(0)),
+        };
+        machine.register_api().map_err(lua_error)?;
+
+        let instructions = machine.instructions.clone();
+        machine.lua.set_hook(
+            HookTriggers::new().every_nth_instruction(10000),
+            move |_, _| {
+                instructions.set(instructions.get() + 10000);
+                if instructions.get() > INSTRUCTION_LIMIT {
+                    return Err(mlua::Error::RuntimeError(
+                        "instruction limit per frame exceeded".into(),
+                    ));
+                }
+                Ok(())
+            },
+        );
+
+        machine
+            .lua
+            .load(code)
+            .set_name("code")
+            .exec()
+            .map_err(lua_error)?;
+        machine.call(b"BOOT", None)?;
+        Ok(machine)
+    }
+
+    /// Runs one frame, returning what it did, including anything done before the first
+    /// frame.
+    pub fn frame(&mut self) -> Result<Frame> {
+        self.instructions.set(0);
+        self.call(b"TIC", None)?;
+        for line in 0..HEIGHT {
+            self.call(b"SCN", Some(line))?;
+            self.call(b"BDR", Some(line))?;
+        }
+        self.call(b"OVR", None)?;
+
+        let mut state = self.state.borrow_mut();
+        state.frame += 1;
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&state.ram[..SCREEN_SIZE]);
+        Ok(Frame {
+            screen_hash: hasher.finish(),
+            writes: std::mem::take(&mut state.writes),
+            calls: std::mem::take(&mut state.calls),
+        })
+    }
+
+    fn call(&self, name: &[u8], line: Option<i64>) -> Result<()> {
+        let name = self.lua.create_string(name).map_err(lua_error)?;
+        if let Value::Function(f) = self.lua.globals().raw_get(name).map_err(lua_error)? {
+            f.call::<_, ()>(line).map_err(lua_error)?;
+        }
+        Ok(())
+    }
+
+    fn register_api(&self) -> mlua::Result<()> {
+        let lua = &self.lua;
+        let globals = lua.globals();
+        let register = |name: &str, f: Function| globals.raw_set(name, f);
+
+        let state = self.state.clone();
+        register(
+            "cls",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                let color = (int(&args, 0, 0) & 15) as u8;
+                let mut state = state.borrow_mut();
+                for byte in &mut state.ram[..SCREEN_SIZE] {
+                    *byte = color | (color << 4);
+                }
+                Ok(())
+            })?,
+        )?;
+        let state = self.state.clone();
+        register(
+            "pix",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                let (x, y) = (int(&args, 0, 0), int(&args, 1, 0));
+                let mut state = state.borrow_mut();
+                if args.len() > 2 {
+                    state.set_pixel(x, y, int(&args, 2, 0));
+                    Ok(None)
+                } else if (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y) {
+                    Ok(Some(state.peek(y * WIDTH + x, 4)))
+                } else {
+                    Ok(Some(0))
+                }
+            })?,
+        )?;
+        let state = self.state.clone();
+        register(
+            "rect",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                let arg = |i| int(&args, i, 0);
+                state
+                    .borrow_mut()
+                    .fill_rect(arg(0), arg(1), arg(2), arg(3), arg(4));
+                Ok(())
+            })?,
+        )?;
+        let state = self.state.clone();
+        register(
+            "rectb",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                let (x, y, w, h, color) = (
+                    int(&args, 0, 0),
+                    int(&args, 1, 0),
+                    int(&args, 2, 0),
+                    int(&args, 3, 0),
+                    int(&args, 4, 0),
+                );
+                let mut state = state.borrow_mut();
+                state.fill_rect(x, y, w, 1, color);
+                state.fill_rect(x, y + h - 1, w, 1, color);
+                state.fill_rect(x, y, 1, h, color);
+                state.fill_rect(x + w - 1, y, 1, h, color);
+                Ok(())
+            })?,
+        )?;
+
+        for &(name, bits) in &[("", 8), ("1", 1), ("2", 2), ("4", 4)] {
+            let state = self.state.clone();
+            register(
+                &format!("peek{}", name),
+                lua.create_function(move |_, args: Variadic<Value>| {
+                    let bits = if bits == 8 {
+                        int(&args, 1, 8) as u32
+                    } else {
+                        bits
+                    };
+                    if !matches!(bits, 1 | 2 | 4 | 8) {
+                        return Ok(0);
+                    }
+                    Ok(state.borrow().peek(int(&args, 0, 0), bits))
+                })?,
+            )?;
+            let state = self.state.clone();
+            register(
+                &format!("poke{}", name),
+                lua.create_function(move |_, args: Variadic<Value>| {
+                    let bits = if bits == 8 {
+                        int(&args, 2, 8) as u32
+                    } else {
+                        bits
+                    };
+                    if matches!(bits, 1 | 2 | 4 | 8) {
+                        state
+                            .borrow_mut()
+                            .poke(int(&args, 0, 0), bits, int(&args, 1, 0));
+                    }
+                    Ok(())
+                })?,
+            )?;
+        }
+        let state = self.state.clone();
+        register(
+            "memcpy",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                let (dst, src, size) = (int(&args, 0, 0), int(&args, 1, 0), int(&args, 2, 0));
+                let mut state = state.borrow_mut();
+                let bytes: Vec<u8> = (0..size).map(|i| state.peek(src + i, 8)).collect();
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    state.poke(dst + i as i64, 8, byte as i64);
+                }
+                Ok(())
+            })?,
+        )?;
+        let state = self.state.clone();
+        register(
+            "memset",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                let (dst, value, size) = (int(&args, 0, 0), int(&args, 1, 0), int(&args, 2, 0));
+                let mut state = state.borrow_mut();
+                for i in 0..size {
+                    state.poke(dst + i, 8, value);
+                }
+                Ok(())
+            })?,
+        )?;
+
+        let state = self.state.clone();
+        register(
+            "time",
+            lua.create_function(move |_, ()| Ok(state.borrow().frame as f64 * 1000. / 60.))?,
+        )?;
+        register("tstamp", lua.create_function(|_, ()| Ok(0))?)?;
+        for &name in &["btn", "btnp", "key", "keyp"] {
+            register(
+                name,
+                lua.create_function(|_, args: Variadic<Value>| {
+                    // without arguments, `btn` returns the bitmask of all buttons
+                    Ok(if args.is_empty() {
+                        Value::Integer(0)
+                    } else {
+                        Value::Boolean(false)
+                    })
+                })?,
+            )?;
+        }
+        register(
+            "mouse",
+            lua.create_function(|_, ()| Ok((0, 0, false, false, false, 0, 0)))?,
+        )?;
+
+        let state = self.state.clone();
+        register(
+            "print",
+            lua.create_function(move |lua, args: Variadic<Value>| {
+                let text = match args.first() {
+                    Some(value) => lua
+                        .coerce_string(value.clone())?
+                        .map_or(0, |s| s.as_bytes().len()),
+                    None => 0,
+                };
+                let described = describe_args(lua, &args, 1)?;
+                state
+                    .borrow_mut()
+                    .calls
+                    .push(format!("print({})", described));
+                Ok(text as i64 * 6)
+            })?,
+        )?;
+        for &(name, result) in RECORDED {
+            let state = self.state.clone();
+            register(
+                name,
+                lua.create_function(move |lua, args: Variadic<Value>| {
+                    let text_args = if name == "trace" { 1 } else { 0 };
+                    let described = describe_args(lua, &args, text_args)?;
+                    state
+                        .borrow_mut()
+                        .calls
+                        .push(format!("{}({})", name, described));
+                    Ok(match result {
+                        Recorded::Nil => Value::Nil,
+                        Recorded::False => Value::Boolean(false),
+                        Recorded::Zero => Value::Integer(0),
+                    })
+                })?,
+            )?;
+        }
+
+        let math: mlua::Table = globals.get("math")?;
+        let state = self.state.clone();
+        math.raw_set(
+            "random",
+            lua.create_function(move |_, args: MultiValue| {
+                let args: Vec<Value> = args.into_iter().collect();
+                let r = state.borrow_mut().random();
+                let (low, high) = match args.len() {
+                    0 => return Ok(Value::Number((r >> 11) as f64 / (1u64 << 53) as f64)),
+                    1 => (1, int(&args, 0, 1)),
+                    _ => (int(&args, 0, 1), int(&args, 1, 1)),
+                };
+                if low > high {
+                    return Err(mlua::Error::RuntimeError(
+                        "bad argument to 'random' (interval is empty)".into(),
+                    ));
+                }
+                let range = (high - low) as u64 + 1;
+                Ok(Value::Integer(low + (r % range) as i64))
+            })?,
+        )?;
+        let state = self.state.clone();
+        math.raw_set(
+            "randomseed",
+            lua.create_function(move |_, args: Variadic<Value>| {
+                state.borrow_mut().rng = int(&args, 0, 0) as u64 | 1;
+                Ok(())
+            })?,
+        )?;
+
+        Ok(())
+    }
+}
+
+fn lua_error(err: mlua::Error) -> anyhow::Error {
+    anyhow!("{}", err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(code: &[u8], frames: usize) -> Vec<Frame> {
+        let mut machine = Machine::new(code).unwrap();
+        (0..frames).map(|_| machine.frame().unwrap()).collect()
+    }
+
+    #[test]
+    fn memory_and_screen() {
+        let frames = run(
+            b"poke4(1,3) function TIC() cls(1) pix(0,0,peek(0)//16) poke(0x3ff8,time()//10) end",
+            2,
+        );
+        assert_eq!(frames[0].writes, vec![(0, 0x30), (0x3ff8, 0)]);
+        assert_eq!(frames[1].writes, vec![(0x3ff8, 1)]);
+        assert!(frames[0]
+            .diff(&frames[1])
+            .unwrap()
+            .contains("memory write #1"));
+    }
+
+    #[test]
+    fn recorded_calls() {
+        let frames = run(
+            b"function TIC() circ(1,2.0,3,'x') print(1.5) print(1000,1e3) trace(1e3) end",
+            1,
+        );
+        assert_eq!(
+            frames[0].calls,
+            vec![
+                "circ(1,2,3,\"x\")",
+                "print(1.5)",
+                "print(1000,1000)",
+                "trace(1000.0)"
+            ]
+        );
+        let integers = run(b"function TIC() print(1000) end", 1);
+        let floats = run(b"function TIC() print(1e3) end", 1);
+        assert!(integers[0].diff(&floats[0]).is_some());
+    }
+
+    #[test]
+    fn deterministic_random() {
+        let code = b"function TIC() poke(0,math.random(255)) end";
+        let a = run(code, 3);
+        let b = run(code, 3);
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!(a.diff(b).is_none());
+        }
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let mut machine = Machine::new(b"function TIC() while true do end end").unwrap();
+        assert!(machine.frame().is_err());
+    }
+}